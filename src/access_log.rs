@@ -0,0 +1,179 @@
+//! HTTP access log in the NCSA Combined Log Format, written to the `--access-log` file apart
+//! from the application logs.
+
+use hyper::{header, Body, Request, Response};
+use once_cell::sync::OnceCell;
+use std::{fs::File, io::Write, net::SocketAddr, path::Path, sync::Mutex, time::SystemTime};
+
+// the access log file given on the command line
+static ACCESS_LOG: OnceCell<Mutex<File>> = OnceCell::new();
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Open `path` for appending access log lines.
+pub(crate) fn init(path: &Path) -> Result<(), String> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| {
+            format!(
+                "Failed to open the access log {}. {}",
+                path.to_string_lossy(),
+                e
+            )
+        })?;
+
+    ACCESS_LOG
+        .set(Mutex::new(file))
+        .map_err(|_| "Failed to set `ACCESS_LOG`.".to_string())
+}
+
+/// Write the logged lines through to the disk, before exiting.
+pub(crate) fn flush() {
+    if let Some(file) = ACCESS_LOG.get() {
+        if let Err(e) = file.lock().unwrap().sync_data() {
+            warn!(target: "stdout", "Failed to flush the access log. {}", e);
+        }
+    }
+}
+
+/// The request side of an access log line, taken before the request is consumed.
+pub(crate) struct Entry {
+    remote_addr: SocketAddr,
+    time: SystemTime,
+    request_line: String,
+    referer: String,
+    user_agent: String,
+}
+
+impl Entry {
+    /// Record the request fields of the line, or `None` when the access log is disabled.
+    pub(crate) fn new(req: &Request<Body>, remote_addr: SocketAddr) -> Option<Self> {
+        ACCESS_LOG.get()?;
+
+        let header_value = |name: header::HeaderName| {
+            req.headers()
+                .get(name)
+                .map(|value| escape(&String::from_utf8_lossy(value.as_bytes())))
+                .unwrap_or_else(|| "-".to_string())
+        };
+
+        Some(Self {
+            remote_addr,
+            time: SystemTime::now(),
+            request_line: escape(&format!(
+                "{} {} {:?}",
+                req.method(),
+                redact_target(req),
+                req.version()
+            )),
+            referer: header_value(header::REFERER),
+            user_agent: header_value(header::USER_AGENT),
+        })
+    }
+
+    /// Complete the line with the status and size of `response` and append it to the log.
+    pub(crate) fn write(self, response: &Response<Body>) {
+        let file = match ACCESS_LOG.get() {
+            Some(file) => file,
+            None => return,
+        };
+
+        // streamed bodies have no known size, which is logged like an empty one
+        let bytes = match hyper::body::HttpBody::size_hint(response.body()).exact() {
+            Some(bytes) if bytes > 0 => bytes.to_string(),
+            _ => "-".to_string(),
+        };
+        let line = format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"\n",
+            self.remote_addr.ip(),
+            format_time(self.time),
+            self.request_line,
+            response.status().as_u16(),
+            bytes,
+            self.referer,
+            self.user_agent,
+        );
+
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!(target: "stdout", "Failed to write the access log. {}", e);
+        }
+    }
+}
+
+/// Path and query of the request, with the value of the `api_key` query parameter hidden.
+fn redact_target(req: &Request<Body>) -> String {
+    let path = req.uri().path();
+    match req.uri().query() {
+        Some(query) => {
+            let query: Vec<&str> = query
+                .split('&')
+                .map(|pair| match pair.starts_with("api_key=") {
+                    true => "api_key=***",
+                    false => pair,
+                })
+                .collect();
+            format!("{}?{}", path, query.join("&"))
+        }
+        None => path.to_string(),
+    }
+}
+
+/// Escape the quotes, backslashes and control characters of a quoted field.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Format a time as `10/Oct/2000:13:55:36 +0000`, in UTC.
+fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Year, month and day of a number of days since 1970-01-01, in the proleptic Gregorian
+/// calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // shift the epoch to 0000-03-01, so that leap days end the 400-year eras
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
@@ -0,0 +1,64 @@
+//! AAC-LC encoding in an ADTS stream, the format served as `audio/aac`.
+
+use super::wav::Pcm;
+use fdk_aac::enc::{BitRate, ChannelMode, Encoder, EncoderParams, Transport};
+
+/// Default bitrate in bits per second, transparent for mono speech.
+pub(crate) const DEFAULT_BITRATE: u32 = 64_000;
+/// Bitrates accepted for AAC output, in bits per second.
+pub(crate) const BITRATE_RANGE: std::ops::RangeInclusive<u32> = 16_000..=320_000;
+
+/// Samples per channel in an AAC-LC frame.
+const FRAME_LEN: usize = 1024;
+/// Zero frames fed after the audio to drain the encoder lookahead, as the bindings cannot flush.
+const FLUSH_FRAMES: usize = 3;
+/// Room for the ADTS frames produced from a single input frame.
+const MAX_OUTPUT_SIZE: usize = 8192;
+
+/// Encode `pcm` as AAC-LC in ADTS frames at the given bitrate.
+///
+/// The encoder lowers the bitrate on its own when it is too high for the sample rate.
+pub(crate) fn encode(pcm: &Pcm, bitrate: u32) -> Result<Vec<u8>, String> {
+    let channels = match pcm.channels {
+        1 => ChannelMode::Mono,
+        2 => ChannelMode::Stereo,
+        n => return Err(format!("aac output supports 1 or 2 channels, got {}", n)),
+    };
+
+    let encoder = Encoder::new(EncoderParams {
+        bit_rate: BitRate::Cbr(bitrate),
+        sample_rate: pcm.sample_rate,
+        transport: Transport::Adts,
+        channels,
+    })
+    .map_err(|e| format!("failed to create the aac encoder. {:?}", e))?;
+
+    let mut samples: Vec<i16> = pcm
+        .samples
+        .iter()
+        .map(|s| (s * 32768.0).round().clamp(-32768.0, 32767.0) as i16)
+        .collect();
+    let frame_len = FRAME_LEN * pcm.channels as usize;
+    let padded_len = samples.len().div_ceil(frame_len) * frame_len + FLUSH_FRAMES * frame_len;
+    samples.resize(padded_len, 0);
+
+    let mut out = Vec::new();
+    let mut output = vec![0u8; MAX_OUTPUT_SIZE];
+    let mut pos = 0;
+    while pos < samples.len() {
+        let info = encoder
+            .encode(
+                &samples[pos..(pos + frame_len).min(samples.len())],
+                &mut output,
+            )
+            .map_err(|e| format!("failed to encode aac frame. {:?}", e))?;
+        if info.input_consumed == 0 && info.output_size == 0 {
+            return Err("the aac encoder made no progress".to_string());
+        }
+
+        out.extend_from_slice(&output[..info.output_size]);
+        pos += info.input_consumed;
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,60 @@
+//! Word timing estimates for synthesized speech.
+
+use super::{dsp, wav::Pcm, DEFAULT_SILENCE_THRESHOLD};
+use serde::{Deserialize, Serialize};
+
+/// Time span of a spoken word, in seconds from the start of the audio.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct WordTimestamp {
+    pub word: String,
+    pub start: f64,
+    pub end: f64,
+}
+
+/// Estimate the timing of the words of `text`, spoken in `pcm` which starts `offset` frames
+/// into the final audio.
+///
+/// The voiced part of the chunk is shared between its words in proportion to the number of
+/// letters and digits in each of them.
+pub(crate) fn estimate(text: &str, pcm: &Pcm, offset: usize) -> Vec<WordTimestamp> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    if words.is_empty() {
+        return vec![];
+    }
+
+    let voiced = dsp::voiced_range(pcm, DEFAULT_SILENCE_THRESHOLD).unwrap_or(0..pcm.frames());
+    let weights: Vec<usize> = words
+        .iter()
+        .map(|word| word.chars().filter(|c| c.is_alphanumeric()).count().max(1))
+        .collect();
+    let total: usize = weights.iter().sum();
+
+    let rate = pcm.sample_rate as f64;
+    let span = voiced.len() as f64;
+    let mut position = (offset + voiced.start) as f64;
+    words
+        .into_iter()
+        .zip(weights)
+        .map(|(word, weight)| {
+            let start = position;
+            position += span * weight as f64 / total as f64;
+            WordTimestamp {
+                word: word.to_string(),
+                start: round_ms(start / rate),
+                end: round_ms(position / rate),
+            }
+        })
+        .collect()
+}
+
+/// Scale the timing of `words` to audio whose speed was changed by `speed`.
+pub(crate) fn change_speed(words: &mut [WordTimestamp], speed: f32) {
+    for word in words {
+        word.start = round_ms(word.start / speed as f64);
+        word.end = round_ms(word.end / speed as f64);
+    }
+}
+
+fn round_ms(seconds: f64) -> f64 {
+    (seconds * 1000.0).round() / 1000.0
+}
@@ -0,0 +1,298 @@
+//! Sample-level processing applied to decoded PCM.
+
+use super::wav::Pcm;
+
+/// Convert a duration in milliseconds to a number of sample frames.
+pub(crate) fn ms_to_frames(sample_rate: u32, ms: u32) -> usize {
+    (sample_rate as u64 * ms as u64 / 1000) as usize
+}
+
+/// Root mean square of a block of samples.
+pub(crate) fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum: f32 = samples.iter().map(|s| s * s).sum();
+    (sum / samples.len() as f32).sqrt()
+}
+
+/// Range of frames between the first and the last 10 ms window whose RMS reaches `threshold`,
+/// or `None` if the audio never does.
+pub(crate) fn voiced_range(pcm: &Pcm, threshold: f32) -> Option<std::ops::Range<usize>> {
+    let channels = pcm.channels.max(1) as usize;
+    let frames = pcm.frames();
+    let window = ms_to_frames(pcm.sample_rate, 10).max(1);
+    let windows = frames.div_ceil(window);
+
+    let is_loud = |w: usize| {
+        let start = w * window * channels;
+        let end = ((w + 1) * window * channels).min(pcm.samples.len());
+        rms(&pcm.samples[start..end]) >= threshold
+    };
+    let first = (0..windows).find(|&w| is_loud(w))?;
+    let last = (first..windows)
+        .rev()
+        .find(|&w| is_loud(w))
+        .unwrap_or(first);
+
+    Some(first * window..((last + 1) * window).min(frames))
+}
+
+/// Remove leading and trailing audio whose RMS stays below `threshold`.
+///
+/// `guard_ms` of audio is kept on either side of the voiced range so word onsets and decays
+/// are not clipped. Audio that never rises above the threshold is left untouched.
+pub(crate) fn trim_silence(pcm: &mut Pcm, threshold: f32, guard_ms: u32) {
+    let Some(voiced) = voiced_range(pcm, threshold) else {
+        return;
+    };
+
+    let channels = pcm.channels.max(1) as usize;
+    let guard = ms_to_frames(pcm.sample_rate, guard_ms);
+    let start = voiced.start.saturating_sub(guard);
+    let end = (voiced.end + guard).min(pcm.frames());
+
+    pcm.samples.truncate(end * channels);
+    pcm.samples.drain(..start * channels);
+}
+
+/// Number of zero crossings on each side of the resampling kernel.
+const SINC_ZERO_CROSSINGS: f64 = 16.0;
+
+/// Resample `pcm` to `target_rate` with a Blackman-windowed sinc interpolator.
+///
+/// When downsampling, the kernel is widened so it also acts as the anti-aliasing filter.
+pub(crate) fn resample(pcm: &mut Pcm, target_rate: u32) {
+    if pcm.sample_rate == target_rate || target_rate == 0 {
+        return;
+    }
+
+    let channels = pcm.channels.max(1) as usize;
+    let frames = pcm.frames();
+    let ratio = target_rate as f64 / pcm.sample_rate as f64;
+    let cutoff = ratio.min(1.0);
+    let half_width = (SINC_ZERO_CROSSINGS / cutoff).ceil() as isize;
+    let out_frames = (frames as f64 * ratio).round() as usize;
+
+    let mut out = Vec::with_capacity(out_frames * channels);
+    for n in 0..out_frames {
+        let t = n as f64 / ratio;
+        let center = t.floor() as isize;
+        let first = (center - half_width + 1).max(0);
+        let last = (center + half_width).min(frames as isize - 1);
+        for c in 0..channels {
+            let mut acc = 0.0;
+            for k in first..=last {
+                let x = (t - k as f64) * cutoff;
+                acc += pcm.samples[k as usize * channels + c] as f64 * windowed_sinc(x);
+            }
+            out.push((acc * cutoff) as f32);
+        }
+    }
+
+    pcm.samples = out;
+    pcm.sample_rate = target_rate;
+}
+
+fn windowed_sinc(x: f64) -> f64 {
+    use std::f64::consts::PI;
+
+    if x.abs() >= SINC_ZERO_CROSSINGS {
+        return 0.0;
+    }
+    let sinc = match x == 0.0 {
+        true => 1.0,
+        false => (PI * x).sin() / (PI * x),
+    };
+    let window = 0.42
+        + 0.5 * (PI * x / SINC_ZERO_CROSSINGS).cos()
+        + 0.08 * (2.0 * PI * x / SINC_ZERO_CROSSINGS).cos();
+    sinc * window
+}
+
+/// Length of the WSOLA analysis window, in milliseconds.
+const STRETCH_WINDOW_MS: u32 = 30;
+/// How far WSOLA may move a window to line it up with the previous one, in milliseconds.
+const STRETCH_TOLERANCE_MS: u32 = 10;
+
+/// Shift the pitch of `pcm` by `semitones` while keeping its duration.
+///
+/// The audio is stretched in time by the pitch ratio, then resampled back to its length.
+pub(crate) fn pitch_shift(pcm: &mut Pcm, semitones: f32) {
+    if semitones == 0.0 || pcm.frames() == 0 {
+        return;
+    }
+
+    let sample_rate = pcm.sample_rate;
+    let ratio = 2f64.powf(semitones as f64 / 12.0);
+    pcm.samples = time_stretch(pcm, ratio);
+    pcm.sample_rate = (sample_rate as f64 * ratio).round() as u32;
+    resample(pcm, sample_rate);
+}
+
+/// Speed `pcm` up, or slow it down below 1.0, by `speed` without changing its pitch.
+pub(crate) fn change_speed(pcm: &mut Pcm, speed: f32) {
+    if speed == 1.0 || speed <= 0.0 || pcm.frames() == 0 {
+        return;
+    }
+
+    pcm.samples = time_stretch(pcm, 1.0 / speed as f64);
+}
+
+/// Change the duration of `pcm` by `factor` without changing its pitch.
+///
+/// Uses WSOLA: Hann windows are overlap-added at a fixed hop, each one taken from the input
+/// position, within a small tolerance, that best continues the previous window.
+fn time_stretch(pcm: &Pcm, factor: f64) -> Vec<f32> {
+    let channels = pcm.channels.max(1) as usize;
+    let frames = pcm.frames();
+    let window = ms_to_frames(pcm.sample_rate, STRETCH_WINDOW_MS).max(2);
+    let tolerance = ms_to_frames(pcm.sample_rate, STRETCH_TOLERANCE_MS) as isize;
+    let hop_out = window / 2;
+    let hop_in = hop_out as f64 / factor;
+    let out_frames = (frames as f64 * factor).round() as usize;
+
+    // the correlation search runs on a mono mix
+    let mono: Vec<f32> = pcm
+        .samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    let hann: Vec<f32> = (0..window)
+        .map(|n| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * n as f32 / window as f32).cos())
+        .collect();
+
+    let mut out = vec![0.0f32; (out_frames + window) * channels];
+    let mut weights = vec![0.0f32; out_frames + window];
+    let last_start = frames.saturating_sub(window) as isize;
+    let mut previous: Option<usize> = None;
+    let mut k = 0;
+    while k * hop_out < out_frames {
+        let nominal = ((k as f64 * hop_in).round() as isize).min(last_start);
+        let start = match previous {
+            None => nominal.max(0) as usize,
+            Some(previous) => {
+                // the input that would naturally follow the previous window
+                let target = previous + hop_out;
+                let first = (nominal - tolerance).max(0);
+                let last = (nominal + tolerance).min(last_start);
+                (first..=last.max(first))
+                    .map(|candidate| candidate as usize)
+                    .max_by(|&a, &b| {
+                        let score = |candidate: usize| -> f32 {
+                            (0..window)
+                                .filter(|n| target + n < frames && candidate + n < frames)
+                                .map(|n| mono[candidate + n] * mono[target + n])
+                                .sum()
+                        };
+                        score(a).total_cmp(&score(b))
+                    })
+                    .unwrap_or(nominal.max(0) as usize)
+            }
+        };
+
+        let out_start = k * hop_out;
+        for n in 0..window.min(frames - start) {
+            let w = hann[n];
+            for c in 0..channels {
+                out[(out_start + n) * channels + c] += pcm.samples[(start + n) * channels + c] * w;
+            }
+            weights[out_start + n] += w;
+        }
+
+        previous = Some(start);
+        k += 1;
+    }
+
+    out.truncate(out_frames * channels);
+    for (frame, &weight) in out.chunks_mut(channels).zip(&weights) {
+        if weight > 1e-3 {
+            frame.iter_mut().for_each(|sample| *sample /= weight);
+        }
+    }
+    out
+}
+
+/// Concatenate chunks of audio, crossfading `crossfade_ms` between neighbours.
+///
+/// The fade is clamped to the length of the shorter of two adjacent chunks.
+pub(crate) fn join(chunks: Vec<Pcm>, crossfade_ms: u32) -> Result<Pcm, String> {
+    let mut chunks = chunks.into_iter();
+    let mut out = chunks.next().ok_or("no audio to join")?;
+    let channels = out.channels.max(1) as usize;
+    let mut previous_frames = out.frames();
+
+    for chunk in chunks {
+        if chunk.sample_rate != out.sample_rate || chunk.channels != out.channels {
+            return Err(format!(
+                "cannot join {} Hz/{} channel audio with {} Hz/{} channel audio",
+                out.sample_rate, out.channels, chunk.sample_rate, chunk.channels
+            ));
+        }
+
+        let fade = crossfade_frames(
+            out.sample_rate,
+            crossfade_ms,
+            previous_frames,
+            chunk.frames(),
+        );
+        let start = out.samples.len() - fade * channels;
+        for i in 0..fade {
+            let t = (i as f32 + 0.5) / fade as f32;
+            for c in 0..channels {
+                let sample = &mut out.samples[start + i * channels + c];
+                *sample = *sample * (1.0 - t) + chunk.samples[i * channels + c] * t;
+            }
+        }
+        out.samples
+            .extend_from_slice(&chunk.samples[fade * channels..]);
+
+        out.bits_per_sample = out.bits_per_sample.max(chunk.bits_per_sample);
+        previous_frames = chunk.frames();
+    }
+
+    Ok(out)
+}
+
+/// Concatenate segments of audio with `silences_ms[i]` of silence before segment `i + 1`, and
+/// none where `silences_ms` is too short.
+pub(crate) fn concat_with_silence(segments: Vec<Pcm>, silences_ms: &[u32]) -> Result<Pcm, String> {
+    let mut segments = segments.into_iter();
+    let mut out = segments.next().ok_or("no audio to join")?;
+    let channels = out.channels.max(1) as usize;
+
+    for (gap, segment) in segments.enumerate() {
+        if segment.sample_rate != out.sample_rate || segment.channels != out.channels {
+            return Err(format!(
+                "cannot join {} Hz/{} channel audio with {} Hz/{} channel audio",
+                out.sample_rate, out.channels, segment.sample_rate, segment.channels
+            ));
+        }
+
+        let silence_ms = silences_ms.get(gap).copied().unwrap_or_default();
+        let silence = ms_to_frames(out.sample_rate, silence_ms) * channels;
+        out.samples.resize(out.samples.len() + silence, 0.0);
+        out.samples.extend_from_slice(&segment.samples);
+        out.bits_per_sample = out.bits_per_sample.max(segment.bits_per_sample);
+    }
+
+    Ok(out)
+}
+
+/// Insert `silence_ms` of silence before the audio.
+pub(crate) fn prepend_silence(pcm: &mut Pcm, silence_ms: u32) {
+    let silence = ms_to_frames(pcm.sample_rate, silence_ms) * pcm.channels.max(1) as usize;
+    pcm.samples.splice(0..0, vec![0.0; silence]);
+}
+
+/// Length in frames of the crossfade between two chunks, clamped to the shorter one.
+pub(crate) fn crossfade_frames(
+    sample_rate: u32,
+    crossfade_ms: u32,
+    previous_frames: usize,
+    next_frames: usize,
+) -> usize {
+    ms_to_frames(sample_rate, crossfade_ms)
+        .min(previous_frames)
+        .min(next_frames)
+}
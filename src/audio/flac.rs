@@ -0,0 +1,459 @@
+//! FLAC encoder using fixed linear predictors and Rice-coded residuals.
+
+use super::{
+    tags::{self, Metadata},
+    wav::Pcm,
+};
+
+/// Samples per channel in every frame but the last one.
+const BLOCK_SIZE: usize = 4096;
+/// Highest Rice partition order tried for a residual.
+const MAX_PARTITION_ORDER: u32 = 6;
+
+/// Encode `pcm` as a FLAC stream, keeping its sample rate and bit depth, tagged with `metadata`.
+pub(crate) fn encode(pcm: &Pcm, metadata: Option<&Metadata>) -> Result<Vec<u8>, String> {
+    let channels = pcm.channels as usize;
+    if !(1..=8).contains(&channels) {
+        return Err(format!("flac supports 1 to 8 channels, got {}", channels));
+    }
+    if pcm.sample_rate == 0 || pcm.sample_rate >= 1 << 20 {
+        return Err(format!("unsupported flac sample rate: {}", pcm.sample_rate));
+    }
+
+    let bits = pcm.output_bits() as u32;
+    let samples = pcm.quantize();
+    let frames = pcm.frames();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"fLaC");
+    out.extend_from_slice(&stream_info(pcm, bits, frames, metadata.is_none()));
+    if let Some(metadata) = metadata {
+        out.extend_from_slice(&vorbis_comment(metadata));
+    }
+
+    for (index, start) in (0..frames).step_by(BLOCK_SIZE).enumerate() {
+        let block = BLOCK_SIZE.min(frames - start);
+        let mut w = BitWriter::new();
+
+        // frame header
+        w.write(0b11_1111_1111_1110, 14);
+        w.write(0, 1);
+        w.write(0, 1); // fixed block size
+        let block_code = match block {
+            BLOCK_SIZE => 12,
+            1..=256 => 6,
+            _ => 7,
+        };
+        w.write(block_code, 4);
+        w.write(0, 4); // sample rate from STREAMINFO
+        w.write(channels as u64 - 1, 4);
+        let size_code = match bits {
+            8 => 1,
+            16 => 4,
+            24 => 6,
+            _ => 0, // from STREAMINFO
+        };
+        w.write(size_code, 3);
+        w.write(0, 1);
+        write_utf8(&mut w, index as u64);
+        match block_code {
+            6 => w.write(block as u64 - 1, 8),
+            7 => w.write(block as u64 - 1, 16),
+            _ => {}
+        }
+        let crc = crc8(w.bytes());
+        w.write(crc as u64, 8);
+
+        for c in 0..channels {
+            let channel: Vec<i64> = (start..start + block)
+                .map(|frame| samples[frame * channels + c] as i64)
+                .collect();
+            write_subframe(&mut w, &channel, bits);
+        }
+
+        w.align();
+        let crc = crc16(w.bytes());
+        w.write(crc as u64, 16);
+        out.extend_from_slice(w.bytes());
+    }
+
+    Ok(out)
+}
+
+/// The mandatory STREAMINFO metadata block.
+fn stream_info(pcm: &Pcm, bits: u32, frames: usize, last: bool) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    w.write(last as u64, 1); // last metadata block
+    w.write(0, 7); // STREAMINFO
+    w.write(34, 24);
+    w.write(BLOCK_SIZE as u64, 16);
+    w.write(BLOCK_SIZE as u64, 16);
+    w.write(0, 24); // minimum frame size unknown
+    w.write(0, 24); // maximum frame size unknown
+    w.write(pcm.sample_rate as u64, 20);
+    w.write(pcm.channels as u64 - 1, 3);
+    w.write(bits as u64 - 1, 5);
+    w.write((frames as u64 >> 32) & 0xF, 4);
+    w.write(frames as u64 & 0xFFFF_FFFF, 32);
+    for _ in 0..4 {
+        w.write(0, 32); // MD5 signature unknown
+    }
+    w.into_bytes()
+}
+
+/// A VORBIS_COMMENT metadata block, flagged as the last one.
+fn vorbis_comment(metadata: &Metadata) -> Vec<u8> {
+    let comment = tags::vorbis_comment(Some(metadata));
+    let mut out = Vec::with_capacity(4 + comment.len());
+    out.push(0x80 | 4); // last metadata block, VORBIS_COMMENT
+    out.extend_from_slice(&(comment.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(&comment);
+    out
+}
+
+fn write_subframe(w: &mut BitWriter, x: &[i64], bits: u32) {
+    if x.iter().all(|&s| s == x[0]) {
+        w.write(0x00, 8); // CONSTANT
+        w.write_signed(x[0], bits);
+        return;
+    }
+
+    let verbatim_bits = x.len() as u64 * bits as u64;
+    let best = (0..=4usize)
+        .filter(|&order| order < x.len())
+        .filter_map(|order| {
+            let residual = fixed_residual(x, order)?;
+            let rice = RiceCoding::plan(&residual, order, x.len());
+            let cost = order as u64 * bits as u64 + rice.bits;
+            Some((cost, order, residual, rice))
+        })
+        .min_by_key(|(cost, ..)| *cost);
+
+    match best {
+        Some((cost, order, residual, rice)) if cost < verbatim_bits => {
+            w.write(0x10 | ((order as u64) << 1), 8); // FIXED
+            for &sample in &x[..order] {
+                w.write_signed(sample, bits);
+            }
+            rice.write(w, &residual);
+        }
+        _ => {
+            w.write(0x02, 8); // VERBATIM
+            for &sample in x {
+                w.write_signed(sample, bits);
+            }
+        }
+    }
+}
+
+/// Residual of the fixed polynomial predictor of `order`, or `None` if it overflows 32 bits.
+fn fixed_residual(x: &[i64], order: usize) -> Option<Vec<i64>> {
+    let residual: Vec<i64> = (order..x.len())
+        .map(|n| match order {
+            0 => x[n],
+            1 => x[n] - x[n - 1],
+            2 => x[n] - 2 * x[n - 1] + x[n - 2],
+            3 => x[n] - 3 * x[n - 1] + 3 * x[n - 2] - x[n - 3],
+            _ => x[n] - 4 * x[n - 1] + 6 * x[n - 2] - 4 * x[n - 3] + x[n - 4],
+        })
+        .collect();
+    residual
+        .iter()
+        .all(|&r| i32::try_from(r).is_ok())
+        .then_some(residual)
+}
+
+/// Partitioned Rice coding parameters chosen for one residual.
+struct RiceCoding {
+    partition_order: u32,
+    /// Samples per partition; the first partition is shorter by the predictor order.
+    partition_len: usize,
+    params: Vec<u32>,
+    /// Estimated size of the coded residual in bits.
+    bits: u64,
+}
+
+impl RiceCoding {
+    fn plan(residual: &[i64], order: usize, block: usize) -> Self {
+        let mut best: Option<RiceCoding> = None;
+        for partition_order in 0..=MAX_PARTITION_ORDER {
+            let partitions = 1usize << partition_order;
+            if block % partitions != 0 || block / partitions <= order {
+                break;
+            }
+
+            let per_partition = block / partitions;
+            let mut params = Vec::with_capacity(partitions);
+            let mut bits = 2 + 4;
+            let mut pos = 0;
+            for p in 0..partitions {
+                let len = match p {
+                    0 => per_partition - order,
+                    _ => per_partition,
+                };
+                let sum: u64 = residual[pos..pos + len].iter().map(|&r| zigzag(r)).sum();
+                let (param, cost) = best_param(sum, len as u64);
+                params.push(param);
+                bits += cost;
+                pos += len;
+            }
+            let param_bits = match params.iter().any(|&k| k > 14) {
+                true => 5,
+                false => 4,
+            };
+            bits += param_bits * partitions as u64;
+
+            if best.as_ref().filter(|b| b.bits <= bits).is_none() {
+                best = Some(RiceCoding {
+                    partition_order,
+                    partition_len: per_partition,
+                    params,
+                    bits,
+                });
+            }
+        }
+        best.expect("partition order 0 is always valid")
+    }
+
+    fn write(&self, w: &mut BitWriter, residual: &[i64]) {
+        let rice2 = self.params.iter().any(|&k| k > 14);
+        w.write(rice2 as u64, 2);
+        w.write(self.partition_order as u64, 4);
+
+        let first = residual.len() - self.partition_len * (self.params.len() - 1);
+        let mut pos = 0;
+        for (p, &k) in self.params.iter().enumerate() {
+            w.write(k as u64, if rice2 { 5 } else { 4 });
+            let len = match p {
+                0 => first,
+                _ => self.partition_len,
+            };
+            for &r in &residual[pos..pos + len] {
+                let u = zigzag(r);
+                w.write_unary(u >> k);
+                w.write(u & ((1 << k) - 1), k);
+            }
+            pos += len;
+        }
+    }
+}
+
+/// Rice parameter minimizing the estimated size of `len` values summing to `sum`.
+fn best_param(sum: u64, len: u64) -> (u32, u64) {
+    (0..=30)
+        .map(|k| (k, len * (k as u64 + 1) + (sum >> k)))
+        .min_by_key(|&(_, bits)| bits)
+        .expect("non-empty range")
+}
+
+fn zigzag(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+fn write_utf8(w: &mut BitWriter, n: u64) {
+    if n < 0x80 {
+        w.write(n, 8);
+        return;
+    }
+    let len = match n {
+        0..=0x7FF => 2,
+        0x800..=0xFFFF => 3,
+        0x1_0000..=0x1F_FFFF => 4,
+        0x20_0000..=0x3FF_FFFF => 5,
+        0x400_0000..=0x7FFF_FFFF => 6,
+        _ => 7,
+    };
+    let lead = (0xFF00u64 >> len) & 0xFF;
+    w.write(lead | (n >> (6 * (len - 1))), 8);
+    for i in (0..len - 1).rev() {
+        w.write(0x80 | ((n >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = match crc & 0x80 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x07,
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x8005,
+            };
+        }
+    }
+    crc
+}
+
+/// Big-endian bit writer.
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Write the low `bits` bits of `value`, at most 32 at a time.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc = (self.acc << bits) | (value & ((1u64 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1u64 << self.bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// Write `n` zero bits followed by a one.
+    fn write_unary(&mut self, mut n: u64) {
+        while n >= 32 {
+            self.write(0, 32);
+            n -= 32;
+        }
+        self.write(1, n as u32 + 1);
+    }
+
+    /// Pad with zero bits up to the next byte boundary.
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+
+    /// The complete bytes written so far.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc8_matches_the_check_value() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn crc16_matches_the_check_value() {
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+        assert_eq!(crc16(&[]), 0);
+    }
+
+    fn utf8(n: u64) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        write_utf8(&mut w, n);
+        w.into_bytes()
+    }
+
+    #[test]
+    fn write_utf8_codes_multi_byte_frame_numbers() {
+        assert_eq!(utf8(0x7F), [0x7F]);
+        assert_eq!(utf8(0x80), [0xC2, 0x80]);
+        assert_eq!(utf8(0x7FF), [0xDF, 0xBF]);
+        assert_eq!(utf8(0x800), [0xE0, 0xA0, 0x80]);
+        assert_eq!(utf8(0x1_0000), [0xF0, 0x90, 0x80, 0x80]);
+        assert_eq!(utf8(0x20_0000), [0xF8, 0x88, 0x80, 0x80, 0x80]);
+        assert_eq!(utf8(0x400_0000), [0xFC, 0x84, 0x80, 0x80, 0x80, 0x80]);
+        assert_eq!(
+            utf8(0xF_FFFF_FFFF),
+            [0xFE, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]
+        );
+    }
+
+    #[test]
+    fn rice_plan_shortens_the_first_partition_by_the_order() {
+        // 8 samples with an order 3 predictor: partitions of 4 leave 1 residual in the first one
+        let residual = [0, 1000, -1000, 1000, -1000];
+        let coding = RiceCoding::plan(&residual, 3, 8);
+        assert_eq!(coding.partition_order, 1);
+        assert_eq!(coding.partition_len, 4);
+        assert_eq!(coding.params[0], 0);
+        assert!(coding.params[1] > 0);
+
+        let mut w = BitWriter::new();
+        coding.write(&mut w, &residual);
+        let bytes = w.into_bytes();
+        // rice method and partition order, then the parameter and the unary zero of the first partition
+        assert_eq!(bytes[0], 0b0000_0100);
+        assert_eq!(bytes[1] >> 5, 0b001);
+    }
+
+    #[test]
+    fn rice_plan_stops_before_partitions_shorter_than_the_order() {
+        let residual = [0, 0, 0, 0, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000];
+        let coding = RiceCoding::plan(&residual, 4, 16);
+        // partitions of 4 samples would leave nothing to the first one
+        assert_eq!(coding.partition_order, 1);
+        assert_eq!(coding.partition_len, 8);
+    }
+
+    /// Undo `fixed_residual` from the warm-up samples.
+    fn restore(warmup: &[i64], residual: &[i64], order: usize) -> Vec<i64> {
+        let mut x = warmup.to_vec();
+        for &r in residual {
+            let n = x.len();
+            let prediction = match order {
+                0 => 0,
+                1 => x[n - 1],
+                2 => 2 * x[n - 1] - x[n - 2],
+                3 => 3 * x[n - 1] - 3 * x[n - 2] + x[n - 3],
+                _ => 4 * x[n - 1] - 6 * x[n - 2] + 4 * x[n - 3] - x[n - 4],
+            };
+            x.push(prediction + r);
+        }
+        x
+    }
+
+    #[test]
+    fn fixed_residual_round_trips_a_short_block() {
+        let block = [0, 3, -7, 12, 40, -100, 5, 9, 32767, -32768];
+        for order in 0..=4 {
+            let residual = fixed_residual(&block, order).unwrap();
+            assert_eq!(residual.len(), block.len() - order);
+            assert_eq!(restore(&block[..order], &residual, order), block);
+        }
+    }
+
+    #[test]
+    fn fixed_residual_rejects_residuals_over_32_bits() {
+        let block = [i32::MIN as i64, i32::MAX as i64];
+        assert_eq!(fixed_residual(&block, 0), Some(block.to_vec()));
+        assert_eq!(fixed_residual(&block, 1), None);
+    }
+}
@@ -0,0 +1,217 @@
+//! Integrated loudness as defined by ITU-R BS.1770, and normalization to a target loudness
+//! with a true-peak limiter.
+
+use super::{dsp, wav::Pcm};
+use std::collections::VecDeque;
+
+/// Loudness targets accepted, in LUFS.
+pub(crate) const TARGET_LUFS_RANGE: std::ops::RangeInclusive<f32> = -40.0..=0.0;
+/// True-peak level the limiter keeps the normalized audio under, in dBTP.
+const TRUE_PEAK_CEILING_DBTP: f32 = -1.0;
+/// Oversampling used to estimate the true peaks between samples.
+const TRUE_PEAK_OVERSAMPLING: u32 = 4;
+/// Length of the gating blocks, in milliseconds.
+const BLOCK_MS: u32 = 400;
+/// Step between gating blocks, for the 75% overlap of the standard, in milliseconds.
+const BLOCK_STEP_MS: u32 = 100;
+/// Blocks quieter than this are left out of the measurement, in LUFS.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// Blocks this far below the absolute-gated loudness are left out of the measurement, in LU.
+const RELATIVE_GATE_LU: f64 = -10.0;
+/// How early the limiter starts reducing the gain before a peak, in milliseconds.
+const LIMITER_LOOKAHEAD_MS: u32 = 5;
+/// Time constant of the limiter gain recovering after a peak, in milliseconds.
+const LIMITER_RELEASE_MS: f64 = 50.0;
+
+/// Integrated loudness of `pcm` in LUFS, or `None` if it is silent.
+pub(crate) fn integrated_loudness(pcm: &Pcm) -> Option<f64> {
+    let channels = pcm.channels.max(1) as usize;
+    let frames = pcm.frames();
+    if frames == 0 {
+        return None;
+    }
+
+    // running sum of the K-weighted power, summed over the channels (all weighted 1.0)
+    let mut power_sums = Vec::with_capacity(frames + 1);
+    power_sums.push(0.0);
+    let mut total = 0.0;
+    for frame in k_weighted(pcm).chunks_exact(channels) {
+        total += frame.iter().map(|s| s * s).sum::<f64>();
+        power_sums.push(total);
+    }
+
+    // audio shorter than a block is measured as a single block
+    let block = dsp::ms_to_frames(pcm.sample_rate, BLOCK_MS).clamp(1, frames);
+    let step = dsp::ms_to_frames(pcm.sample_rate, BLOCK_STEP_MS).max(1);
+    let blocks: Vec<f64> = (0..=frames - block)
+        .step_by(step)
+        .map(|start| (power_sums[start + block] - power_sums[start]) / block as f64)
+        .filter(|&power| loudness(power) > ABSOLUTE_GATE_LUFS)
+        .collect();
+    if blocks.is_empty() {
+        return None;
+    }
+
+    let threshold = loudness(mean(&blocks)) + RELATIVE_GATE_LU;
+    let gated: Vec<f64> = blocks
+        .into_iter()
+        .filter(|&power| loudness(power) > threshold)
+        .collect();
+
+    Some(loudness(mean(&gated)))
+}
+
+/// Scale `pcm` to an integrated loudness of `target_lufs`, then limit its true peaks.
+///
+/// Silent audio is left untouched. The limiter may leave loud audio slightly under the target.
+pub(crate) fn normalize(pcm: &mut Pcm, target_lufs: f32) {
+    let measured = match integrated_loudness(pcm) {
+        Some(measured) => measured,
+        None => return,
+    };
+
+    let gain = 10f64.powf((target_lufs as f64 - measured) / 20.0) as f32;
+    for sample in &mut pcm.samples {
+        *sample *= gain;
+    }
+
+    limit_true_peaks(pcm, 10f32.powf(TRUE_PEAK_CEILING_DBTP / 20.0));
+}
+
+/// Reduce the gain around the true peaks of `pcm` over `ceiling`, with a short lookahead so that
+/// the gain is already down when a peak comes and a release so that it recovers smoothly.
+fn limit_true_peaks(pcm: &mut Pcm, ceiling: f32) {
+    let channels = pcm.channels.max(1) as usize;
+    let frames = pcm.frames();
+    let required: Vec<f32> = true_peaks(pcm)
+        .into_iter()
+        .map(|peak| match peak > ceiling {
+            true => ceiling / peak,
+            false => 1.0,
+        })
+        .collect();
+    if required.iter().all(|&gain| gain >= 1.0) {
+        return;
+    }
+
+    // lowest required gain over the lookahead window starting at every frame
+    let lookahead = dsp::ms_to_frames(pcm.sample_rate, LIMITER_LOOKAHEAD_MS).max(1);
+    let mut window: VecDeque<usize> = VecDeque::new();
+    let mut gains = vec![1.0f32; frames];
+    for n in (0..frames).rev() {
+        while window.back().is_some_and(|&k| required[k] >= required[n]) {
+            window.pop_back();
+        }
+        window.push_back(n);
+        while window.front().is_some_and(|&k| k >= n + lookahead) {
+            window.pop_front();
+        }
+        gains[n] = required[window[0]];
+    }
+
+    // the gain may only recover gradually, which never raises it over the required one
+    let release = 1.0 - (-1000.0 / (LIMITER_RELEASE_MS * pcm.sample_rate as f64)).exp();
+    let mut previous = 1.0f32;
+    for gain in &mut gains {
+        *gain = gain.min(previous + (1.0 - previous) * release as f32);
+        previous = *gain;
+    }
+
+    // averaging over the lookahead smooths the attack, and every window covering a frame only
+    // holds gains at most as high as the one that frame requires
+    let mut sum = 0.0f64;
+    for (n, &gain) in gains.iter().enumerate() {
+        sum += gain as f64;
+        if n >= lookahead {
+            sum -= gains[n - lookahead] as f64;
+        }
+        let smoothed = (sum / lookahead.min(n + 1) as f64) as f32;
+        for sample in &mut pcm.samples[n * channels..(n + 1) * channels] {
+            *sample *= smoothed;
+        }
+    }
+}
+
+/// Highest absolute level of every frame of `pcm`, including the peaks between its samples.
+fn true_peaks(pcm: &Pcm) -> Vec<f32> {
+    let channels = pcm.channels.max(1) as usize;
+    let mut oversampled = pcm.clone();
+    dsp::resample(&mut oversampled, pcm.sample_rate * TRUE_PEAK_OVERSAMPLING);
+
+    let per_frame = TRUE_PEAK_OVERSAMPLING as usize * channels;
+    pcm.samples
+        .chunks_exact(channels)
+        .enumerate()
+        .map(|(n, frame)| {
+            let start = (n * per_frame).min(oversampled.samples.len());
+            let end = ((n + 1) * per_frame).min(oversampled.samples.len());
+            frame
+                .iter()
+                .chain(&oversampled.samples[start..end])
+                .fold(0.0f32, |peak, s| peak.max(s.abs()))
+        })
+        .collect()
+}
+
+/// The interleaved samples of `pcm` through the K-weighting filter: a high shelf modelling the
+/// head, then a high-pass filter.
+fn k_weighted(pcm: &Pcm) -> Vec<f64> {
+    use std::f64::consts::PI;
+
+    let rate = pcm.sample_rate as f64;
+    let channels = pcm.channels.max(1) as usize;
+
+    // coefficients of BS.1770 recomputed for the sample rate, as libebur128 does
+    let k = (PI * 1_681.974_450_955_533 / rate).tan();
+    let q = 0.707_175_236_955_419_6;
+    let vh = 10f64.powf(3.999_843_853_973_347 / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    let k = (PI * 38.135_470_876_024_44 / rate).tan();
+    let q = 0.500_327_037_323_877_3;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    let mut out: Vec<f64> = pcm.samples.iter().map(|&s| s as f64).collect();
+    for c in 0..channels {
+        for filter in [&shelf, &high_pass] {
+            let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+            for sample in out.iter_mut().skip(c).step_by(channels) {
+                let x = *sample;
+                let y = filter.b[0] * x + filter.b[1] * x1 + filter.b[2] * x2
+                    - filter.a[0] * y1
+                    - filter.a[1] * y2;
+                (x2, x1, y2, y1) = (x1, x, y1, y);
+                *sample = y;
+            }
+        }
+    }
+    out
+}
+
+/// Coefficients of a biquad filter, normalized so that `a0` is 1.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+/// Loudness in LUFS of a mean square K-weighted power.
+fn loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
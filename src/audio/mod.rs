@@ -0,0 +1,617 @@
+//! Post-processing of the audio produced by the TTS backends.
+
+#[cfg(feature = "aac")]
+pub(crate) mod aac;
+pub(crate) mod align;
+pub(crate) mod dsp;
+pub(crate) mod flac;
+pub(crate) mod loudness;
+#[cfg(feature = "mp3")]
+pub(crate) mod mp3;
+#[cfg(feature = "opus")]
+mod ogg;
+#[cfg(feature = "opus")]
+pub(crate) mod opus;
+pub(crate) mod tags;
+pub(crate) mod wav;
+
+use align::WordTimestamp;
+use serde::{Deserialize, Serialize};
+use wav::Pcm;
+
+/// Default RMS level, relative to full scale, below which audio counts as silence.
+pub(crate) const DEFAULT_SILENCE_THRESHOLD: f32 = 0.01;
+/// Audio kept around the speech when trimming silence, in milliseconds.
+const TRIM_GUARD_MS: u32 = 20;
+/// Default crossfade between chunks of synthesized audio, in milliseconds.
+pub(crate) const DEFAULT_CROSSFADE_MS: u32 = 5;
+/// Longest crossfade accepted between chunks, in milliseconds.
+const MAX_CROSSFADE_MS: u32 = 100;
+/// Default silence between the segments of an input, in milliseconds.
+const DEFAULT_SEGMENT_SILENCE_MS: u32 = 250;
+/// Longest silence accepted between segments, in milliseconds.
+const MAX_SEGMENT_SILENCE_MS: u32 = 5000;
+/// Base name of downloaded audio files when the request does not set `filename`.
+const DEFAULT_FILENAME: &str = "speech";
+/// Longest base name kept from the `filename` of a request, in characters.
+const MAX_FILENAME_CHARS: usize = 100;
+/// Longest silence accepted before the speech, in milliseconds.
+const MAX_LEAD_SILENCE_MS: u32 = 2000;
+/// Slowest speed accepted, as in the OpenAI API.
+const MIN_SPEED: f32 = 0.25;
+/// Fastest speed accepted, as in the OpenAI API.
+const MAX_SPEED: f32 = 4.0;
+/// Largest pitch shift accepted in either direction, in semitones.
+const MAX_PITCH_SEMITONES: f32 = 12.0;
+/// Output sample rates the audio can be resampled to, in Hz.
+pub(crate) const SUPPORTED_SAMPLE_RATES: [u32; 8] =
+    [8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000];
+/// Bit depths wav and flac output can be written with.
+const SUPPORTED_BIT_DEPTHS: [u16; 2] = [16, 24];
+
+/// Audio formats the speech endpoint can respond with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ResponseFormat {
+    #[default]
+    Wav,
+    Opus,
+    Flac,
+    Pcm,
+    Aac,
+    Mp3,
+}
+
+impl ResponseFormat {
+    /// Value of the `Content-Type` header for this format.
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            ResponseFormat::Wav => "audio/wav",
+            ResponseFormat::Opus => "audio/ogg",
+            ResponseFormat::Flac => "audio/flac",
+            ResponseFormat::Pcm => "audio/pcm",
+            ResponseFormat::Aac => "audio/aac",
+            ResponseFormat::Mp3 => "audio/mpeg",
+        }
+    }
+
+    /// File extension for this format.
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            ResponseFormat::Wav => "wav",
+            ResponseFormat::Opus => "ogg",
+            ResponseFormat::Flac => "flac",
+            ResponseFormat::Pcm => "pcm",
+            ResponseFormat::Aac => "aac",
+            ResponseFormat::Mp3 => "mp3",
+        }
+    }
+}
+impl std::fmt::Display for ResponseFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ResponseFormat::Wav => write!(f, "wav"),
+            ResponseFormat::Opus => write!(f, "opus"),
+            ResponseFormat::Flac => write!(f, "flac"),
+            ResponseFormat::Pcm => write!(f, "pcm"),
+            ResponseFormat::Aac => write!(f, "aac"),
+            ResponseFormat::Mp3 => write!(f, "mp3"),
+        }
+    }
+}
+
+/// Output options accepted by the speech endpoint of every backend.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct SpeechOptions {
+    /// The format of the returned audio. Defaults to `--default-response-format`, or `wav`.
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    /// Bitrate in bits per second for compressed formats.
+    #[serde(default)]
+    pub bitrate: Option<u32>,
+    /// Sample rate of the returned audio in Hz. Defaults to the native rate of the model.
+    #[serde(default)]
+    pub sample_rate: Option<u32>,
+    /// Bit depth of wav and flac output. Defaults to 16.
+    #[serde(default)]
+    pub bit_depth: Option<u16>,
+    /// Tags embedded in the formats that can carry them, ignored for the others.
+    #[serde(default)]
+    pub metadata: Option<tags::Metadata>,
+    /// Trim leading and trailing silence from the synthesized audio.
+    #[serde(default)]
+    pub trim_silence: bool,
+    /// RMS level (0.0 - 1.0 of full scale) below which audio is trimmed as silence.
+    #[serde(default)]
+    pub silence_threshold: Option<f32>,
+    /// Crossfade in milliseconds between chunks when the audio is synthesized in pieces.
+    #[serde(default)]
+    pub crossfade_ms: Option<u32>,
+    /// Speed of the speech, 1.0 being the natural speed of the voice, applied without changing
+    /// its pitch.
+    #[serde(default)]
+    pub speed: Option<f32>,
+    /// Pitch shift in semitones, applied without changing the duration of the audio.
+    #[serde(default, alias = "pitch")]
+    pub pitch_semitones: Option<f32>,
+    /// `Content-Type` of the response, replacing the one of the response format.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// Base name of the downloaded file, completed with the extension of the response format.
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// Silence in milliseconds inserted between the segments of an array `input`.
+    #[serde(default)]
+    pub segment_silence_ms: Option<u32>,
+    /// Silence in milliseconds before every segment but the first, overriding
+    /// `segment_silence_ms` for the segments it covers.
+    #[serde(default)]
+    pub segment_silences_ms: Option<Vec<u32>>,
+    /// Integrated loudness in LUFS the audio is normalized to. Defaults to `--default-target-lufs`.
+    #[serde(default)]
+    pub target_lufs: Option<f32>,
+    /// Silence in milliseconds prepended to the audio, for players that clip its start.
+    #[serde(default)]
+    pub lead_silence_ms: u32,
+    /// Style guidance in the OpenAI format, e.g. "speak cheerfully".
+    #[serde(default)]
+    pub instructions: Option<String>,
+    /// Return estimated word-level timestamps along with the audio.
+    #[serde(default)]
+    pub timestamps: bool,
+}
+
+impl SpeechOptions {
+    /// The requested response format, falling back to the default one.
+    pub(crate) fn format(&self) -> ResponseFormat {
+        self.response_format
+            .or_else(|| crate::DEFAULT_RESPONSE_FORMAT.get().copied())
+            .unwrap_or_default()
+    }
+
+    /// The requested loudness target, falling back to the default one.
+    pub(crate) fn target_lufs(&self) -> Option<f32> {
+        self.target_lufs
+            .or_else(|| crate::DEFAULT_TARGET_LUFS.get().copied())
+    }
+
+    /// Check the option values, returning a message suitable for a 400 response.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let format = self.format();
+        match format {
+            ResponseFormat::Wav | ResponseFormat::Flac | ResponseFormat::Pcm => {
+                if self.bitrate.is_some() {
+                    return Err(format!(
+                        "`bitrate` is not supported for the `{}` format",
+                        format
+                    ));
+                }
+            }
+            #[cfg(feature = "opus")]
+            ResponseFormat::Opus => {
+                if let Some(bitrate) = self.bitrate {
+                    if !opus::BITRATE_RANGE.contains(&bitrate) {
+                        return Err(format!(
+                            "`bitrate` for the `opus` format must be between {} and {}, got {}",
+                            opus::BITRATE_RANGE.start(),
+                            opus::BITRATE_RANGE.end(),
+                            bitrate
+                        ));
+                    }
+                }
+            }
+            #[cfg(not(feature = "opus"))]
+            ResponseFormat::Opus => {
+                return Err("the `opus` format is not enabled in this build".to_string());
+            }
+            #[cfg(feature = "aac")]
+            ResponseFormat::Aac => {
+                if let Some(bitrate) = self.bitrate {
+                    if !aac::BITRATE_RANGE.contains(&bitrate) {
+                        return Err(format!(
+                            "`bitrate` for the `aac` format must be between {} and {}, got {}",
+                            aac::BITRATE_RANGE.start(),
+                            aac::BITRATE_RANGE.end(),
+                            bitrate
+                        ));
+                    }
+                }
+            }
+            #[cfg(not(feature = "aac"))]
+            ResponseFormat::Aac => {
+                return Err("the `aac` format is not enabled in this build".to_string());
+            }
+            #[cfg(feature = "mp3")]
+            ResponseFormat::Mp3 => {
+                if let Some(bitrate) = self.bitrate {
+                    if !mp3::BITRATES.contains(&bitrate) {
+                        return Err(format!(
+                            "`bitrate` for the `mp3` format must be one of {:?}, got {}",
+                            mp3::BITRATES,
+                            bitrate
+                        ));
+                    }
+                }
+            }
+            #[cfg(not(feature = "mp3"))]
+            ResponseFormat::Mp3 => {
+                return Err("the `mp3` format is not enabled in this build".to_string());
+            }
+        }
+
+        if let Some(sample_rate) = self.sample_rate {
+            if !SUPPORTED_SAMPLE_RATES.contains(&sample_rate) {
+                return Err(format!(
+                    "`sample_rate` must be one of {:?}, got {}",
+                    SUPPORTED_SAMPLE_RATES, sample_rate
+                ));
+            }
+        }
+
+        if let Some(bit_depth) = self.bit_depth {
+            if !matches!(format, ResponseFormat::Wav | ResponseFormat::Flac) {
+                return Err(format!(
+                    "`bit_depth` is not supported for the `{}` format",
+                    format
+                ));
+            }
+            if !SUPPORTED_BIT_DEPTHS.contains(&bit_depth) {
+                return Err(format!(
+                    "`bit_depth` must be one of {:?}, got {}",
+                    SUPPORTED_BIT_DEPTHS, bit_depth
+                ));
+            }
+        }
+
+        if let Some(metadata) = &self.metadata {
+            metadata.validate()?;
+        }
+
+        if let Some(threshold) = self.silence_threshold {
+            if !(threshold > 0.0 && threshold < 1.0) {
+                return Err(format!(
+                    "`silence_threshold` must be between 0.0 and 1.0, got {}",
+                    threshold
+                ));
+            }
+        }
+
+        if let Some(crossfade_ms) = self.crossfade_ms {
+            if crossfade_ms > MAX_CROSSFADE_MS {
+                return Err(format!(
+                    "`crossfade_ms` must be at most {}, got {}",
+                    MAX_CROSSFADE_MS, crossfade_ms
+                ));
+            }
+        }
+
+        if let Some(speed) = self.speed {
+            if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+                return Err(format!(
+                    "`speed` must be between {} and {}, got {}",
+                    MIN_SPEED, MAX_SPEED, speed
+                ));
+            }
+        }
+
+        if let Some(semitones) = self.pitch_semitones {
+            if !(-MAX_PITCH_SEMITONES..=MAX_PITCH_SEMITONES).contains(&semitones) {
+                return Err(format!(
+                    "`pitch_semitones` must be between -{} and {}, got {}",
+                    MAX_PITCH_SEMITONES, MAX_PITCH_SEMITONES, semitones
+                ));
+            }
+        }
+
+        if let Some(segment_silence_ms) = self.segment_silence_ms {
+            if segment_silence_ms > MAX_SEGMENT_SILENCE_MS {
+                return Err(format!(
+                    "`segment_silence_ms` must be at most {}, got {}",
+                    MAX_SEGMENT_SILENCE_MS, segment_silence_ms
+                ));
+            }
+        }
+
+        if let Some(silence_ms) = self
+            .segment_silences_ms
+            .iter()
+            .flatten()
+            .find(|&&silence_ms| silence_ms > MAX_SEGMENT_SILENCE_MS)
+        {
+            return Err(format!(
+                "`segment_silences_ms` values must be at most {}, got {}",
+                MAX_SEGMENT_SILENCE_MS, silence_ms
+            ));
+        }
+
+        if let Some(target_lufs) = self.target_lufs {
+            if !loudness::TARGET_LUFS_RANGE.contains(&target_lufs) {
+                return Err(format!(
+                    "`target_lufs` must be between {} and {}, got {}",
+                    loudness::TARGET_LUFS_RANGE.start(),
+                    loudness::TARGET_LUFS_RANGE.end(),
+                    target_lufs
+                ));
+            }
+        }
+
+        if self.lead_silence_ms > MAX_LEAD_SILENCE_MS {
+            return Err(format!(
+                "`lead_silence_ms` must be at most {}, got {}",
+                MAX_LEAD_SILENCE_MS, self.lead_silence_ms
+            ));
+        }
+
+        if let Some(content_type) = &self.content_type {
+            if !is_mime_type(content_type) {
+                return Err(format!(
+                    "`content_type` must be a MIME type like `audio/wav`, got `{}`",
+                    content_type
+                ));
+            }
+        }
+
+        if self.timestamps && self.trim_silence {
+            return Err("`timestamps` cannot be combined with `trim_silence`".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Whether any option requires the audio to be decoded and re-encoded.
+    fn needs_processing(&self) -> bool {
+        self.format() != ResponseFormat::Wav
+            || self.sample_rate.is_some()
+            || self.bit_depth.is_some()
+            || self.trim_silence
+            || self.lead_silence_ms > 0
+            || self.target_lufs().is_some()
+            || self.speed.filter(|&s| s != 1.0).is_some()
+            || self.pitch_semitones.filter(|&s| s != 0.0).is_some()
+            || self.timestamps
+    }
+}
+
+/// Encoded audio ready to be sent back to the client.
+#[derive(Clone)]
+pub(crate) struct EncodedAudio {
+    pub data: Vec<u8>,
+    pub format: ResponseFormat,
+    /// Length of the audio in seconds.
+    pub duration: f64,
+    /// Sample rate of headerless output, which clients cannot read from the data itself.
+    pub sample_rate: Option<u32>,
+    /// Estimated word timings, present when they were requested.
+    pub words: Option<Vec<WordTimestamp>>,
+    /// `Content-Type` requested by the client instead of the one of the format.
+    pub content_type: Option<String>,
+    /// Base name of the downloaded file requested by the client.
+    pub filename: Option<String>,
+}
+
+impl EncodedAudio {
+    /// Value of the `Content-Type` header for this audio.
+    pub(crate) fn content_type(&self) -> &str {
+        self.content_type
+            .as_deref()
+            .unwrap_or_else(|| self.format.content_type())
+    }
+
+    /// Value of the `Content-Disposition` header for this audio, e.g.
+    /// `attachment; filename="speech.wav"`.
+    pub(crate) fn content_disposition(&self) -> String {
+        format!("attachment; filename=\"{}\"", self.file_name())
+    }
+
+    /// Name of the file of this audio, the requested one or `speech`, with the extension of
+    /// the format, e.g. `speech.wav`.
+    pub(crate) fn file_name(&self) -> String {
+        let name = self
+            .filename
+            .as_deref()
+            .map(sanitize_filename)
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| DEFAULT_FILENAME.to_string());
+        format!("{}.{}", name, self.format.extension())
+    }
+}
+
+/// Reduce a requested file name to ASCII characters that are safe in a quoted header parameter
+/// and on any file system, dropping directories and a trailing extension.
+fn sanitize_filename(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name = match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => name,
+    };
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || "-_. ".contains(c) {
+            true => c,
+            false => '_',
+        })
+        .take(MAX_FILENAME_CHARS)
+        .collect();
+
+    sanitized
+        .trim_matches(|c: char| c == '.' || c == ' ')
+        .to_string()
+}
+
+/// Whether `value` looks like a MIME type: `type/subtype`, optionally followed by parameters.
+fn is_mime_type(value: &str) -> bool {
+    let is_token = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&^_.+-".contains(c))
+    };
+    let (essence, parameters) = value.split_once(';').unwrap_or((value, ""));
+    let valid_essence = match essence.trim().split_once('/') {
+        Some((kind, subtype)) => is_token(kind) && is_token(subtype),
+        None => false,
+    };
+
+    valid_essence
+        && parameters
+            .chars()
+            .all(|c| c.is_ascii() && !c.is_ascii_control())
+}
+
+/// A wav chunk produced by a backend and the text it was synthesized from.
+pub(crate) struct Chunk {
+    pub text: String,
+    pub wav: Vec<u8>,
+}
+
+/// Join the wav chunks produced by a backend, apply the requested post-processing and encode
+/// the result in the requested format.
+///
+/// Chunks of a segment are crossfaded, segments are separated by silence.
+pub(crate) fn process(
+    mut segments: Vec<Vec<Chunk>>,
+    options: &SpeechOptions,
+) -> Result<EncodedAudio, String> {
+    let format = options.format();
+    if segments.len() == 1 && segments[0].len() == 1 && !options.needs_processing() {
+        let wav = segments.remove(0).remove(0).wav;
+        return Ok(EncodedAudio {
+            duration: Pcm::from_wav(&wav)
+                .map(|pcm| pcm.duration())
+                .unwrap_or_default(),
+            data: wav,
+            format,
+            sample_rate: None,
+            words: None,
+            content_type: options.content_type.clone(),
+            filename: options.filename.clone(),
+        });
+    }
+
+    let crossfade_ms = options.crossfade_ms.unwrap_or(DEFAULT_CROSSFADE_MS);
+    let silence_ms = options
+        .segment_silence_ms
+        .unwrap_or(DEFAULT_SEGMENT_SILENCE_MS);
+    // silence before every segment but the first
+    let silences_ms: Vec<u32> = (0..segments.len().saturating_sub(1))
+        .map(|gap| {
+            options
+                .segment_silences_ms
+                .as_ref()
+                .and_then(|silences_ms| silences_ms.get(gap).copied())
+                .unwrap_or(silence_ms)
+        })
+        .collect();
+    let mut joined = Vec::with_capacity(segments.len());
+    let mut words = Vec::new();
+    // start of the current chunk in the final audio, in frames
+    let mut offset = 0;
+    for chunks in &segments {
+        let mut pcms: Vec<Pcm> = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            let pcm = Pcm::from_wav(&chunk.wav)?;
+            if options.timestamps {
+                match pcms.last() {
+                    Some(previous) => {
+                        offset -= dsp::crossfade_frames(
+                            pcm.sample_rate,
+                            crossfade_ms,
+                            previous.frames(),
+                            pcm.frames(),
+                        )
+                    }
+                    None if !joined.is_empty() => {
+                        offset += dsp::ms_to_frames(pcm.sample_rate, silences_ms[joined.len() - 1])
+                    }
+                    None => {}
+                }
+                words.extend(align::estimate(&chunk.text, &pcm, offset));
+                offset += pcm.frames();
+            }
+            pcms.push(pcm);
+        }
+        joined.push(dsp::join(pcms, crossfade_ms)?);
+    }
+    let mut pcm = dsp::concat_with_silence(joined, &silences_ms)?;
+
+    if let Some(speed) = options.speed.filter(|&speed| speed != 1.0) {
+        dsp::change_speed(&mut pcm, speed);
+        align::change_speed(&mut words, speed);
+    }
+
+    if options.trim_silence {
+        let threshold = options
+            .silence_threshold
+            .unwrap_or(DEFAULT_SILENCE_THRESHOLD);
+        dsp::trim_silence(&mut pcm, threshold, TRIM_GUARD_MS);
+    }
+
+    if let Some(semitones) = options.pitch_semitones {
+        dsp::pitch_shift(&mut pcm, semitones);
+    }
+
+    if let Some(sample_rate) = options.sample_rate {
+        dsp::resample(&mut pcm, sample_rate);
+    }
+
+    if let Some(target_lufs) = options.target_lufs() {
+        loudness::normalize(&mut pcm, target_lufs);
+    }
+
+    // after resampling, so that the silence is generated at the output sample rate
+    if options.lead_silence_ms > 0 {
+        dsp::prepend_silence(&mut pcm, options.lead_silence_ms);
+        let lead_secs = options.lead_silence_ms as f64 / 1000.0;
+        for word in &mut words {
+            word.start += lead_secs;
+            word.end += lead_secs;
+        }
+    }
+
+    if let Some(bit_depth) = options.bit_depth {
+        pcm.bits_per_sample = bit_depth;
+    }
+
+    let data = match format {
+        ResponseFormat::Wav => pcm.to_wav(),
+        ResponseFormat::Flac => flac::encode(&pcm, options.metadata.as_ref())?,
+        ResponseFormat::Pcm => {
+            pcm.bits_per_sample = 16;
+            pcm.to_le_bytes()
+        }
+        #[cfg(feature = "opus")]
+        ResponseFormat::Opus => opus::encode(
+            &pcm,
+            options.bitrate.unwrap_or(opus::DEFAULT_BITRATE),
+            options.metadata.as_ref(),
+        )?,
+        #[cfg(not(feature = "opus"))]
+        ResponseFormat::Opus => return Err("the `opus` format is not enabled".to_string()),
+        #[cfg(feature = "aac")]
+        ResponseFormat::Aac => aac::encode(&pcm, options.bitrate.unwrap_or(aac::DEFAULT_BITRATE))?,
+        #[cfg(not(feature = "aac"))]
+        ResponseFormat::Aac => return Err("the `aac` format is not enabled".to_string()),
+        #[cfg(feature = "mp3")]
+        ResponseFormat::Mp3 => mp3::encode(
+            &pcm,
+            options.bitrate.unwrap_or(mp3::DEFAULT_BITRATE),
+            options.metadata.as_ref(),
+        )?,
+        #[cfg(not(feature = "mp3"))]
+        ResponseFormat::Mp3 => return Err("the `mp3` format is not enabled".to_string()),
+    };
+
+    let sample_rate = match format {
+        ResponseFormat::Pcm => Some(pcm.sample_rate),
+        _ => None,
+    };
+
+    Ok(EncodedAudio {
+        data,
+        format,
+        duration: pcm.duration(),
+        sample_rate,
+        words: options.timestamps.then_some(words),
+        content_type: options.content_type.clone(),
+        filename: options.filename.clone(),
+    })
+}
@@ -0,0 +1,83 @@
+//! MP3 encoding with LAME, the format served as `audio/mpeg`.
+
+use super::{
+    tags::{self, Metadata},
+    wav::Pcm,
+};
+use mp3lame_encoder::{Bitrate, Builder, FlushNoGap, InterleavedPcm, MonoPcm, Quality};
+
+/// Default bitrate in bits per second.
+pub(crate) const DEFAULT_BITRATE: u32 = 128_000;
+/// Bitrates accepted for MP3 output, in bits per second, as MP3 frames only have these.
+pub(crate) const BITRATES: [u32; 16] = [
+    8_000, 16_000, 24_000, 32_000, 40_000, 48_000, 64_000, 80_000, 96_000, 112_000, 128_000,
+    160_000, 192_000, 224_000, 256_000, 320_000,
+];
+
+/// Encode `pcm` as constant bitrate MP3, preceded by an ID3v2 tag when `metadata` is set.
+pub(crate) fn encode(
+    pcm: &Pcm,
+    bitrate: u32,
+    metadata: Option<&Metadata>,
+) -> Result<Vec<u8>, String> {
+    if !matches!(pcm.channels, 1 | 2) {
+        return Err(format!(
+            "mp3 output supports 1 or 2 channels, got {}",
+            pcm.channels
+        ));
+    }
+
+    let bitrate = lame_bitrate(bitrate)?;
+    let mut builder = Builder::new().ok_or("failed to create the mp3 encoder")?;
+    let configured = builder
+        .set_num_channels(pcm.channels as u8)
+        .and(builder.set_sample_rate(pcm.sample_rate))
+        .and(builder.set_brate(bitrate))
+        .and(builder.set_quality(Quality::Good));
+    configured.map_err(|e| format!("failed to configure the mp3 encoder. {:?}", e))?;
+    let mut encoder = builder
+        .build()
+        .map_err(|e| format!("failed to create the mp3 encoder. {:?}", e))?;
+
+    let samples: Vec<i16> = pcm
+        .samples
+        .iter()
+        .map(|s| (s * 32768.0).round().clamp(-32768.0, 32767.0) as i16)
+        .collect();
+
+    let mut out = metadata.map(tags::id3v2).unwrap_or_default();
+    out.reserve(mp3lame_encoder::max_required_buffer_size(samples.len()));
+    let encoded = match pcm.channels {
+        1 => encoder.encode_to_vec(MonoPcm(&samples), &mut out),
+        _ => encoder.encode_to_vec(InterleavedPcm(&samples), &mut out),
+    };
+    encoded.map_err(|e| format!("failed to encode mp3. {:?}", e))?;
+    encoder
+        .flush_to_vec::<FlushNoGap>(&mut out)
+        .map_err(|e| format!("failed to flush the mp3 encoder. {:?}", e))?;
+
+    Ok(out)
+}
+
+/// The LAME bitrate for a bitrate of [`BITRATES`], in bits per second.
+fn lame_bitrate(bitrate: u32) -> Result<Bitrate, String> {
+    Ok(match bitrate {
+        8_000 => Bitrate::Kbps8,
+        16_000 => Bitrate::Kbps16,
+        24_000 => Bitrate::Kbps24,
+        32_000 => Bitrate::Kbps32,
+        40_000 => Bitrate::Kbps40,
+        48_000 => Bitrate::Kbps48,
+        64_000 => Bitrate::Kbps64,
+        80_000 => Bitrate::Kbps80,
+        96_000 => Bitrate::Kbps96,
+        112_000 => Bitrate::Kbps112,
+        128_000 => Bitrate::Kbps128,
+        160_000 => Bitrate::Kbps160,
+        192_000 => Bitrate::Kbps192,
+        224_000 => Bitrate::Kbps224,
+        256_000 => Bitrate::Kbps256,
+        320_000 => Bitrate::Kbps320,
+        _ => return Err(format!("unsupported mp3 bitrate: {}", bitrate)),
+    })
+}
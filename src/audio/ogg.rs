@@ -0,0 +1,100 @@
+//! Ogg page writer used to wrap encoded audio packets.
+
+/// Pages are flushed once they hold roughly this many bytes of packet data.
+const TARGET_PAGE_SIZE: usize = 4096;
+
+/// Writes a single logical Ogg bitstream.
+pub(crate) struct OggWriter {
+    serial: u32,
+    sequence: u32,
+    lacing: Vec<u8>,
+    body: Vec<u8>,
+    granule: u64,
+    first_page: bool,
+    out: Vec<u8>,
+}
+
+impl OggWriter {
+    pub(crate) fn new(serial: u32) -> Self {
+        Self {
+            serial,
+            sequence: 0,
+            lacing: Vec::new(),
+            body: Vec::new(),
+            granule: 0,
+            first_page: true,
+            out: Vec::new(),
+        }
+    }
+
+    /// Append a packet ending at `granule`, optionally forcing it to end its page.
+    pub(crate) fn write_packet(&mut self, packet: &[u8], granule: u64, end_page: bool) {
+        let segments = packet.len() / 255 + 1;
+        if self.lacing.len() + segments > 255 {
+            self.flush_page(false);
+        }
+
+        self.lacing
+            .extend(std::iter::repeat(255).take(packet.len() / 255));
+        self.lacing.push((packet.len() % 255) as u8);
+        self.body.extend_from_slice(packet);
+        self.granule = granule;
+
+        if end_page || self.body.len() >= TARGET_PAGE_SIZE {
+            self.flush_page(false);
+        }
+    }
+
+    /// Flush the pending packets as the final page of the stream.
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        self.flush_page(true);
+        self.out
+    }
+
+    fn flush_page(&mut self, last: bool) {
+        if self.lacing.is_empty() && !last {
+            return;
+        }
+
+        let mut header_type = 0u8;
+        if self.first_page {
+            header_type |= 0x02;
+        }
+        if last {
+            header_type |= 0x04;
+        }
+
+        let start = self.out.len();
+        self.out.extend_from_slice(b"OggS");
+        self.out.push(0);
+        self.out.push(header_type);
+        self.out.extend_from_slice(&self.granule.to_le_bytes());
+        self.out.extend_from_slice(&self.serial.to_le_bytes());
+        self.out.extend_from_slice(&self.sequence.to_le_bytes());
+        self.out.extend_from_slice(&[0; 4]);
+        self.out.push(self.lacing.len() as u8);
+        self.out.append(&mut self.lacing);
+        self.out.append(&mut self.body);
+
+        let crc = crc32(&self.out[start..]);
+        self.out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+
+        self.sequence += 1;
+        self.first_page = false;
+    }
+}
+
+/// CRC-32 as specified by Ogg: polynomial 0x04c11db7, no reflection, zero initial value.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = match crc & 0x8000_0000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x04c1_1db7,
+            };
+        }
+    }
+    crc
+}
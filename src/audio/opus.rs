@@ -0,0 +1,107 @@
+//! Opus encoding in an Ogg container, as described in RFC 7845.
+
+use super::{
+    dsp,
+    ogg::OggWriter,
+    tags::{self, Metadata},
+    wav::Pcm,
+};
+
+/// Default bitrate in bits per second, plenty for intelligible mono speech.
+pub(crate) const DEFAULT_BITRATE: u32 = 32_000;
+/// Bitrates accepted by libopus, in bits per second.
+pub(crate) const BITRATE_RANGE: std::ops::RangeInclusive<u32> = 6_000..=510_000;
+
+/// Sample rates the Opus encoder accepts as input.
+const SUPPORTED_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+/// Frame duration used for every packet.
+const FRAME_MS: u32 = 20;
+/// Encoder lookahead at 48 kHz that decoders must discard, see RFC 7845 section 4.2.
+const PRE_SKIP: u16 = 312;
+/// Largest packet libopus can produce.
+const MAX_PACKET_SIZE: usize = 1275;
+
+/// Encode `pcm` as Ogg Opus at the given bitrate, tagged with `metadata`.
+pub(crate) fn encode(
+    pcm: &Pcm,
+    bitrate: u32,
+    metadata: Option<&Metadata>,
+) -> Result<Vec<u8>, String> {
+    let channels = match pcm.channels {
+        1 => opus::Channels::Mono,
+        2 => opus::Channels::Stereo,
+        n => return Err(format!("opus output supports 1 or 2 channels, got {}", n)),
+    };
+
+    // the encoder only takes a handful of rates, so pick the closest one that loses nothing
+    let input_rate = pcm.sample_rate;
+    let mut pcm = pcm.clone();
+    let rate = SUPPORTED_RATES
+        .into_iter()
+        .find(|&rate| rate >= pcm.sample_rate)
+        .unwrap_or(48_000);
+    dsp::resample(&mut pcm, rate);
+
+    let mut encoder = opus::Encoder::new(rate, channels, opus::Application::Voip)
+        .map_err(|e| format!("failed to create the opus encoder. {}", e))?;
+    encoder
+        .set_bitrate(opus::Bitrate::Bits(bitrate as i32))
+        .map_err(|e| format!("failed to set the opus bitrate. {}", e))?;
+
+    let mut ogg = OggWriter::new(0x5454_5321);
+    ogg.write_packet(&head(pcm.channels as u8, input_rate), 0, true);
+    ogg.write_packet(&comment_header(metadata), 0, true);
+
+    let samples: Vec<i16> = pcm
+        .samples
+        .iter()
+        .map(|s| (s * 32768.0).round().clamp(-32768.0, 32767.0) as i16)
+        .collect();
+    let frame_len = (rate * FRAME_MS / 1000) as usize * pcm.channels as usize;
+    let granule_per_frame = (48_000 * FRAME_MS / 1000) as u64;
+    let total_granule = PRE_SKIP as u64 + pcm.frames() as u64 * 48_000 / rate as u64;
+
+    let mut granule = PRE_SKIP as u64;
+    let mut packet = vec![0u8; MAX_PACKET_SIZE];
+    let mut frame = vec![0i16; frame_len];
+    let chunks = samples.chunks(frame_len);
+    let count = chunks.len();
+    for (i, chunk) in chunks.enumerate() {
+        frame[..chunk.len()].copy_from_slice(chunk);
+        frame[chunk.len()..].fill(0);
+
+        let len = encoder
+            .encode(&frame, &mut packet)
+            .map_err(|e| format!("failed to encode opus frame. {}", e))?;
+
+        // the last packet's granule position tells the decoder where the audio really ends
+        granule = match i + 1 == count {
+            true => total_granule,
+            false => granule + granule_per_frame,
+        };
+        ogg.write_packet(&packet[..len], granule, false);
+    }
+
+    Ok(ogg.finish())
+}
+
+/// Identification header.
+fn head(channels: u8, input_rate: u32) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(channels);
+    head.extend_from_slice(&PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&input_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+/// Comment header.
+fn comment_header(metadata: Option<&Metadata>) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(b"OpusTags");
+    header.extend_from_slice(&tags::vorbis_comment(metadata));
+    header
+}
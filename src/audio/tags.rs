@@ -0,0 +1,117 @@
+//! Metadata tags embedded in the formats that can carry them.
+
+use serde::Deserialize;
+
+/// Vendor string written in Vorbis comments.
+const VENDOR: &str = concat!("tts-api-server ", env!("CARGO_PKG_VERSION"));
+/// Longest value accepted for a tag, in characters.
+const MAX_TAG_CHARS: usize = 256;
+
+/// Tags requested for the output file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Metadata {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub artist: Option<String>,
+    #[serde(default)]
+    pub album: Option<String>,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+impl Metadata {
+    /// The tags that are set, by Vorbis comment field name.
+    fn fields(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("TITLE", &self.title),
+            ("ARTIST", &self.artist),
+            ("ALBUM", &self.album),
+            ("COMMENT", &self.comment),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+        .collect()
+    }
+
+    /// Check the tag values, returning a message suitable for a 400 response.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        for (name, value) in self.fields() {
+            let chars = value.chars().count();
+            if chars > MAX_TAG_CHARS {
+                return Err(format!(
+                    "`metadata.{}` is limited to {} characters, got {}",
+                    name.to_lowercase(),
+                    MAX_TAG_CHARS,
+                    chars
+                ));
+            }
+            if value.chars().any(|c| c.is_control()) {
+                return Err(format!(
+                    "`metadata.{}` must not contain control characters",
+                    name.to_lowercase()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A Vorbis comment structure, as used by FLAC and Ogg Opus, without any framing.
+pub(crate) fn vorbis_comment(metadata: Option<&Metadata>) -> Vec<u8> {
+    let fields = metadata.map(Metadata::fields).unwrap_or_default();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+    out.extend_from_slice(VENDOR.as_bytes());
+    out.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+    for (name, value) in fields {
+        let field = format!("{}={}", name, value);
+        out.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        out.extend_from_slice(field.as_bytes());
+    }
+    out
+}
+
+/// An ID3v2.3 tag, as prepended to MP3 streams, with the text in UTF-16.
+#[cfg(feature = "mp3")]
+pub(crate) fn id3v2(metadata: &Metadata) -> Vec<u8> {
+    let mut frames = Vec::new();
+    for (name, value) in metadata.fields() {
+        let (id, mut data) = match name {
+            "TITLE" => (b"TIT2", vec![1]),
+            "ARTIST" => (b"TPE1", vec![1]),
+            "ALBUM" => (b"TALB", vec![1]),
+            _ => {
+                // language, then an empty description
+                let mut data = b"\x01eng".to_vec();
+                data.extend_from_slice(&utf16_with_bom(""));
+                data.extend_from_slice(&[0, 0]);
+                (b"COMM", data)
+            }
+        };
+        data.extend_from_slice(&utf16_with_bom(value));
+
+        frames.extend_from_slice(id);
+        frames.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&[0, 0]);
+        frames.extend_from_slice(&data);
+    }
+
+    // the tag size is stored in 7-bit bytes, so that it never looks like an MPEG sync word
+    let size = frames.len() as u32;
+    let mut out = b"ID3\x03\x00\x00".to_vec();
+    out.extend((0..4).rev().map(|i| (size >> (7 * i)) as u8 & 0x7F));
+    out.extend_from_slice(&frames);
+    out
+}
+
+/// `value` as little-endian UTF-16 after a byte order mark.
+#[cfg(feature = "mp3")]
+fn utf16_with_bom(value: &str) -> Vec<u8> {
+    let mut out = vec![0xFF, 0xFE];
+    out.extend(value.encode_utf16().flat_map(u16::to_le_bytes));
+    out
+}
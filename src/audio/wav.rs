@@ -0,0 +1,194 @@
+//! Minimal RIFF/WAVE reader and writer for the audio produced by the backends.
+
+/// Size written in the RIFF and `data` headers of a wav stream whose length is not known when
+/// the header is sent. Decoders of streamed wav read such a `data` chunk up to the end of input.
+pub(crate) const STREAMING_SIZE: u32 = u32::MAX;
+
+/// Interleaved PCM samples normalized to `[-1.0, 1.0]`.
+#[derive(Debug, Clone)]
+pub(crate) struct Pcm {
+    /// Sample rate in Hz.
+    pub sample_rate: u32,
+    /// Number of interleaved channels.
+    pub channels: u16,
+    /// Bit depth of the source audio.
+    pub bits_per_sample: u16,
+    /// Interleaved samples.
+    pub samples: Vec<f32>,
+}
+
+impl Pcm {
+    /// Decode a RIFF/WAVE buffer holding integer or float PCM.
+    pub(crate) fn from_wav(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err("not a RIFF/WAVE buffer".to_string());
+        }
+
+        // streamed wav files carry placeholder sizes, as they are written before the data
+        let streamed = matches!(read_u32(bytes, 4), 0 | STREAMING_SIZE);
+        let mut fmt = None;
+        let mut data = None;
+        let mut pos = 12;
+        while pos + 8 <= bytes.len() {
+            let id = &bytes[pos..pos + 4];
+            let start = pos + 8;
+            let size = match (id, read_u32(bytes, pos + 4)) {
+                // some streaming encoders leave the size of the data at 0 instead of the sentinel
+                (b"data", 0) if streamed => bytes.len() - start,
+                (_, size) => size as usize,
+            };
+            // a placeholder size may run past the buffer, so clamp to what is actually there
+            let end = start.saturating_add(size).min(bytes.len());
+            match id {
+                b"fmt " => fmt = Some(&bytes[start..end]),
+                b"data" => data = Some(&bytes[start..end]),
+                _ => {}
+            }
+            // chunks are word aligned
+            pos = start.saturating_add(size).saturating_add(size & 1);
+        }
+
+        let fmt = fmt.ok_or("missing `fmt ` chunk")?;
+        let data = data.ok_or("missing `data` chunk")?;
+        if fmt.len() < 16 {
+            return Err("truncated `fmt ` chunk".to_string());
+        }
+
+        let mut format = read_u16(fmt, 0);
+        let channels = read_u16(fmt, 2);
+        let sample_rate = read_u32(fmt, 4);
+        let bits_per_sample = read_u16(fmt, 14);
+        if format == 0xFFFE && fmt.len() >= 26 {
+            // WAVE_FORMAT_EXTENSIBLE: the real format is the head of the sub-format GUID
+            format = read_u16(fmt, 24);
+        }
+        if channels == 0 || sample_rate == 0 {
+            return Err("invalid channel count or sample rate".to_string());
+        }
+
+        let samples = match (format, bits_per_sample) {
+            (1, 8) => data.iter().map(|&b| (b as f32 - 128.0) / 128.0).collect(),
+            (1, 16) => data
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+                .collect(),
+            (1, 24) => data
+                .chunks_exact(3)
+                .map(|b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8388608.0)
+                .collect(),
+            (1, 32) => data
+                .chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0)
+                .collect(),
+            (3, 32) => data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            _ => {
+                return Err(format!(
+                    "unsupported wav encoding: format {}, {} bits per sample",
+                    format, bits_per_sample
+                ))
+            }
+        };
+
+        Ok(Self {
+            sample_rate,
+            channels,
+            // float input is written back as 16-bit integer PCM
+            bits_per_sample: match format {
+                3 => 16,
+                _ => bits_per_sample,
+            },
+            samples,
+        })
+    }
+
+    /// Number of sample frames, i.e. samples per channel.
+    pub(crate) fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    /// Length of the audio in seconds.
+    pub(crate) fn duration(&self) -> f64 {
+        self.frames() as f64 / self.sample_rate.max(1) as f64
+    }
+
+    /// Bit depth used when writing the samples out: 8, 16, 24 or 32.
+    pub(crate) fn output_bits(&self) -> u16 {
+        match self.bits_per_sample {
+            8 | 24 | 32 => self.bits_per_sample,
+            _ => 16,
+        }
+    }
+
+    /// Quantize the samples to signed integers of `output_bits()` bits.
+    pub(crate) fn quantize(&self) -> Vec<i32> {
+        let scale = (1u64 << (self.output_bits() - 1)) as f64;
+        self.samples
+            .iter()
+            .map(|&sample| (sample as f64 * scale).round().clamp(-scale, scale - 1.0) as i32)
+            .collect()
+    }
+
+    /// Encode the samples as little-endian integers of `output_bits()` bits.
+    ///
+    /// As in wav files, 8-bit samples are unsigned and everything else is signed.
+    pub(crate) fn to_le_bytes(&self) -> Vec<u8> {
+        let bits = self.output_bits();
+        let mut out = Vec::with_capacity(self.samples.len() * bits as usize / 8);
+        for sample in self.quantize() {
+            match bits {
+                8 => out.push((sample + 128) as u8),
+                _ => out.extend_from_slice(&sample.to_le_bytes()[..bits as usize / 8]),
+            }
+        }
+        out
+    }
+
+    /// Encode the samples as a RIFF/WAVE buffer.
+    pub(crate) fn to_wav(&self) -> Vec<u8> {
+        let data = self.to_le_bytes();
+        let mut out = self.wav_header(36 + data.len() as u32, data.len() as u32);
+        out.extend_from_slice(&data);
+        out
+    }
+
+    /// The RIFF/WAVE header of a stream of samples in the format of `self`, to be followed by
+    /// the [`Pcm::to_le_bytes`] of every part of the stream.
+    ///
+    /// The sizes are set to [`STREAMING_SIZE`], as the length of the stream is not known yet.
+    pub(crate) fn wav_stream_header(&self) -> Vec<u8> {
+        self.wav_header(STREAMING_SIZE, STREAMING_SIZE)
+    }
+
+    fn wav_header(&self, riff_size: u32, data_size: u32) -> Vec<u8> {
+        let bits_per_sample = self.output_bits();
+        let block_align = self.channels * bits_per_sample / 8;
+        let byte_rate = self.sample_rate * block_align as u32;
+
+        let mut out = Vec::with_capacity(44);
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&riff_size.to_le_bytes());
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(b"fmt ");
+        out.extend_from_slice(&16u32.to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&self.channels.to_le_bytes());
+        out.extend_from_slice(&self.sample_rate.to_le_bytes());
+        out.extend_from_slice(&byte_rate.to_le_bytes());
+        out.extend_from_slice(&block_align.to_le_bytes());
+        out.extend_from_slice(&bits_per_sample.to_le_bytes());
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&data_size.to_le_bytes());
+        out
+    }
+}
+
+fn read_u16(bytes: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([bytes[pos], bytes[pos + 1]])
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+}
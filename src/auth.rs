@@ -0,0 +1,173 @@
+//! API keys accepted by the server: the `TTS_API_KEY` environment variable, or the `api_key` of
+//! the config file, the keys listed in the `TTS_API_KEYS` environment variable, and the keys
+//! listed in `--api-key-file`, which can be reloaded at runtime. The legacy `API_KEY` and
+//! `API_KEYS` are read when the former are not set.
+//!
+//! The listed keys can be given a name, e.g. the team they were issued to, which is logged
+//! instead of the key when a request uses it.
+
+use once_cell::sync::{Lazy, OnceCell};
+use std::{collections::HashMap, path::PathBuf, sync::RwLock};
+
+/// Name logged for the keys that are not given one.
+const UNNAMED: &str = "unnamed";
+
+// path of the API key file given on the command line
+static KEY_FILE: OnceCell<PathBuf> = OnceCell::new();
+
+// keys read from the API key file, with their names
+static FILE_KEYS: Lazy<RwLock<HashMap<String, Option<String>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+// keys of the `TTS_API_KEYS` environment variable, with their names
+static ENV_KEYS: OnceCell<HashMap<String, Option<String>>> = OnceCell::new();
+
+/// Load the keys of `path` and remember it for later reloads.
+pub(crate) fn init(path: PathBuf) -> Result<usize, String> {
+    let keys = read_key_file(&path)?;
+    let count = keys.len();
+    *FILE_KEYS.write().unwrap() = keys;
+    KEY_FILE
+        .set(path)
+        .map_err(|_| "Failed to set `KEY_FILE`.".to_string())?;
+
+    Ok(count)
+}
+
+/// Load the keys of the `TTS_API_KEYS` environment variable: entries separated by commas, each
+/// one a key optionally followed by whitespace and its name.
+///
+/// Unlike the lines of the key file, a malformed entry is an error.
+pub(crate) fn init_env(list: &str) -> Result<usize, String> {
+    let mut keys = HashMap::new();
+    for (index, entry) in list.split(',').enumerate() {
+        match parse_entry(entry) {
+            Ok((key, name)) => {
+                keys.insert(key, name);
+            }
+            Err(reason) => {
+                return Err(format!(
+                    "Invalid entry {} of `TTS_API_KEYS`: {}",
+                    index + 1,
+                    reason
+                ))
+            }
+        }
+    }
+
+    let count = keys.len();
+    ENV_KEYS
+        .set(keys)
+        .map_err(|_| "Failed to set `ENV_KEYS`.".to_string())?;
+
+    Ok(count)
+}
+
+/// Read the API key file again, replacing the keys loaded from it.
+///
+/// The current keys are kept if the file cannot be read.
+pub(crate) fn reload() -> Result<usize, String> {
+    let path = match KEY_FILE.get() {
+        Some(path) => path,
+        None => return Err("no API key file is configured".to_string()),
+    };
+
+    let keys = read_key_file(path)?;
+    let count = keys.len();
+    *FILE_KEYS.write().unwrap() = keys;
+
+    Ok(count)
+}
+
+/// Whether requests must carry one of the configured API keys, which `--no-auth` turns off.
+pub(crate) fn enabled() -> bool {
+    if crate::AUTH_DISABLED.get().copied().unwrap_or_default() {
+        return false;
+    }
+
+    crate::API_KEY.get().is_some() || ENV_KEYS.get().is_some() || KEY_FILE.get().is_some()
+}
+
+/// The name of the configured API key `api_key`, or `None` if it is not one of them.
+///
+/// The `TTS_API_KEY` key is named `TTS_API_KEY`, and the listed keys without a name `unnamed`.
+pub(crate) fn key_name(api_key: &str) -> Option<String> {
+    if crate::API_KEY.get().map(String::as_str) == Some(api_key) {
+        return Some("TTS_API_KEY".to_string());
+    }
+
+    let name = match ENV_KEYS.get().and_then(|keys| keys.get(api_key)) {
+        Some(name) => name.clone(),
+        None => FILE_KEYS.read().unwrap().get(api_key)?.clone(),
+    };
+    Some(name.unwrap_or_else(|| UNNAMED.to_string()))
+}
+
+/// Whether `given` is the key `expected`, compared in a time that does not depend on where they
+/// differ, so the key cannot be guessed byte by byte. Only the length may be told apart.
+pub(crate) fn keys_match(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Number of API keys listed in `TTS_API_KEYS` and in the API key file.
+pub(crate) fn listed_keys() -> usize {
+    ENV_KEYS.get().map_or(0, HashMap::len) + FILE_KEYS.read().unwrap().len()
+}
+
+/// Parse an API key file: one key per line, optionally followed by whitespace and its name,
+/// `#` starting a comment.
+///
+/// Empty lines and lines that do not start with a valid key are skipped with a warning.
+fn read_key_file(path: &PathBuf) -> Result<HashMap<String, Option<String>>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        format!(
+            "Failed to read the API key file {}. {}",
+            path.to_string_lossy(),
+            e
+        )
+    })?;
+
+    let mut keys = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let entry = line.split('#').next().unwrap_or_default();
+        match parse_entry(entry) {
+            Ok((key, name)) => {
+                keys.insert(key, name);
+            }
+            Err(reason) => {
+                warn!(target: "stdout", "Skip line {} of the API key file: {}", index + 1, reason)
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Split an entry of a key list into its key and its name, if any.
+fn parse_entry(entry: &str) -> Result<(String, Option<String>), &'static str> {
+    let entry = entry.trim();
+    if entry.is_empty() {
+        return Err("empty entry");
+    }
+    if entry.chars().any(char::is_control) {
+        return Err("control characters");
+    }
+
+    let (key, name) = match entry.split_once(char::is_whitespace) {
+        Some((key, name)) => (key, Some(name.trim().to_string())),
+        None => (entry, None),
+    };
+
+    Ok((key.to_string(), name))
+}
@@ -0,0 +1,203 @@
+//! `POST /v1/audio/speech/batch`: synthesize several speech requests in one call.
+
+use crate::{
+    audio::align::WordTimestamp,
+    error::{self, ServerError},
+};
+use hyper::{body::to_bytes, http::Method, Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of items accepted in a single batch.
+const MAX_BATCH_ITEMS: usize = 256;
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    /// Speech requests, each with the same shape as a `/v1/audio/speech` request body.
+    items: Vec<serde_json::Value>,
+}
+
+/// Describes the outcome of every item, in request order.
+#[derive(Debug, Serialize)]
+struct Manifest {
+    items: Vec<ManifestItem>,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestItem {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    words: Option<Vec<WordTimestamp>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ItemError>,
+}
+
+#[derive(Debug, Serialize)]
+struct ItemError {
+    status: u16,
+    message: String,
+}
+
+/// Synthesize every item of the batch and return a `multipart/mixed` response.
+///
+/// The first part is a JSON manifest mapping item indices to the filenames of the following
+/// audio parts, or to the error that item failed with. Failed items have no audio part.
+pub(crate) async fn audio_speech_batch_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming audio speech batch request");
+
+    if req.method().eq(&Method::OPTIONS) {
+        let result = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
+
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        }
+    }
+
+    // parse request
+    let body_bytes = match to_bytes(req.into_body()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+    let batch_request: BatchRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(batch_request) => batch_request,
+        Err(e) => {
+            let err_msg = format!("Fail to deserialize speech batch request: {msg}", msg = e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+    if batch_request.items.is_empty() || batch_request.items.len() > MAX_BATCH_ITEMS {
+        let err_msg = format!(
+            "A batch must contain between 1 and {} items, got {}.",
+            MAX_BATCH_ITEMS,
+            batch_request.items.len()
+        );
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::bad_request(err_msg);
+    }
+
+    let boundary = format!("batch-{}", uuid::Uuid::new_v4().simple());
+    let mut manifest = Manifest { items: vec![] };
+    let mut parts = Vec::new();
+    for (index, item) in batch_request.items.into_iter().enumerate() {
+        info!(target: "stdout", "Synthesize batch item {}", index);
+
+        match super::synthesize(item).await {
+            Ok(audio) => {
+                let filename = format!("speech-{}.{}", index, audio.format.extension());
+                let content_type = audio.content_type().to_string();
+
+                parts.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                parts.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+                parts.extend_from_slice(
+                    format!(
+                        "Content-Disposition: attachment; filename=\"{}\"\r\n\r\n",
+                        filename
+                    )
+                    .as_bytes(),
+                );
+                parts.extend_from_slice(&audio.data);
+                parts.extend_from_slice(b"\r\n");
+
+                manifest.items.push(ManifestItem {
+                    index,
+                    filename: Some(filename),
+                    content_type: Some(content_type),
+                    words: audio.words,
+                    error: None,
+                });
+            }
+            Err(e) => {
+                let status = match e {
+                    ServerError::BadRequest(_) => hyper::StatusCode::BAD_REQUEST,
+                    ServerError::Busy(_) => hyper::StatusCode::SERVICE_UNAVAILABLE,
+                    ServerError::QueueFull(_) => hyper::StatusCode::TOO_MANY_REQUESTS,
+                    ServerError::NotImplemented(_) => hyper::StatusCode::NOT_IMPLEMENTED,
+                    _ => hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                manifest.items.push(ManifestItem {
+                    index,
+                    filename: None,
+                    content_type: None,
+                    words: None,
+                    error: Some(ItemError {
+                        status: status.as_u16(),
+                        message: e.to_string(),
+                    }),
+                });
+            }
+        }
+    }
+
+    let manifest = match serde_json::to_string(&manifest) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the batch manifest. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    let mut body = Vec::with_capacity(parts.len() + manifest.len() + 256);
+    body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+    body.extend_from_slice(b"Content-Type: application/json\r\n");
+    body.extend_from_slice(b"Content-Disposition: inline; filename=\"manifest.json\"\r\n\r\n");
+    body.extend_from_slice(manifest.as_bytes());
+    body.extend_from_slice(b"\r\n");
+    body.extend_from_slice(&parts);
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    // return response
+    let result = Response::builder()
+        .header(
+            "Content-Type",
+            format!("multipart/mixed; boundary={}", boundary),
+        )
+        .body(Body::from(body));
+
+    let res = match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    };
+
+    info!(target: "stdout", "Send the audio speech batch response");
+
+    res
+}
@@ -0,0 +1,393 @@
+//! LRU cache of synthesized audio, keyed by a hash of the normalized speech request: in memory,
+//! and optionally on disk in `--cache-dir`, where it survives restarts.
+//!
+//! Every disk entry is a pair of files named after the hash: `<hash>.audio` with the encoded
+//! audio, and `<hash>.json` with the request and the other fields of the audio.
+
+use crate::audio::{align::WordTimestamp, EncodedAudio, ResponseFormat};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+static CACHE: OnceCell<Mutex<Lru>> = OnceCell::new();
+
+static DISK: OnceCell<Mutex<Disk>> = OnceCell::new();
+
+/// Identifies the audio of a speech request.
+pub(crate) struct Key {
+    hash: u64,
+    /// The normalized request, compared on lookup so that hash collisions are misses.
+    request: String,
+}
+
+struct Lru {
+    capacity: usize,
+    /// Total size of the cached audio, kept under `max_bytes`.
+    bytes: u64,
+    max_bytes: Option<u64>,
+    entries: HashMap<u64, Entry>,
+    /// Incremented on every access, to find the least recently used entry.
+    clock: u64,
+}
+
+struct Entry {
+    request: String,
+    audio: EncodedAudio,
+    last_used: u64,
+}
+
+struct Disk {
+    dir: PathBuf,
+    /// Total size of the entry files, kept under `max_bytes`.
+    bytes: u64,
+    max_bytes: Option<u64>,
+    /// Size and last use of the entries, the files themselves are read on hits.
+    entries: HashMap<u64, DiskEntry>,
+    clock: u64,
+}
+
+struct DiskEntry {
+    size: u64,
+    last_used: u64,
+}
+
+/// Content of the `.json` file of a disk entry.
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    request: String,
+    format: ResponseFormat,
+    duration: f64,
+    sample_rate: Option<u32>,
+    words: Option<Vec<WordTimestamp>>,
+    content_type: Option<String>,
+    filename: Option<String>,
+}
+
+/// Enable the memory cache with room for `capacity` entries and `max_bytes` of audio. A capacity
+/// of 0 leaves it disabled.
+pub(crate) fn init(capacity: usize, max_bytes: Option<u64>) -> Result<(), String> {
+    if capacity == 0 {
+        return Ok(());
+    }
+
+    CACHE
+        .set(Mutex::new(Lru {
+            capacity,
+            bytes: 0,
+            max_bytes,
+            entries: HashMap::with_capacity(capacity),
+            clock: 0,
+        }))
+        .map_err(|_| "the audio cache is already initialized".to_string())
+}
+
+/// Enable the disk cache in `dir`, created if missing, with room for `max_bytes` of files.
+///
+/// The entries left by a previous run are kept, the least recently written evicted first.
+/// Returns the number of entries found.
+pub(crate) fn init_disk(dir: PathBuf, max_bytes: Option<u64>) -> Result<usize, String> {
+    std::fs::create_dir_all(&dir).map_err(|e| {
+        format!(
+            "Failed to create the cache directory {}. {}",
+            dir.to_string_lossy(),
+            e
+        )
+    })?;
+    let read_dir = std::fs::read_dir(&dir).map_err(|e| {
+        format!(
+            "Failed to read the cache directory {}. {}",
+            dir.to_string_lossy(),
+            e
+        )
+    })?;
+
+    let mut found = Vec::new();
+    for dir_entry in read_dir.flatten() {
+        let path = dir_entry.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        let hash = match path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| u64::from_str_radix(stem, 16).ok())
+        {
+            Some(hash) => hash,
+            None => continue,
+        };
+        let (Ok(metadata), Ok(audio)) = (
+            std::fs::metadata(&path),
+            std::fs::metadata(path.with_extension("audio")),
+        ) else {
+            continue;
+        };
+        let modified = metadata.modified().ok();
+        found.push((modified, hash, metadata.len() + audio.len()));
+    }
+    found.sort();
+
+    let mut disk = Disk {
+        dir,
+        bytes: 0,
+        max_bytes,
+        entries: HashMap::with_capacity(found.len()),
+        clock: 0,
+    };
+    for (_, hash, size) in found {
+        disk.clock += 1;
+        disk.bytes += size;
+        let last_used = disk.clock;
+        disk.entries.insert(hash, DiskEntry { size, last_used });
+    }
+    disk.evict(0);
+
+    let count = disk.entries.len();
+    DISK.set(Mutex::new(disk))
+        .map_err(|_| "the disk cache is already initialized".to_string())?;
+
+    Ok(count)
+}
+
+pub(crate) fn enabled() -> bool {
+    CACHE.get().is_some() || DISK.get().is_some()
+}
+
+/// Key of a normalized speech request body, every field of which determines the audio.
+pub(crate) fn key(body: &serde_json::Value) -> Key {
+    // objects serialize with sorted keys, so equal requests give equal strings
+    let request = body.to_string();
+
+    let hash = fnv1a(request.as_bytes());
+
+    Key { hash, request }
+}
+
+/// FNV-1a hash of `bytes`, which is stable across runs and builds, unlike the ones of
+/// `DefaultHasher`.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+pub(crate) fn get(key: &Key) -> Option<EncodedAudio> {
+    if let Some(cache) = CACHE.get() {
+        let mut lru = cache.lock().unwrap();
+        lru.clock += 1;
+        let clock = lru.clock;
+        if let Some(entry) = lru.entries.get_mut(&key.hash) {
+            if entry.request == key.request {
+                entry.last_used = clock;
+                return Some(entry.audio.clone());
+            }
+        }
+    }
+
+    let audio = DISK.get()?.lock().unwrap().get(key)?;
+    // keep it at hand for the next requests
+    insert_memory(key, audio.clone());
+
+    Some(audio)
+}
+
+/// Store the audio of `key` in the caches. Returns whether any of them took it, the entries
+/// larger than `--cache-max-bytes` being left out.
+pub(crate) fn insert(key: &Key, audio: EncodedAudio) -> bool {
+    let on_disk = match DISK.get() {
+        Some(disk) => disk.lock().unwrap().insert(key, &audio),
+        None => false,
+    };
+    let in_memory = insert_memory(key, audio);
+    on_disk || in_memory
+}
+
+fn insert_memory(key: &Key, audio: EncodedAudio) -> bool {
+    let Some(cache) = CACHE.get() else {
+        return false;
+    };
+    let mut lru = cache.lock().unwrap();
+
+    let size = audio.data.len() as u64;
+    if lru.max_bytes.is_some_and(|max_bytes| size > max_bytes) {
+        return false;
+    }
+    if let Some(entry) = lru.entries.remove(&key.hash) {
+        lru.bytes -= entry.audio.data.len() as u64;
+    }
+
+    while lru.entries.len() >= lru.capacity
+        || lru
+            .max_bytes
+            .is_some_and(|max_bytes| lru.bytes + size > max_bytes)
+    {
+        let oldest = lru
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(&hash, _)| hash);
+        match oldest.and_then(|oldest| lru.entries.remove(&oldest)) {
+            Some(entry) => lru.bytes -= entry.audio.data.len() as u64,
+            None => break,
+        }
+    }
+
+    lru.clock += 1;
+    let last_used = lru.clock;
+    lru.bytes += size;
+    lru.entries.insert(
+        key.hash,
+        Entry {
+            request: key.request.clone(),
+            audio,
+            last_used,
+        },
+    );
+    true
+}
+
+/// Drop every cached entry. Must be called whenever the model or its lexicon is reloaded.
+#[allow(dead_code)]
+pub(crate) fn clear() {
+    if let Some(cache) = CACHE.get() {
+        let mut lru = cache.lock().unwrap();
+        lru.entries.clear();
+        lru.bytes = 0;
+    }
+    if let Some(disk) = DISK.get() {
+        let mut disk = disk.lock().unwrap();
+        let hashes: Vec<u64> = disk.entries.keys().copied().collect();
+        for hash in hashes {
+            disk.remove(hash);
+        }
+    }
+}
+
+impl Disk {
+    fn path(&self, hash: u64, extension: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.{}", hash, extension))
+    }
+
+    fn get(&mut self, key: &Key) -> Option<EncodedAudio> {
+        self.entries.get(&key.hash)?;
+
+        let audio = match self.read(key.hash) {
+            Ok((metadata, _)) if metadata.request != key.request => return None,
+            Ok((metadata, data)) => EncodedAudio {
+                data,
+                format: metadata.format,
+                duration: metadata.duration,
+                sample_rate: metadata.sample_rate,
+                words: metadata.words,
+                content_type: metadata.content_type,
+                filename: metadata.filename,
+            },
+            Err(e) => {
+                // log
+                warn!(target: "stdout", "Drop the unreadable cache entry {:016x}: {}", key.hash, e);
+
+                self.remove(key.hash);
+                return None;
+            }
+        };
+
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(&key.hash) {
+            entry.last_used = clock;
+        }
+
+        Some(audio)
+    }
+
+    fn read(&self, hash: u64) -> Result<(Metadata, Vec<u8>), String> {
+        let metadata = std::fs::read(self.path(hash, "json")).map_err(|e| e.to_string())?;
+        let metadata: Metadata = serde_json::from_slice(&metadata).map_err(|e| e.to_string())?;
+        let data = std::fs::read(self.path(hash, "audio")).map_err(|e| e.to_string())?;
+
+        Ok((metadata, data))
+    }
+
+    /// Write the entry of `key`. Returns whether it was written.
+    fn insert(&mut self, key: &Key, audio: &EncodedAudio) -> bool {
+        let metadata = Metadata {
+            request: key.request.clone(),
+            format: audio.format,
+            duration: audio.duration,
+            sample_rate: audio.sample_rate,
+            words: audio.words.clone(),
+            content_type: audio.content_type.clone(),
+            filename: audio.filename.clone(),
+        };
+        let metadata = match serde_json::to_vec(&metadata) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                // log
+                warn!(target: "stdout", "Failed to serialize the cache entry {:016x}: {}", key.hash, e);
+
+                return false;
+            }
+        };
+
+        let size = (metadata.len() + audio.data.len()) as u64;
+        if self.max_bytes.is_some_and(|max_bytes| size > max_bytes) {
+            return false;
+        }
+        self.remove(key.hash);
+        self.evict(size);
+
+        // the audio is written first, so that an entry is never listed without it
+        let written = write_atomic(&self.path(key.hash, "audio"), &audio.data)
+            .and_then(|_| write_atomic(&self.path(key.hash, "json"), &metadata));
+        if let Err(e) = written {
+            // log
+            warn!(target: "stdout", "Failed to write the cache entry {:016x}: {}", key.hash, e);
+
+            let _ = std::fs::remove_file(self.path(key.hash, "audio"));
+            return false;
+        }
+
+        self.clock += 1;
+        let last_used = self.clock;
+        self.bytes += size;
+        self.entries.insert(key.hash, DiskEntry { size, last_used });
+        true
+    }
+
+    fn remove(&mut self, hash: u64) {
+        if let Some(entry) = self.entries.remove(&hash) {
+            self.bytes -= entry.size;
+        }
+        let _ = std::fs::remove_file(self.path(hash, "json"));
+        let _ = std::fs::remove_file(self.path(hash, "audio"));
+    }
+
+    /// Remove the least recently used entries until `size` more bytes fit.
+    fn evict(&mut self, size: u64) {
+        let Some(max_bytes) = self.max_bytes else {
+            return;
+        };
+
+        while self.bytes + size > max_bytes {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(&hash, _)| hash);
+            match oldest {
+                Some(oldest) => self.remove(oldest),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Write `data` to a temporary file renamed to `path`, so that readers never see a partial file.
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)
+}
@@ -0,0 +1,172 @@
+//! Streamed audio mode of `/v1/audio/speech`, selected with `"stream_format": "audio"`.
+//!
+//! The input is split into sentences, which are synthesized one after the other and written to
+//! a chunked response as soon as each is ready. `wav` streams start with a header whose sizes
+//! are set to [`audio::wav::STREAMING_SIZE`], since the length is not known before the last
+//! sentence; `pcm` streams are the bare samples; `aac` streams are the ADTS frames of every
+//! sentence, which decoders read as a single stream, and `mp3` streams likewise the MPEG frames
+//! of every sentence. Other formats cannot be streamed. The first
+//! sentence is synthesized before answering, so that its errors get a plain error response and
+//! the stream headers carry its sample rate.
+
+use crate::{
+    audio::{self, wav::Pcm, ResponseFormat},
+    error::{self, ServerError},
+    text,
+};
+use hyper::{body::Bytes, Body, Response};
+
+/// Whether the client asked for the audio as a chunked stream.
+pub(crate) fn requested(body: &serde_json::Value) -> bool {
+    body["stream_format"].as_str() == Some("audio")
+}
+
+/// Validate a speech request, then answer with its audio streamed sentence by sentence.
+pub(crate) async fn audio_speech_chunked(mut body: serde_json::Value) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Stream the audio speech in chunks");
+
+    if let Err(e) = super::validate(body.clone()) {
+        return error::server_error(e);
+    }
+    let (format, bitrate) = match serde_json::from_value::<audio::SpeechOptions>(body.clone()) {
+        Ok(options) => (options.format(), options.bitrate),
+        Err(e) => return error::bad_request(e.to_string()),
+    };
+    if !matches!(
+        format,
+        ResponseFormat::Wav | ResponseFormat::Pcm | ResponseFormat::Aac | ResponseFormat::Mp3
+    ) {
+        return error::bad_request(format!(
+            "`{}` audio cannot be streamed, use `wav`, `pcm`, `aac` or `mp3`",
+            format
+        ));
+    }
+    // already checked by the validation
+    let _ = text::ssml::expand(&mut body);
+    let input: text::Input = match serde_json::from_value(body["input"].clone()) {
+        Ok(input) => input,
+        Err(e) => return error::bad_request(e.to_string()),
+    };
+    let mut sentences = input
+        .segments()
+        .iter()
+        .flat_map(|segment| text::stream_sentences(segment))
+        .collect::<Vec<_>>()
+        .into_iter();
+    if let Some(body) = body.as_object_mut() {
+        body.remove("stream_format");
+        // every sentence is decoded from wav and written in the streamed format
+        body.insert("response_format".to_string(), "wav".into());
+    }
+
+    let first = match sentences.next() {
+        Some(sentence) => match synthesize_sentence(&body, sentence, format).await {
+            Ok(pcm) => pcm,
+            Err(e) => return error::server_error(e),
+        },
+        None => return error::bad_request("The input is empty."),
+    };
+    // the lead silence only belongs at the start of the stream
+    if let Some(body) = body.as_object_mut() {
+        body.remove("lead_silence_ms");
+    }
+    let mut head = match format {
+        ResponseFormat::Wav => first.wav_stream_header(),
+        _ => Vec::new(),
+    };
+    match encode_chunk(&first, format, bitrate) {
+        Ok(chunk) => head.extend_from_slice(&chunk),
+        Err(e) => return error::server_error(e),
+    }
+    let sample_rate = first.sample_rate;
+
+    let (mut sender, stream) = Body::channel();
+    tokio::spawn(async move {
+        if sender.send_data(Bytes::from(head)).await.is_err() {
+            return;
+        }
+
+        for sentence in sentences {
+            let chunk = match synthesize_sentence(&body, sentence, format)
+                .await
+                .and_then(|pcm| encode_chunk(&pcm, format, bitrate))
+            {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    // the status is already sent, so cut the stream short
+                    error!(target: "stdout", "Abort the audio stream. {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+
+            if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                info!(target: "stdout", "Audio stream client disconnected, abort the synthesis");
+                return;
+            }
+        }
+    });
+
+    let result = Response::builder()
+        .header("Content-Type", format.content_type())
+        .header("X-Audio-Sample-Rate", sample_rate)
+        .body(stream);
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// Synthesize one sentence of the stream and decode it.
+async fn synthesize_sentence(
+    body: &serde_json::Value,
+    sentence: String,
+    format: ResponseFormat,
+) -> Result<Pcm, ServerError> {
+    let mut body = body.clone();
+    body["input"] = sentence.into();
+
+    let audio = super::synthesize(body).await?;
+    let mut pcm = Pcm::from_wav(&audio.data).map_err(|e| {
+        ServerError::Operation(format!("Failed to decode the synthesized audio. {}", e))
+    })?;
+    if format == ResponseFormat::Pcm {
+        pcm.bits_per_sample = 16;
+    }
+
+    Ok(pcm)
+}
+
+/// Bytes of a sentence in the streamed format, after the header of `wav` streams.
+///
+/// `aac` and `mp3` are only accepted by the validation when their features are enabled.
+#[cfg_attr(not(any(feature = "aac", feature = "mp3")), allow(unused_variables))]
+fn encode_chunk(
+    pcm: &Pcm,
+    format: ResponseFormat,
+    bitrate: Option<u32>,
+) -> Result<Vec<u8>, ServerError> {
+    match format {
+        #[cfg(feature = "aac")]
+        ResponseFormat::Aac => {
+            audio::aac::encode(pcm, bitrate.unwrap_or(audio::aac::DEFAULT_BITRATE))
+                .map_err(ServerError::Operation)
+        }
+        // the tags are left out, as they only belong at the start of the stream
+        #[cfg(feature = "mp3")]
+        ResponseFormat::Mp3 => {
+            audio::mp3::encode(pcm, bitrate.unwrap_or(audio::mp3::DEFAULT_BITRATE), None)
+                .map_err(ServerError::Operation)
+        }
+        _ => Ok(pcm.to_le_bytes()),
+    }
+}
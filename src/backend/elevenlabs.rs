@@ -0,0 +1,213 @@
+//! Endpoints of the ElevenLabs API, so that its clients can be pointed at the server as they are.
+//!
+//! - `POST /v1/text-to-speech/{voice_id}` synthesizes `text` with the voice `voice_id`, answering
+//!   the audio in the `output_format` of the query, `mp3_44100_128` if not set.
+//! - `POST /v1/text-to-speech/{voice_id}/stream` streams it as it is synthesized, as
+//!   `"stream_format": "audio"` does.
+//! - `GET /v1/voices` lists the voices of the backend.
+//!
+//! The requests are mapped onto `/v1/audio/speech` requests, so the voices keep the defaults of
+//! `--voices-config`. The `speed` of the `voice_settings` is applied, its other settings,
+//! `model_id` and `language_code` are ignored. Clients may send their API key as the `xi-api-key` header.
+
+use crate::error::{self, ServerError};
+use hyper::{body::to_bytes, http::Method, Body, Request, Response};
+use serde::Deserialize;
+
+/// Output format of the requests whose query does not set `output_format`, as in the ElevenLabs
+/// API.
+const DEFAULT_OUTPUT_FORMAT: &str = "mp3_44100_128";
+
+#[derive(Debug, Deserialize)]
+struct TextToSpeechRequest {
+    /// The text to speak.
+    text: String,
+    #[serde(default)]
+    voice_settings: Option<VoiceSettings>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct VoiceSettings {
+    #[serde(default)]
+    speed: Option<f32>,
+}
+
+/// `POST /v1/text-to-speech/{voice_id}` and `POST /v1/text-to-speech/{voice_id}/stream`.
+pub(crate) async fn text_to_speech_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming text to speech request");
+
+    if req.method() != Method::POST {
+        return error::bad_request(format!(
+            "`{}` is not supported by the text to speech endpoint, use `POST`.",
+            req.method()
+        ));
+    }
+
+    let path = req.uri().path().trim_end_matches('/');
+    let (voice_id, stream) = match path.strip_prefix("/v1/text-to-speech/") {
+        Some(rest) => match rest.strip_suffix("/stream") {
+            Some(voice_id) => (voice_id.to_string(), true),
+            None => (rest.to_string(), false),
+        },
+        None => return error::invalid_endpoint(path),
+    };
+    if voice_id.is_empty() || voice_id.contains('/') {
+        return error::invalid_endpoint(path);
+    }
+    let output_format = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("output_format="))
+        .unwrap_or(DEFAULT_OUTPUT_FORMAT)
+        .to_string();
+
+    // parse request
+    let body_bytes = match to_bytes(req.into_body()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+    let request: TextToSpeechRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            let err_msg = format!("Fail to deserialize text to speech request: {msg}", msg = e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    let body = match speech_body(request, &voice_id, &output_format, stream) {
+        Ok(body) => body,
+        Err(e) => {
+            // log
+            error!(target: "stdout", "{}", &e);
+
+            return error::server_error(e);
+        }
+    };
+
+    super::log_speech_request(&body);
+
+    if stream {
+        return super::chunked::audio_speech_chunked(body).await;
+    }
+
+    let res = match super::synthesize_with_timeout(body).await {
+        Ok(synthesis) => super::audio_response(synthesis),
+        Err(e) => error::server_error(e),
+    };
+
+    info!(target: "stdout", "Send the text to speech response");
+
+    res
+}
+
+/// The `/v1/audio/speech` request body of a text to speech request.
+fn speech_body(
+    request: TextToSpeechRequest,
+    voice_id: &str,
+    output_format: &str,
+    stream: bool,
+) -> Result<serde_json::Value, ServerError> {
+    let (response_format, sample_rate, bitrate) = parse_output_format(output_format)
+        .ok_or_else(|| {
+            ServerError::BadRequest(format!(
+                "Unsupported `output_format` {}, expected e.g. `mp3_44100_128`, `opus_48000_64`, `pcm_16000` or `wav_22050`.",
+                output_format
+            ))
+        })?;
+
+    let mut body = serde_json::json!({
+        "model": crate::MODEL_NAME.get().cloned().unwrap_or_default(),
+        "input": request.text,
+        "voice": voice_id,
+        "response_format": response_format,
+        "sample_rate": sample_rate,
+    });
+    if let Some(bitrate) = bitrate {
+        body["bitrate"] = bitrate.into();
+    }
+    if let Some(speed) = request.voice_settings.unwrap_or_default().speed {
+        body["speed"] = speed.into();
+    }
+    if stream {
+        body["stream_format"] = "audio".into();
+    }
+
+    Ok(body)
+}
+
+/// The response format, sample rate and bitrate of an ElevenLabs output format, e.g.
+/// `mp3_44100_128` or `pcm_16000`.
+fn parse_output_format(output_format: &str) -> Option<(&str, u32, Option<u32>)> {
+    let mut parts = output_format.split('_');
+    let format = parts.next()?;
+    let sample_rate = parts.next()?.parse().ok()?;
+    let kbps: Option<u32> = match parts.next() {
+        Some(kbps) => Some(kbps.parse().ok()?),
+        None => None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+
+    match (format, kbps) {
+        ("mp3" | "opus", Some(kbps)) => Some((format, sample_rate, Some(kbps.checked_mul(1000)?))),
+        ("pcm" | "wav", None) => Some((format, sample_rate, None)),
+        _ => None,
+    }
+}
+
+/// `GET /v1/voices`: the voices of `GET /v1/audio/voices`, in the shape of the ElevenLabs API.
+pub(crate) async fn voices_handler(_req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming ElevenLabs voices request");
+
+    let voices: Vec<serde_json::Value> = super::voices::list()
+        .into_iter()
+        .map(|voice| {
+            let mut labels = serde_json::Map::new();
+            for label in ["language", "gender"] {
+                if let Some(value) = voice[label].as_str() {
+                    labels.insert(label.to_string(), value.into());
+                }
+            }
+
+            serde_json::json!({
+                "voice_id": voice["id"],
+                "name": voice["id"],
+                "category": "premade",
+                "labels": labels,
+            })
+        })
+        .collect();
+    let body = serde_json::json!({ "voices": voices });
+
+    let result = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
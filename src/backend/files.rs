@@ -0,0 +1,948 @@
+//! `/v1/files`: storage of uploaded files.
+//!
+//! The `infer` function of the gpt_sovits plugin only takes a speaker and a text, so gpt_sovits
+//! requests cannot reference an uploaded file; its reference audio is set up in the plugin.
+//!
+//! Files are kept in `{output dir}/{file_id}/{filename}`, the output directory being
+//! `--output-dir`, `archives` by default. With `--file-ttl` or `--files-max-bytes`,
+//! a janitor task removes the files once they expire, and the oldest ones while the directory
+//! is over its size limit.
+
+use crate::{audio::wav::Pcm, error};
+use endpoints::files::{DeleteFileStatus, FileObject, ListFilesResponse};
+use hyper::{body::to_bytes, http::Method, Body, Request, Response, StatusCode};
+use multipart::server::{Multipart, ReadEntry, ReadEntryResult};
+use once_cell::sync::OnceCell;
+use std::{
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Directory holding the uploaded files when `--output-dir` is not set.
+pub(crate) const DEFAULT_OUTPUT_DIR: &str = "archives";
+
+// directory holding the uploaded files, given by `--output-dir`
+static OUTPUT_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// Longest time between two runs of the janitor.
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60);
+
+// the retention policy given on the command line
+static RETENTION: OnceCell<Retention> = OnceCell::new();
+
+#[derive(Debug, Clone, Copy)]
+struct Retention {
+    /// Age after which a file is removed.
+    ttl: Option<Duration>,
+    /// Total size of the files above which the oldest ones are removed.
+    max_bytes: Option<u64>,
+}
+
+/// Why a stored file could not be accessed.
+enum FileError {
+    /// The id is not of the form `file_{uuid}`.
+    InvalidId(String),
+    /// No file is stored with the id.
+    NotFound(String),
+    /// The file exists but could not be read or removed.
+    Io(String),
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FileError::InvalidId(id) => write!(f, "Invalid file id: {}", id),
+            FileError::NotFound(id) => write!(f, "No such file: {}", id),
+            FileError::Io(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl FileError {
+    /// The error response: 400 or 404 for a bad id, 500 otherwise.
+    fn into_response(self) -> Response<Body> {
+        let err_msg = self.to_string();
+
+        match self {
+            FileError::InvalidId(_) => error::error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid_file_id",
+                Some("file_id"),
+                err_msg,
+            ),
+            FileError::NotFound(_) => error::error_response(
+                StatusCode::NOT_FOUND,
+                "file_not_found",
+                Some("file_id"),
+                err_msg,
+            ),
+            FileError::Io(_) => error::internal_server_error(err_msg),
+        }
+    }
+}
+
+/// Upload, download, retrieve and delete a file, or list all files.
+///
+/// - `POST /v1/files`: Upload a file.
+/// - `GET /v1/files`: List all files.
+/// - `GET /v1/files/{file_id}`: Retrieve a file by id.
+/// - `GET /v1/files/{file_id}/content`: Retrieve the content of a file by id.
+/// - `GET /v1/files/download/{file_id}`: Download a file by id.
+/// - `DELETE /v1/files/{file_id}`: Delete a file by id.
+///
+pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming files request");
+
+    let res = if req.method() == Method::POST {
+        upload_file(req).await
+    } else if req.method() == Method::GET {
+        let uri_path = req.uri().path().trim_end_matches('/').to_lowercase();
+
+        // Split the path into segments
+        let segments: Vec<&str> = uri_path.split('/').collect();
+
+        match segments.as_slice() {
+            ["", "v1", "files"] => list_files().await,
+            ["", "v1", "files", file_id, "content"] => retrieve_file_content(file_id).await,
+            ["", "v1", "files", file_id] => retrieve_file(file_id).await,
+            ["", "v1", "files", "download", file_id] => download_file(file_id).await,
+            _ => {
+                let err_msg = format!("unsupported uri path: {}", uri_path);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::internal_server_error(err_msg)
+            }
+        }
+    } else if req.method() == Method::DELETE {
+        let id = req.uri().path().trim_start_matches("/v1/files/");
+        let status = match delete_file(id).await {
+            Ok(status) => status,
+            Err(e @ (FileError::InvalidId(_) | FileError::NotFound(_))) => {
+                return e.into_response()
+            }
+            Err(e) => {
+                let err_msg = format!("Failed to delete the target file with id {}. {}", id, e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                DeleteFileStatus {
+                    id: id.into(),
+                    object: "file".to_string(),
+                    deleted: false,
+                }
+            }
+        };
+
+        // serialize status
+        let s = match serde_json::to_string(&status) {
+            Ok(s) => s,
+            Err(e) => {
+                let err_msg = format!(
+                    "Failed to serialize the status of the file deletion operation. {}",
+                    e
+                );
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        };
+
+        // return response
+        let result = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(s));
+
+        match result {
+            Ok(response) => response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::internal_server_error(err_msg)
+            }
+        }
+    } else if req.method() == Method::OPTIONS {
+        let result = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
+
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "files_handler", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        }
+    } else {
+        let err_msg = "Invalid HTTP Method.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        error::internal_server_error(err_msg)
+    };
+
+    info!(target: "stdout", "Send the files response");
+
+    res
+}
+
+async fn list_files() -> Response<Body> {
+    match list_file_objects().await {
+        Ok(file_objects) => {
+            // serialize chat completion object
+            let s = match serde_json::to_string(&file_objects) {
+                Ok(s) => s,
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize file list. {}", e);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+
+            // return response
+            let result = Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(s));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to list all files. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+async fn retrieve_file(id: impl AsRef<str>) -> Response<Body> {
+    match find_file_object(id.as_ref()).await {
+        Ok(fo) => {
+            // serialize chat completion object
+            let s = match file_object_json(&fo) {
+                Ok(s) => s,
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize file object. {}", e);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+
+            // return response
+            let result = Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(s));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn retrieve_file_content(id: impl AsRef<str>) -> Response<Body> {
+    match read_file_content(id.as_ref())
+        .await
+        .and_then(|(_, buffer)| {
+            String::from_utf8(buffer)
+                .map_err(|e| FileError::Io(format!("Failed to read the file. {}", e)))
+        }) {
+        Ok(content) => {
+            // serialize chat completion object
+            let s = match serde_json::to_string(&content) {
+                Ok(s) => s,
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize file content. {}", e);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+
+            // return response
+            let result = Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(s));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn download_file(id: impl AsRef<str>) -> Response<Body> {
+    // files stored in S3 are downloaded from the bucket rather than through the server
+    #[cfg(feature = "s3")]
+    if super::s3::bucket().is_some() {
+        return match find_file_object(id.as_ref()).await {
+            Ok(file_object) => redirect(presigned_url(&file_object).unwrap_or_default()),
+            Err(e) => e.into_response(),
+        };
+    }
+
+    match read_file_content(id.as_ref()).await {
+        Ok((filename, buffer)) => {
+            // get the extension of the file
+            let extension = filename.split('.').last().unwrap_or("unknown");
+            let content_type = match extension {
+                "txt" => "text/plain",
+                "json" => "application/json",
+                "png" => "image/png",
+                "jpg" => "image/jpeg",
+                "jpeg" => "image/jpeg",
+                "wav" => "audio/wav",
+                "mp3" => "audio/mpeg",
+                "ogg" => "audio/ogg",
+                "flac" => "audio/flac",
+                "aac" => "audio/aac",
+                "pcm" => "audio/pcm",
+                "mp4" => "video/mp4",
+                "md" => "text/markdown",
+                _ => {
+                    let err_msg = format!("Unsupported file extension: {}", extension);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+            let content_disposition = format!("attachment; filename={}", filename);
+
+            // return response
+            let result = Response::builder()
+                .header("Content-Type", content_type)
+                .header("Content-Disposition", content_disposition)
+                .body(Body::from(buffer));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Store the `file` field of a `multipart/form-data` request, which must be a readable audio.
+async fn upload_file(req: Request<Body>) -> Response<Body> {
+    let boundary = req
+        .headers()
+        .get("content-type")
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split_once("boundary="))
+        .map(|(_, boundary)| boundary.trim_matches('"').to_string());
+    let boundary = match boundary {
+        Some(boundary) => boundary,
+        None => {
+            let err_msg = "Expected a `multipart/form-data` request with a boundary.";
+
+            // log
+            error!(target: "stdout", "{}", err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    let content_length = req
+        .headers()
+        .get("content-length")
+        .and_then(|content_length| content_length.to_str().ok())
+        .and_then(|content_length| content_length.parse::<usize>().ok());
+
+    // a body that cannot be read to the end was aborted by the client
+    let body_bytes = match to_bytes(req.into_body()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("The upload was interrupted. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+    if let Some(content_length) = content_length {
+        if body_bytes.len() != content_length {
+            let err_msg = format!(
+                "The upload is incomplete, expected {} bytes but received {}.",
+                content_length,
+                body_bytes.len()
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    }
+
+    let mut multipart = Multipart::with_body(Cursor::new(body_bytes.to_vec()), boundary);
+    let mut upload = None;
+    let mut purpose = "assistants".to_string();
+    while let ReadEntryResult::Entry(mut field) = multipart.read_entry_mut() {
+        let mut buffer = Vec::new();
+        if let Err(e) = field.data.read_to_end(&mut buffer) {
+            let err_msg = format!("Failed to read the `{}` field. {}", field.headers.name, e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+
+        match &*field.headers.name {
+            "file" => {
+                let filename = field
+                    .headers
+                    .filename
+                    .clone()
+                    .unwrap_or_else(|| "audio.wav".to_string());
+                upload = Some((filename, buffer));
+            }
+            "purpose" => purpose = String::from_utf8_lossy(&buffer).trim().to_string(),
+            _ => {}
+        }
+    }
+
+    let (filename, buffer) = match upload {
+        Some(upload) => upload,
+        None => {
+            let err_msg = "The request has no `file` field.";
+
+            // log
+            error!(target: "stdout", "{}", err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    // only the file name is kept, so the upload cannot escape its directory
+    let filename = match std::path::Path::new(&filename)
+        .file_name()
+        .and_then(|name| name.to_str())
+    {
+        Some(filename) => filename.to_string(),
+        None => {
+            let err_msg = format!("Invalid file name: {}", filename);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    if let Err(e) = Pcm::from_wav(&buffer) {
+        let err_msg = format!("The uploaded file is not a readable audio file. {}", e);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::bad_request(err_msg);
+    }
+
+    let id = format!("file_{}", uuid::Uuid::new_v4());
+    if let Err(e) = save_file(&id, &filename, &buffer).await {
+        let err_msg = format!("Failed to store the uploaded file. {}", e);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    // log
+    info!(target: "stdout", "Stored {} as {}", &filename, &id);
+
+    // make room at once rather than at the next run of the janitor
+    if RETENTION
+        .get()
+        .is_some_and(|retention| retention.max_bytes.is_some())
+    {
+        clean_up().await;
+    }
+
+    let file_object = FileObject {
+        id,
+        bytes: buffer.len() as u64,
+        created_at: unix_time(SystemTime::now()),
+        filename,
+        object: "file".to_string(),
+        purpose,
+    };
+
+    // serialize file object
+    let s = match file_object_json(&file_object) {
+        Ok(s) => s,
+        Err(e) => {
+            let err_msg = format!("Failed to serialize file object. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    // return response
+    let result = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(s));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// Write `buffer` to `{output dir}/{id}/{filename}`.
+///
+/// The file is written under a temporary name first and renamed once complete, so a failed
+/// write never leaves a truncated file behind.
+fn store_file(id: &str, filename: &str, buffer: &[u8]) -> std::io::Result<()> {
+    let dir = output_dir().join(id);
+    std::fs::create_dir_all(&dir)?;
+
+    let tmp_path = dir.join(format!(".{}.tmp", filename));
+    let result = std::fs::write(&tmp_path, buffer)
+        .and_then(|_| std::fs::rename(&tmp_path, dir.join(filename)));
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    result
+}
+
+/// Whether `name` is the name of a file still being written by [`store_file`].
+fn is_temporary(name: &std::ffi::OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with('.') && name.ends_with(".tmp")
+}
+
+/// Check that `id` is of the form `file_{uuid}`.
+fn check_id(id: &str) -> Result<(), FileError> {
+    let valid_id = id
+        .strip_prefix("file_")
+        .is_some_and(|uuid| uuid::Uuid::try_parse(uuid).is_ok());
+    match valid_id {
+        true => Ok(()),
+        false => Err(FileError::InvalidId(id.to_string())),
+    }
+}
+
+/// Path of the stored file with the given id.
+fn read_file_path(id: &str) -> Result<PathBuf, FileError> {
+    check_id(id)?;
+
+    let dir = output_dir().join(id);
+    let entry = std::fs::read_dir(&dir)
+        .map_err(|_| FileError::NotFound(id.to_string()))?
+        .flatten()
+        .find(|entry| entry.path().is_file() && !is_temporary(&entry.file_name()))
+        .ok_or_else(|| FileError::NotFound(id.to_string()))?;
+
+    Ok(entry.path())
+}
+
+fn read_file_object(id: &str) -> Result<FileObject, FileError> {
+    let path = read_file_path(id)?;
+    let metadata = std::fs::metadata(&path)
+        .map_err(|e| FileError::Io(format!("Failed to read the file. {}", e)))?;
+
+    Ok(FileObject {
+        id: id.to_string(),
+        bytes: metadata.len(),
+        created_at: metadata.modified().map(unix_time).unwrap_or_default(),
+        filename: path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_string(),
+        object: "file".to_string(),
+        purpose: "assistants".to_string(),
+    })
+}
+
+fn read_file_objects() -> Result<ListFilesResponse, String> {
+    let mut data = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(output_dir()) {
+        for entry in entries.flatten() {
+            if let Some(id) = entry.file_name().to_str() {
+                if let Ok(file_object) = read_file_object(id) {
+                    data.push(file_object);
+                }
+            }
+        }
+    }
+    data.sort_by_key(|file_object| file_object.created_at);
+
+    Ok(ListFilesResponse {
+        object: "list".to_string(),
+        data,
+    })
+}
+
+fn remove_file(id: &str) -> Result<DeleteFileStatus, FileError> {
+    let path = read_file_path(id)?;
+    let dir = path
+        .parent()
+        .ok_or_else(|| FileError::Io("Invalid file path.".to_string()))?;
+    std::fs::remove_dir_all(dir)
+        .map_err(|e| FileError::Io(format!("Failed to remove the file. {}", e)))?;
+
+    Ok(DeleteFileStatus {
+        id: id.to_string(),
+        object: "file".to_string(),
+        deleted: true,
+    })
+}
+
+/// Store synthesized audio as a new file named `filename`.
+///
+/// Returns its file object and the URL it can be downloaded from: the presigned URL of the file
+/// when it is stored in S3, or its `/v1/files/download` path.
+pub(crate) async fn store_audio(
+    filename: &str,
+    buffer: &[u8],
+) -> Result<(FileObject, String), String> {
+    let id = format!("file_{}", uuid::Uuid::new_v4());
+    save_file(&id, filename, buffer).await?;
+
+    // log
+    info!(target: "stdout", "Stored {} as {}", filename, &id);
+
+    if RETENTION
+        .get()
+        .is_some_and(|retention| retention.max_bytes.is_some())
+    {
+        clean_up().await;
+    }
+
+    let file_object = FileObject {
+        id,
+        bytes: buffer.len() as u64,
+        created_at: unix_time(SystemTime::now()),
+        filename: filename.to_string(),
+        object: "file".to_string(),
+        purpose: "speech".to_string(),
+    };
+    let url = presigned_url(&file_object)
+        .unwrap_or_else(|| format!("/v1/files/download/{}", file_object.id));
+
+    Ok((file_object, url))
+}
+
+/// Store the file `id` in the bucket, or on the local disk.
+async fn save_file(id: &str, filename: &str, buffer: &[u8]) -> Result<(), String> {
+    #[cfg(feature = "s3")]
+    if let Some(bucket) = super::s3::bucket() {
+        return bucket.put(id, filename, buffer.to_vec()).await;
+    }
+
+    store_file(id, filename, buffer).map_err(|e| e.to_string())
+}
+
+/// The file object of `id`, from the bucket or the local disk.
+async fn find_file_object(id: &str) -> Result<FileObject, FileError> {
+    check_id(id)?;
+
+    #[cfg(feature = "s3")]
+    if let Some(bucket) = super::s3::bucket() {
+        return match bucket.find(id).await {
+            Ok(Some(object)) => Ok(object.into()),
+            Ok(None) => Err(FileError::NotFound(id.to_string())),
+            Err(e) => Err(FileError::Io(e)),
+        };
+    }
+
+    read_file_object(id)
+}
+
+/// The file objects of every stored file, oldest first.
+async fn list_file_objects() -> Result<ListFilesResponse, String> {
+    #[cfg(feature = "s3")]
+    if let Some(bucket) = super::s3::bucket() {
+        let mut data: Vec<FileObject> = bucket
+            .list("")
+            .await?
+            .into_iter()
+            .map(FileObject::from)
+            .collect();
+        data.sort_by_key(|file_object| file_object.created_at);
+
+        return Ok(ListFilesResponse {
+            object: "list".to_string(),
+            data,
+        });
+    }
+
+    read_file_objects()
+}
+
+/// The name and the content of the file `id`.
+async fn read_file_content(id: &str) -> Result<(String, Vec<u8>), FileError> {
+    #[cfg(feature = "s3")]
+    if let Some(bucket) = super::s3::bucket() {
+        check_id(id)?;
+        let object = match bucket.find(id).await {
+            Ok(Some(object)) => object,
+            Ok(None) => return Err(FileError::NotFound(id.to_string())),
+            Err(e) => return Err(FileError::Io(e)),
+        };
+        let buffer = bucket.get(&object).await.map_err(FileError::Io)?;
+        return Ok((object.filename, buffer));
+    }
+
+    let path = read_file_path(id)?;
+    let filename = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_string();
+    std::fs::read(&path)
+        .map(|buffer| (filename, buffer))
+        .map_err(|e| FileError::Io(format!("Failed to read the file. {}", e)))
+}
+
+/// Delete the file `id` from the bucket or the local disk.
+async fn delete_file(id: &str) -> Result<DeleteFileStatus, FileError> {
+    #[cfg(feature = "s3")]
+    if let Some(bucket) = super::s3::bucket() {
+        check_id(id)?;
+        let object = match bucket.find(id).await {
+            Ok(Some(object)) => object,
+            Ok(None) => return Err(FileError::NotFound(id.to_string())),
+            Err(e) => return Err(FileError::Io(e)),
+        };
+        bucket.delete(&object).await.map_err(FileError::Io)?;
+        return Ok(DeleteFileStatus {
+            id: id.to_string(),
+            object: "file".to_string(),
+            deleted: true,
+        });
+    }
+
+    remove_file(id)
+}
+
+/// The JSON of `file_object`, with the presigned `url` of the file when it is stored in S3.
+fn file_object_json(file_object: &FileObject) -> serde_json::Result<String> {
+    let mut value = serde_json::to_value(file_object)?;
+    if let Some(url) = presigned_url(file_object) {
+        value["url"] = serde_json::Value::String(url);
+    }
+    serde_json::to_string(&value)
+}
+
+/// A URL the file can be downloaded from without credentials, if it is stored in S3.
+fn presigned_url(file_object: &FileObject) -> Option<String> {
+    #[cfg(feature = "s3")]
+    if let Some(bucket) = super::s3::bucket() {
+        return Some(bucket.presigned_url(&file_object.id, &file_object.filename));
+    }
+
+    let _ = file_object;
+    None
+}
+
+/// `307 Temporary Redirect` to `location`.
+#[cfg(feature = "s3")]
+fn redirect(location: String) -> Response<Body> {
+    let result = Response::builder()
+        .status(StatusCode::TEMPORARY_REDIRECT)
+        .header("Location", location)
+        .body(Body::empty());
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+impl From<super::s3::Object> for FileObject {
+    fn from(object: super::s3::Object) -> Self {
+        FileObject {
+            id: object.file_id,
+            bytes: object.size,
+            created_at: object.last_modified,
+            filename: object.filename,
+            object: "file".to_string(),
+            purpose: "assistants".to_string(),
+        }
+    }
+}
+
+/// Store the files in `dir`, created if missing.
+pub(crate) fn init_output_dir(dir: PathBuf) -> Result<(), String> {
+    std::fs::create_dir_all(&dir).map_err(|e| {
+        format!(
+            "Failed to create the output directory {}. {}",
+            dir.to_string_lossy(),
+            e
+        )
+    })?;
+
+    OUTPUT_DIR
+        .set(dir)
+        .map_err(|_| "Failed to set `OUTPUT_DIR`.".to_string())
+}
+
+fn output_dir() -> &'static Path {
+    OUTPUT_DIR
+        .get()
+        .map(PathBuf::as_path)
+        .unwrap_or(Path::new(DEFAULT_OUTPUT_DIR))
+}
+
+/// Set the retention policy of the files. Returns whether any limit is set, in which case
+/// [`janitor`] must be spawned.
+pub(crate) fn init_retention(
+    ttl: Option<Duration>,
+    max_bytes: Option<u64>,
+) -> Result<bool, String> {
+    RETENTION
+        .set(Retention { ttl, max_bytes })
+        .map_err(|_| "Failed to set `RETENTION`.".to_string())?;
+
+    Ok(ttl.is_some() || max_bytes.is_some())
+}
+
+/// Remove the expired files, and the oldest ones over the size limit, at regular intervals.
+pub(crate) async fn janitor() {
+    let interval = RETENTION
+        .get()
+        .and_then(|retention| retention.ttl)
+        .map_or(JANITOR_INTERVAL, |ttl| ttl.min(JANITOR_INTERVAL))
+        .max(Duration::from_secs(1));
+
+    loop {
+        clean_up().await;
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Apply the retention policy once.
+async fn clean_up() {
+    let Some(retention) = RETENTION.get() else {
+        return;
+    };
+    let files = match list_file_objects().await {
+        Ok(files) => files.data,
+        Err(e) => {
+            // log
+            error!(target: "stdout", "Failed to list the stored files. {}", e);
+
+            return;
+        }
+    };
+
+    // oldest first
+    let now = unix_time(SystemTime::now());
+    let mut total: u64 = files.iter().map(|file_object| file_object.bytes).sum();
+    for file_object in files {
+        let expired = retention
+            .ttl
+            .is_some_and(|ttl| now.saturating_sub(file_object.created_at) >= ttl.as_secs());
+        let over_limit = retention
+            .max_bytes
+            .is_some_and(|max_bytes| total > max_bytes);
+        if !expired && !over_limit {
+            continue;
+        }
+
+        match delete_file(&file_object.id).await {
+            Ok(_) => {
+                total -= file_object.bytes;
+
+                // log
+                info!(target: "stdout", "Removed the {} file {}", if expired { "expired" } else { "oldest" }, &file_object.id);
+            }
+            Err(e) => {
+                // log
+                warn!(target: "stdout", "Failed to remove the file {}. {}", &file_object.id, e);
+            }
+        }
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
@@ -1,5 +1,8 @@
-use crate::error;
-use hyper::{body::to_bytes, http::Method, Body, Request, Response};
+use super::{
+    registry::{BoxFuture, Capabilities, TtsBackend},
+    Synthesis,
+};
+use crate::{audio, error::ServerError, text};
 
 mod ffi {
     #[link(wasm_import_module = "gpt_sovits")]
@@ -35,112 +38,56 @@ fn infer(speaker: &str, text: &str) -> Result<Vec<u8>, &'static str> {
 #[allow(unused)]
 #[derive(Debug, serde::Deserialize)]
 pub struct SpeechRequest {
-    /// The text to generate audio for.
-    pub input: String,
+    /// The text to generate audio for, or segments of it separated by silence.
+    pub input: text::Input,
     /// Id of speaker.
     #[serde(alias = "voice")]
     pub speaker: String,
-
-    #[serde(default)]
-    pub response_format: String,
-    #[serde(default)]
-    pub speed: f32,
+    #[serde(flatten)]
+    pub options: audio::SpeechOptions,
+    #[serde(flatten)]
+    pub text: text::TextOptions,
 }
 
-fn create_speech(speech_request: SpeechRequest) -> anyhow::Result<Vec<u8>> {
-    let result =
-        infer(&speech_request.speaker, &speech_request.input).map_err(|e| anyhow::anyhow!(e))?;
-    Ok(result)
+/// Parse and validate a speech request, without synthesizing it.
+pub(crate) fn prepare(body: serde_json::Value) -> Result<SpeechRequest, ServerError> {
+    let mut speech_request: SpeechRequest = super::parse_speech_request(body)?;
+    super::check_speech_request(
+        &GptSovits,
+        &mut speech_request.input,
+        &speech_request.options,
+        &speech_request.text,
+    )?;
+
+    Ok(speech_request)
 }
 
-pub(crate) async fn audio_speech_handler(req: Request<Body>) -> Response<Body> {
-    // log
-    info!(target: "stdout", "Handling the coming audio speech request");
-
-    if req.method().eq(&Method::OPTIONS) {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
-
-        match result {
-            Ok(response) => return response,
-            Err(e) => {
-                let err_msg = e.to_string();
+/// Parse and validate a speech request, then synthesize and encode the audio.
+pub(crate) async fn synthesize(body: serde_json::Value) -> Result<Synthesis, ServerError> {
+    let speech_request = prepare(body)?;
+    super::synthesize_pieces(&speech_request.input, &speech_request.options, |piece| {
+        infer(&speech_request.speaker, piece)
+    })
+    .await
+}
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+/// The gpt_sovits backend.
+pub(crate) struct GptSovits;
 
-                return error::internal_server_error(err_msg);
-            }
-        }
+impl TtsBackend for GptSovits {
+    fn name(&self) -> &'static str {
+        "gpt_sovits"
     }
 
-    info!(target: "stdout", "Prepare the chat completion request.");
-
-    // parse request
-    let body_bytes = match to_bytes(req.into_body()).await {
-        Ok(body_bytes) => body_bytes,
-        Err(e) => {
-            let err_msg = format!("Fail to read buffer from request body. {}", e);
-
-            // log
-            error!(target: "stdout", "{}", &err_msg);
-
-            return error::internal_server_error(err_msg);
-        }
-    };
-    let speech_request: SpeechRequest = match serde_json::from_slice(&body_bytes) {
-        Ok(speech_request) => speech_request,
-        Err(e) => {
-            let err_msg = format!("Fail to deserialize speech request: {msg}", msg = e);
-
-            // log
-            error!(target: "stdout", "{}", &err_msg);
-
-            return error::bad_request(err_msg);
-        }
-    };
-
-    let wav_data = match create_speech(speech_request) {
-        Ok(obj) => obj,
-        Err(e) => {
-            let err_msg = format!("Failed to transcribe the audio. {}", e);
-
-            // log
-            error!(target: "stdout", "{}", &err_msg);
-
-            return error::internal_server_error(err_msg);
-        }
-    };
-
-    // return response
-    let result = Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .header(hyper::header::CONTENT_TYPE, "audio/wav")
-        .header(
-            hyper::header::CONTENT_DISPOSITION,
-            "attachment; filename=audio.wav",
-        )
-        .body(Body::from(wav_data));
-
-    let res = match result {
-        Ok(response) => response,
-        Err(e) => {
-            let err_msg = e.to_string();
-
-            // log
-            error!(target: "stdout", "{}", &err_msg);
-
-            error::internal_server_error(err_msg)
-        }
-    };
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
 
-    info!(target: "stdout", "Send the audio speech response");
+    fn prepare(&self, body: serde_json::Value) -> Result<(), ServerError> {
+        prepare(body).map(|_| ())
+    }
 
-    res
+    fn synthesize(&self, body: serde_json::Value) -> BoxFuture<Result<Synthesis, ServerError>> {
+        Box::pin(synthesize(body))
+    }
 }
@@ -0,0 +1,194 @@
+//! `GET /v1/health`: liveness of the server and, with `?deep=true`, of the synthesizer.
+//!
+//! `GET /health` and `GET /ready` are the liveness and readiness probes of orchestrators and load
+//! balancers. They are served without an API key, as probes cannot carry one.
+
+use crate::error;
+use hyper::{Body, Request, Response, StatusCode};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once the server is shutting down, so that it is no longer reported as ready.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Report the server as not ready from now on, while the pending requests are finished.
+pub(crate) fn set_draining() {
+    DRAINING.store(true, Ordering::Release);
+}
+
+/// Whether the query of `req` asks for a test synthesis.
+fn is_deep(req: &Request<Body>) -> bool {
+    req.uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .any(|pair| pair == "deep=true" || pair == "deep=1")
+}
+
+/// `GET /health`: the process is up and serving requests.
+pub(crate) fn liveness_handler() -> Response<Body> {
+    json_response(StatusCode::OK, serde_json::json!({ "status": "ok" }))
+}
+
+/// `GET /ready`: the model is loaded, the server is not shutting down and the synthesizer takes
+/// requests, its queue not being full. With `?deep=true`, a short text is also synthesized, as
+/// in the deep health checks.
+///
+/// Answers `503` with the failed checks otherwise.
+pub(crate) async fn readiness_handler(req: Request<Body>) -> Response<Body> {
+    let mut checks = serde_json::Map::new();
+    let mut ready = true;
+    let mut check = |name: &str, outcome: Result<(), String>| {
+        let value = match outcome {
+            Ok(()) => serde_json::json!({ "status": "ok" }),
+            Err(reason) => {
+                ready = false;
+                serde_json::json!({ "status": "failed", "reason": reason })
+            }
+        };
+        checks.insert(name.to_string(), value);
+    };
+
+    check(
+        "model",
+        match super::registry::registered() {
+            Some(_) => Ok(()),
+            None => Err("no synthesis backend is loaded".to_string()),
+        },
+    );
+    check(
+        "draining",
+        match DRAINING.load(Ordering::Acquire) {
+            true => Err("the server is shutting down".to_string()),
+            false => Ok(()),
+        },
+    );
+    check(
+        "queue",
+        match crate::MAX_QUEUE_SIZE.get() {
+            Some(&max_queue_size) if super::queue_len() >= max_queue_size => Err(format!(
+                "the queue is full, {} requests are already waiting",
+                max_queue_size
+            )),
+            _ => Ok(()),
+        },
+    );
+    if is_deep(&req) {
+        let outcome = match super::registry::registered() {
+            Some(backend) if backend.capabilities().test_synthesis => deep::check(backend).await,
+            Some(backend) => Err(format!(
+                "test syntheses are not supported by the {} backend",
+                backend.name()
+            )),
+            None => Err("no synthesis backend is loaded".to_string()),
+        };
+        check("synthesis", outcome);
+    }
+
+    let (status, value) = match ready {
+        true => (StatusCode::OK, "ready"),
+        false => (StatusCode::SERVICE_UNAVAILABLE, "not_ready"),
+    };
+    if !ready {
+        // log
+        warn!(target: "stdout", "Not ready: {}", serde_json::Value::Object(checks.clone()));
+    }
+
+    json_response(
+        status,
+        serde_json::json!({ "status": value, "checks": checks }),
+    )
+}
+
+pub(crate) async fn health_handler(req: Request<Body>) -> Response<Body> {
+    let deep = is_deep(&req);
+
+    if deep {
+        match super::registry::registered() {
+            Some(backend) if backend.capabilities().test_synthesis => {
+                if let Err(e) = deep::check(backend).await {
+                    return error::service_unavailable(format!(
+                        "The synthesizer is not working. {}",
+                        e
+                    ));
+                }
+            }
+            // e.g. gpt_sovits has no speaker known to be available for a test synthesis
+            Some(backend) => {
+                return error::not_implemented(format!(
+                    "deep checks are not supported by the {} backend",
+                    backend.name()
+                ))
+            }
+            None => return error::not_implemented("deep checks need a synthesis backend"),
+        }
+    }
+
+    json_response(
+        StatusCode::OK,
+        serde_json::json!({ "status": "ok", "deep": deep }),
+    )
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    let result = Response::builder()
+        .header("Content-Type", "application/json")
+        .status(status)
+        .body(Body::from(body.to_string()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+mod deep {
+    use crate::backend::registry::TtsBackend;
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    /// Longest time a deep health check may take before the synthesizer is reported as broken.
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    /// Deep health checks run at most once per interval; probes in between get the last outcome.
+    const INTERVAL: Duration = Duration::from_secs(30);
+
+    /// Time and outcome of the last deep health check.
+    static LAST_CHECK: Mutex<Option<(Instant, Result<(), String>)>> = Mutex::new(None);
+
+    /// Run the test synthesis of `backend`, reusing the last outcome if it is recent enough.
+    pub(super) async fn check(backend: &'static dyn TtsBackend) -> Result<(), String> {
+        if let Some((checked_at, outcome)) = LAST_CHECK.lock().unwrap().as_ref() {
+            if checked_at.elapsed() < INTERVAL {
+                return outcome.clone();
+            }
+        }
+
+        // log
+        info!(target: "stdout", "Run a deep health check");
+
+        let outcome = match tokio::time::timeout(TIMEOUT, backend.health()).await {
+            Ok(outcome) => outcome,
+            Err(_) => Err(format!(
+                "the synthesis did not finish within {} seconds",
+                TIMEOUT.as_secs()
+            )),
+        };
+
+        if let Err(e) = &outcome {
+            // log
+            error!(target: "stdout", "Deep health check failed: {}", e);
+        }
+
+        *LAST_CHECK.lock().unwrap() = Some((Instant::now(), outcome.clone()));
+
+        outcome
+    }
+}
@@ -0,0 +1,70 @@
+//! `GET /v1/info`: what the server is and how it is set up, so that clients and gateways fronting
+//! several inference servers can tell a text to speech server from the others and introspect it.
+//!
+//! The paths of the model files are given as hashes, which tell whether two servers load the same
+//! files without exposing the layout of the host.
+
+use crate::{audio, error};
+use hyper::{Body, Request, Response};
+
+/// Name of the server, as its package.
+pub(crate) const SERVER_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Role of the server among the inference servers.
+const SERVER_ROLE: &str = "text-to-speech";
+
+pub(crate) async fn info_handler(_req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming info request");
+
+    let backend = super::registry::registered();
+    let config = crate::CONFIG.get().cloned().unwrap_or_default();
+    let path_hash = |path: &serde_json::Value| {
+        path.as_str()
+            .map(|path| format!("{:016x}", super::cache::fnv1a(path.as_bytes())))
+    };
+
+    let body = serde_json::json!({
+        "name": SERVER_NAME,
+        "role": SERVER_ROLE,
+        "version": env!("CARGO_PKG_VERSION"),
+        "backend": backend.map(|backend| backend.name()),
+        "model": {
+            "name": crate::MODEL_NAME.get(),
+            "path_hash": path_hash(&config["model"]["path"]),
+            "config_hash": path_hash(&config["model"]["config"]),
+            "sample_rate": backend.and_then(|backend| backend.sample_rate()),
+        },
+        "apis": ["openai", "elevenlabs"],
+        "response_formats": super::models::response_formats(),
+        "sample_rates": audio::SUPPORTED_SAMPLE_RATES,
+        "stream_formats": ["sse", "audio"],
+        "limits": {
+            "max_input_chars": config["limits"]["max_input_chars"],
+            "max_request_bytes": config["limits"]["max_request_bytes"],
+            "max_chunk_chars": config["limits"]["max_chunk_chars"],
+            "max_queue_size": config["limits"]["max_queue_size"],
+            "max_connections": config["server"]["max_connections"],
+            "rate_limit_rpm": config["limits"]["rate_limit_rpm"],
+            "rate_limit_chars_per_day": config["limits"]["rate_limit_chars_per_day"],
+            "files_max_bytes": config["limits"]["files_max_bytes"],
+            "request_timeout_secs": config["limits"]["request_timeout_secs"],
+        },
+    });
+
+    let result = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
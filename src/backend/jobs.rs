@@ -0,0 +1,401 @@
+//! `/v1/audio/speech/jobs`: synthesis of long documents in the background.
+//!
+//! `POST /v1/audio/speech/jobs` takes the body of a `/v1/audio/speech` request and answers
+//! `202 Accepted` with a job right away. Jobs run one at a time, so a backlog of long documents
+//! does not crowd the synthesizer out for the other requests. Once done, the audio is stored as
+//! a `/v1/files` file, and `GET /v1/audio/speech/jobs/{id}` gives its file object and URL.
+//!
+//! A request with a `callback_url` also has the finished job, or its audio with
+//! `"callback_format": "audio"`, posted to that URL, see [`super::webhook`].
+
+use super::{files, webhook};
+use crate::{
+    audio::EncodedAudio,
+    error::{self, ServerError},
+};
+use hyper::{body::to_bytes, http::Method, Body, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::Semaphore;
+
+/// Time the finished jobs are kept before they are forgotten. Their files follow the retention
+/// policy of the files.
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Maximum number of jobs known at once, queued or finished.
+const MAX_JOBS: usize = 1024;
+
+static JOBS: Lazy<Mutex<HashMap<String, Job>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Held by the running job.
+static RUNNER: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(1));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JobStatus {
+    Queued,
+    InProgress,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Job {
+    id: String,
+    object: &'static str,
+    status: JobStatus,
+    /// Unix times in seconds.
+    created_at: u64,
+    completed_at: Option<u64>,
+    /// File object of the stored audio, once completed.
+    file: Option<serde_json::Value>,
+    /// URL the audio can be downloaded from, once completed.
+    url: Option<String>,
+    error: Option<JobError>,
+    callback: Option<Callback>,
+}
+
+/// Where and how the outcome of a job is posted.
+#[derive(Debug, Clone, Serialize)]
+struct Callback {
+    url: String,
+    format: CallbackFormat,
+    status: CallbackStatus,
+    /// Number of attempts made so far.
+    attempts: u32,
+    /// Error of the last attempt, once the callback is given up.
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CallbackFormat {
+    /// The job object, as returned by `GET /v1/audio/speech/jobs/{id}`.
+    Json,
+    /// The audio of completed jobs, the job object of failed ones.
+    Audio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CallbackStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct JobError {
+    status: u16,
+    message: String,
+}
+
+/// Create a job, or get the state of one.
+///
+/// - `POST /v1/audio/speech/jobs`: Create a job.
+/// - `GET /v1/audio/speech/jobs/{job_id}`: Get a job by id.
+pub(crate) async fn jobs_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming audio speech jobs request");
+
+    let method = req.method().clone();
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    match (&method, path.strip_prefix("/v1/audio/speech/jobs")) {
+        (&Method::OPTIONS, _) => json_response(StatusCode::OK, None),
+        (&Method::POST, Some("")) => create_job(req).await,
+        (&Method::GET, Some(id)) if id.starts_with('/') => {
+            let id = id.trim_start_matches('/');
+            match JOBS.lock().unwrap().get(id) {
+                Some(job) => json_response(StatusCode::OK, Some(job)),
+                None => error::invalid_endpoint(format!("No such job: {}", id)),
+            }
+        }
+        _ => {
+            let err_msg = format!("unsupported request: {} {}", method, path);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::bad_request(err_msg)
+        }
+    }
+}
+
+async fn create_job(req: Request<Body>) -> Response<Body> {
+    // parse request
+    let body_bytes = match to_bytes(req.into_body()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+    let mut body: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+        Ok(body) => body,
+        Err(e) => {
+            let err_msg = format!("Fail to deserialize speech request: {msg}", msg = e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    // the callback is not part of the speech request
+    let callback = match take_callback(&mut body) {
+        Ok(callback) => callback,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    // reject invalid requests now rather than in the job
+    if let Err(e) = super::validate(body.clone()) {
+        return error::server_error(e);
+    }
+    super::log_speech_request(&body);
+
+    let job = Job {
+        id: format!("job_{}", uuid::Uuid::new_v4()),
+        object: "speech.job",
+        status: JobStatus::Queued,
+        created_at: unix_time(),
+        completed_at: None,
+        file: None,
+        url: None,
+        error: None,
+        callback,
+    };
+    {
+        let mut jobs = JOBS.lock().unwrap();
+        forget_finished(&mut jobs);
+        if jobs.len() >= MAX_JOBS {
+            return error::too_many_requests(format!(
+                "Too many jobs, {} are already known.",
+                MAX_JOBS
+            ));
+        }
+        jobs.insert(job.id.clone(), job.clone());
+    }
+
+    // log
+    info!(target: "stdout", "Queued the job {}", &job.id);
+
+    tokio::spawn(run(job.id.clone(), body));
+
+    json_response(StatusCode::ACCEPTED, Some(&job))
+}
+
+/// Synthesize the body of the job `id` once the runner is free, and store the audio.
+async fn run(id: String, body: serde_json::Value) {
+    let _permit = RUNNER.acquire().await;
+    update(&id, |job| job.status = JobStatus::InProgress);
+
+    // log
+    info!(target: "stdout", "Run the job {}", &id);
+
+    // a single job holds the runner, it waits for the context rather than fail while busy
+    let (outcome, audio) = match super::synthesize_uncapped(body).await {
+        Ok(audio) => (
+            files::store_audio(&audio.file_name(), &audio.data)
+                .await
+                .map_err(ServerError::Operation),
+            Some(audio),
+        ),
+        Err(e) => (Err(e), None),
+    };
+
+    match outcome {
+        Ok((file, url)) => {
+            // log
+            info!(target: "stdout", "Completed the job {}", &id);
+
+            let file = serde_json::to_value(&file).ok();
+            update(&id, |job| {
+                job.status = JobStatus::Completed;
+                job.file = file;
+                job.url = Some(url);
+            })
+        }
+        Err(e) => {
+            // log
+            error!(target: "stdout", "The job {} failed. {}", &id, e);
+
+            let status = match e {
+                ServerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+                ServerError::Busy(_) => StatusCode::SERVICE_UNAVAILABLE,
+                ServerError::QueueFull(_) => StatusCode::TOO_MANY_REQUESTS,
+                ServerError::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            update(&id, |job| {
+                job.status = JobStatus::Failed;
+                job.error = Some(JobError {
+                    status: status.as_u16(),
+                    message: e.to_string(),
+                });
+            })
+        }
+    }
+
+    notify(&id, audio).await;
+}
+
+/// Post the finished job `id`, or its `audio`, to its callback URL, if it has one.
+async fn notify(id: &str, audio: Option<EncodedAudio>) {
+    let Some(job) = JOBS.lock().unwrap().get(id).cloned() else {
+        return;
+    };
+    let Some(callback) = &job.callback else {
+        return;
+    };
+
+    let status = match job.status {
+        JobStatus::Completed => "completed",
+        _ => "failed",
+    };
+    let (content_type, payload) = match (callback.format, audio) {
+        (CallbackFormat::Audio, Some(audio)) if job.status == JobStatus::Completed => {
+            (audio.content_type().to_string(), audio.data)
+        }
+        _ => match serde_json::to_vec(&job) {
+            Ok(payload) => ("application/json".to_string(), payload),
+            Err(e) => {
+                // log
+                error!(target: "stdout", "Failed to serialize the job {}. {}", id, e);
+
+                return;
+            }
+        },
+    };
+
+    let (attempts, err_msg) =
+        webhook::deliver(&callback.url, id, status, &content_type, payload).await;
+    match &err_msg {
+        None => {
+            // log
+            info!(target: "stdout", "Delivered the callback of the job {}", id);
+        }
+        Some(err_msg) => {
+            // log
+            error!(target: "stdout", "Gave up the callback of the job {} after {} attempts. {}", id, attempts, err_msg);
+        }
+    }
+
+    if let Some(job) = JOBS.lock().unwrap().get_mut(id) {
+        if let Some(callback) = &mut job.callback {
+            callback.status = match err_msg {
+                None => CallbackStatus::Delivered,
+                Some(_) => CallbackStatus::Failed,
+            };
+            callback.attempts = attempts;
+            callback.error = err_msg;
+        }
+    }
+}
+
+/// Remove `callback_url` and `callback_format` from a job request body.
+fn take_callback(body: &mut serde_json::Value) -> Result<Option<Callback>, String> {
+    let Some(fields) = body.as_object_mut() else {
+        return Ok(None);
+    };
+    let url = fields.remove("callback_url");
+    let format = fields.remove("callback_format");
+
+    let url = match url {
+        None | Some(serde_json::Value::Null) => return Ok(None),
+        Some(serde_json::Value::String(url)) => url,
+        Some(url) => return Err(format!("`callback_url` must be a string, got `{}`", url)),
+    };
+    if !cfg!(feature = "webhooks") {
+        return Err(webhook::UNSUPPORTED.to_string());
+    }
+    webhook::check_url(&url)?;
+
+    let format = match format {
+        None | Some(serde_json::Value::Null) => CallbackFormat::Json,
+        Some(format) => serde_json::from_value(format.clone()).map_err(|_| {
+            format!(
+                "`callback_format` must be `json` or `audio`, got `{}`",
+                format
+            )
+        })?,
+    };
+
+    Ok(Some(Callback {
+        url,
+        format,
+        status: CallbackStatus::Pending,
+        attempts: 0,
+        error: None,
+    }))
+}
+
+fn update(id: &str, change: impl FnOnce(&mut Job)) {
+    if let Some(job) = JOBS.lock().unwrap().get_mut(id) {
+        change(job);
+        if matches!(job.status, JobStatus::Completed | JobStatus::Failed) {
+            job.completed_at = Some(unix_time());
+        }
+    }
+}
+
+/// Drop the jobs finished more than [`FINISHED_JOB_TTL`] ago.
+fn forget_finished(jobs: &mut HashMap<String, Job>) {
+    let now = unix_time();
+    jobs.retain(|_, job| match job.completed_at {
+        Some(completed_at) => now.saturating_sub(completed_at) < FINISHED_JOB_TTL.as_secs(),
+        None => true,
+    });
+}
+
+fn json_response(status: StatusCode, job: Option<&Job>) -> Response<Body> {
+    let body = match job.map(serde_json::to_string).transpose() {
+        Ok(body) => body.map(Body::from).unwrap_or_else(Body::empty),
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the job. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    let result = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(body);
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
@@ -1,32 +1,650 @@
+pub(crate) mod batch;
+pub(crate) mod cache;
+pub(crate) mod chunked;
+pub(crate) mod elevenlabs;
+pub(crate) mod files;
 #[cfg(feature = "gpt_sovits")]
 pub(crate) mod gpt_sovits;
+pub(crate) mod health;
+pub(crate) mod info;
+pub(crate) mod jobs;
+pub(crate) mod models;
 #[cfg(feature = "piper")]
 pub(crate) mod piper;
+pub(crate) mod registry;
+#[cfg(feature = "s3")]
+pub(crate) mod s3;
+pub(crate) mod sse;
+pub(crate) mod validate;
+pub(crate) mod voices;
+pub(crate) mod webhook;
+pub(crate) mod ws;
 
-use crate::error;
+use crate::{
+    audio::EncodedAudio,
+    error::{self, ServerError},
+};
 
-use hyper::{Body, Request, Response};
+use base64::Engine;
+use hyper::{body::to_bytes, http::Method, Body, Request, Response};
+use registry::TtsBackend;
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 
-#[cfg(all(feature = "piper", feature = "gpt_sovits"))]
-compile_error!("Only one of the features 'piper' and 'gpt_sovits' can be enabled at a time.");
+/// Reported when a speech endpoint is hit by a build without a synthesis backend.
+const NO_BACKEND: &str =
+    "no synthesis backend is enabled, build the server with the `piper` or `gpt_sovits` feature";
+
+/// Synthesis backends the server can be built with, selected at startup with `--backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    Piper,
+    #[value(name = "gpt_sovits")]
+    GptSovits,
+}
+impl Backend {
+    /// Whether the cargo feature of the backend is enabled in this build.
+    pub(crate) fn is_compiled(self) -> bool {
+        self.engine().is_some()
+    }
+
+    /// The implementation of the backend, if its cargo feature is enabled in this build.
+    #[allow(unreachable_patterns)]
+    pub(crate) fn engine(self) -> Option<&'static dyn TtsBackend> {
+        match self {
+            #[cfg(feature = "piper")]
+            Backend::Piper => Some(&piper::Piper),
+            #[cfg(feature = "gpt_sovits")]
+            Backend::GptSovits => Some(&gpt_sovits::GptSovits),
+            _ => None,
+        }
+    }
+
+    /// The backend used when `--backend` is not set: the first one compiled in.
+    pub(crate) fn default_compiled() -> Option<Self> {
+        [Backend::Piper, Backend::GptSovits]
+            .into_iter()
+            .find(|backend| backend.is_compiled())
+    }
+}
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Piper => write!(f, "piper"),
+            Backend::GptSovits => write!(f, "gpt_sovits"),
+        }
+    }
+}
+
+/// Set while a request is being synthesized, the backend context serves one at a time.
+static CONTEXT_BUSY: AtomicBool = AtomicBool::new(false);
+/// Number of requests waiting for the backend context.
+static QUEUE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+tokio::task_local! {
+    // set while a background job synthesizes, it waits for the backend context without the
+    // `--busy-retries` cap
+    static UNCAPPED_WAIT: bool;
+}
 
 pub(crate) async fn handle_llama_request(req: Request<Body>) -> Response<Body> {
     match req.uri().path() {
-        #[cfg(feature = "piper")]
-        "/v1/audio/speech" => piper::audio_speech_handler(req).await,
-        #[cfg(feature = "gpt_sovits")]
-        "/v1/audio/speech" => gpt_sovits::audio_speech_handler(req).await,
-        #[cfg(feature = "piper")]
-        "/v1/files" => piper::files_handler(req).await,
+        "/v1/audio/speech" => audio_speech_handler(req).await,
+        "/v1/audio/speech/batch" => batch::audio_speech_batch_handler(req).await,
+        "/v1/audio/speech/validate" => validate::audio_speech_validate_handler(req).await,
+        "/v1/audio/speech/stream" => ws::audio_speech_stream_handler(req).await,
+        "/v1/audio/speech/ws" => ws::audio_speech_ws_handler(req).await,
+        "/v1/audio/speech/jobs" => jobs::jobs_handler(req).await,
+        "/v1/audio/voices" => voices::voices_handler(req).await,
+        "/v1/health" => health::health_handler(req).await,
+        "/v1/info" => info::info_handler(req).await,
+        "/v1/models" => models::models_handler(req).await,
+        "/v1/files" => files::files_handler(req).await,
+        "/v1/voices" => elevenlabs::voices_handler(req).await,
         path => {
-            #[cfg(feature = "piper")]
             if path.starts_with("/v1/files/") {
-                piper::files_handler(req).await
+                files::files_handler(req).await
+            } else if path.starts_with("/v1/audio/speech/jobs/") {
+                jobs::jobs_handler(req).await
+            } else if path.starts_with("/v1/text-to-speech/") {
+                elevenlabs::text_to_speech_handler(req).await
+            } else if path.starts_with("/v1/models/") {
+                models::models_handler(req).await
             } else {
                 error::invalid_endpoint(path)
             }
-            #[cfg(feature = "gpt_sovits")]
-            error::invalid_endpoint(path)
+        }
+    }
+}
+
+/// `POST /v1/audio/speech`: synthesize a speech request with the registered backend, answering
+/// the audio, or streaming it as server-sent events or chunks when asked to.
+async fn audio_speech_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming audio speech request");
+
+    if req.method().eq(&Method::OPTIONS) {
+        let result = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
+
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        }
+    }
+
+    let backend = match registry::registered() {
+        Some(backend) => backend,
+        None => return error::not_implemented(NO_BACKEND),
+    };
+
+    // parse request
+    let headers = req.headers().clone();
+    let body_bytes = match to_bytes(req.into_body()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+    let body: serde_json::Value = match backend.speech_body(&headers, &body_bytes) {
+        Ok(body) => body,
+        Err(e) => {
+            let err_msg = format!("Fail to deserialize speech request: {msg}", msg = e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    log_speech_request(&body);
+
+    if sse::requested(&headers, &body) {
+        return sse::audio_speech_events(body);
+    }
+    if chunked::requested(&body) {
+        return chunked::audio_speech_chunked(body).await;
+    }
+
+    let res = match synthesize_with_timeout(body).await {
+        Ok(synthesis) => audio_response(synthesis),
+        Err(e) => error::server_error(e),
+    };
+
+    info!(target: "stdout", "Send the audio speech response");
+
+    res
+}
+
+/// Synthesized audio and how it was obtained.
+pub(crate) struct Synthesis {
+    pub audio: EncodedAudio,
+    /// Whether the audio was served from the cache.
+    pub cache_hit: bool,
+    /// Whether the synthesized audio was stored in the cache.
+    pub cache_stored: bool,
+    /// Time spent waiting for the backend context.
+    pub queue_time: Duration,
+    /// Position in the queue when the request arrived, 0 if it did not have to wait.
+    pub queue_position: usize,
+    /// Time spent synthesizing and post-processing the audio.
+    pub synthesis_time: Duration,
+}
+
+/// Synthesize a single speech request body with the enabled backend.
+pub(crate) async fn synthesize(body: serde_json::Value) -> Result<EncodedAudio, ServerError> {
+    synthesize_cached(body)
+        .await
+        .map(|synthesis| synthesis.audio)
+}
+
+/// Like [`synthesize`], but waits for the backend context however long it is held, instead of
+/// giving up after `--busy-retries` retries. For background jobs, which no client waits on.
+pub(crate) async fn synthesize_uncapped(
+    body: serde_json::Value,
+) -> Result<EncodedAudio, ServerError> {
+    UNCAPPED_WAIT.scope(true, synthesize(body)).await
+}
+
+/// Time allowed to synthesize a speech request: `--synthesis-timeout-base-ms` plus
+/// `--synthesis-timeout-per-char-ms` for every input character, capped at
+/// `--synthesis-timeout-max-ms`.
+pub(crate) fn synthesis_timeout(body: &serde_json::Value) -> Duration {
+    let chars = input_chars(body);
+
+    let base_ms = crate::SYNTHESIS_TIMEOUT_BASE_MS
+        .get()
+        .copied()
+        .unwrap_or(10_000);
+    let per_char_ms = crate::SYNTHESIS_TIMEOUT_PER_CHAR_MS
+        .get()
+        .copied()
+        .unwrap_or(100);
+    let max_ms = crate::SYNTHESIS_TIMEOUT_MAX_MS
+        .get()
+        .copied()
+        .unwrap_or(600_000);
+    let timeout_ms = base_ms
+        .saturating_add(per_char_ms.saturating_mul(chars as u64))
+        .min(max_ms);
+
+    // log
+    debug!(target: "stdout", "synthesis timeout: {} ms for {} input characters", timeout_ms, chars);
+
+    Duration::from_millis(timeout_ms)
+}
+
+/// Number of characters of the input of a speech request body, 0 if it is invalid.
+pub(crate) fn input_chars(body: &serde_json::Value) -> usize {
+    serde_json::from_value::<crate::text::Input>(body["input"].clone())
+        .map(|input| {
+            input
+                .segments()
+                .iter()
+                .map(|segment| segment.chars().count())
+                .sum()
+        })
+        .unwrap_or_default()
+}
+
+/// Like [`synthesize_cached`], but gives up once the request takes longer than its
+/// [`synthesis_timeout`].
+///
+/// The synthesizer is only interrupted between the pieces of the input, a single piece stuck in
+/// the plugin still blocks the server.
+pub(crate) async fn synthesize_with_timeout(
+    body: serde_json::Value,
+) -> Result<Synthesis, ServerError> {
+    let timeout = synthesis_timeout(&body);
+    match tokio::time::timeout(timeout, synthesize_cached(body)).await {
+        Ok(result) => result,
+        Err(_) => {
+            let err_msg = format!(
+                "The synthesis did not finish within {} ms.",
+                timeout.as_millis()
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            Err(ServerError::Timeout(err_msg))
+        }
+    }
+}
+
+/// Like [`synthesize`], but also tells whether the audio came from the cache and how long it
+/// took.
+pub(crate) async fn synthesize_cached(
+    mut body: serde_json::Value,
+) -> Result<Synthesis, ServerError> {
+    voices::apply_defaults(&mut body);
+    crate::text::ssml::expand(&mut body)
+        .map_err(|e| ServerError::BadRequest(format!("Invalid input: {}", e)))?;
+
+    let key = cache::key(&body);
+    if let Some(audio) = cache::get(&key) {
+        // log
+        info!(target: "stdout", "Serve the audio from the cache");
+
+        return Ok(Synthesis {
+            audio,
+            cache_hit: true,
+            cache_stored: false,
+            queue_time: Duration::ZERO,
+            queue_position: 0,
+            synthesis_time: Duration::ZERO,
+        });
+    }
+
+    let chars = input_chars(&body);
+    let backend = registry::registered()
+        .ok_or_else(|| ServerError::NotImplemented(NO_BACKEND.to_string()))?;
+    let synthesis = backend.synthesize(body).await?;
+    crate::metrics::record_synthesis(chars, synthesis.synthesis_time, synthesis.audio.duration);
+
+    let cache_stored = cache::insert(&key, synthesis.audio.clone());
+
+    Ok(Synthesis {
+        cache_stored,
+        ..synthesis
+    })
+}
+
+/// Exclusive use of the backend context, released on drop.
+pub(crate) struct ContextGuard {
+    /// Time spent waiting for the context.
+    pub waited: Duration,
+    /// Position in the queue when the request arrived, 0 if it did not have to wait.
+    pub queue_position: usize,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXT_BUSY.store(false, Ordering::Release);
+    }
+}
+
+/// Number of requests waiting for the backend context.
+pub(crate) fn queue_len() -> usize {
+    QUEUE_LEN.load(Ordering::Acquire)
+}
+
+/// A place in the queue of requests waiting for the backend context, left on drop.
+struct QueueSlot {
+    position: usize,
+}
+
+impl QueueSlot {
+    fn enter() -> Self {
+        QueueSlot {
+            position: QUEUE_LEN.fetch_add(1, Ordering::AcqRel) + 1,
+        }
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        QUEUE_LEN.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Take the backend context, retrying with exponential backoff while another request holds it.
+///
+/// Gives up with [`ServerError::Busy`] after `--busy-retries` retries, or right away with
+/// [`ServerError::QueueFull`] when `--max-queue-size` requests are already waiting. Within
+/// [`synthesize_uncapped`], it keeps retrying at the last backoff instead.
+pub(crate) async fn acquire_context() -> Result<ContextGuard, ServerError> {
+    let retries = crate::BUSY_RETRIES.get().copied().unwrap_or_default();
+    let backoff_ms = crate::BUSY_BACKOFF_MS.get().copied().unwrap_or_default();
+    let uncapped = UNCAPPED_WAIT
+        .try_with(|uncapped| *uncapped)
+        .unwrap_or_default();
+
+    let start = Instant::now();
+    let mut attempt = 0;
+    let mut queue_slot: Option<QueueSlot> = None;
+    loop {
+        if CONTEXT_BUSY
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            return Ok(ContextGuard {
+                waited: start.elapsed(),
+                queue_position: queue_slot.map_or(0, |slot| slot.position),
+            });
+        }
+
+        if queue_slot.is_none() {
+            let slot = QueueSlot::enter();
+            if let Some(&max_queue_size) = crate::MAX_QUEUE_SIZE.get() {
+                if slot.position > max_queue_size {
+                    return Err(ServerError::QueueFull(format!(
+                        "The queue is full, {} requests are already waiting.",
+                        max_queue_size
+                    )));
+                }
+            }
+            queue_slot = Some(slot);
+        }
+
+        if attempt >= retries && !uncapped {
+            return Err(ServerError::Busy(format!(
+                "The synthesizer is busy, gave up after {} retries.",
+                retries
+            )));
+        }
+
+        let delay_ms = backoff_ms.saturating_mul(1 << attempt.min(retries).min(16));
+        attempt += 1;
+
+        // log
+        match uncapped {
+            true => {
+                debug!(target: "stdout", "The synthesizer is busy, retry {} in {} ms", attempt, delay_ms)
+            }
+            false => {
+                debug!(target: "stdout", "The synthesizer is busy, retry {} of {} in {} ms", attempt, retries, delay_ms)
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+    }
+}
+
+/// Deserialize the speech request body of a backend.
+#[cfg(feature = "gpt_sovits")]
+pub(crate) fn parse_speech_request<T: serde::de::DeserializeOwned>(
+    body: serde_json::Value,
+) -> Result<T, ServerError> {
+    serde_json::from_value(body).map_err(|e| {
+        let err_msg = format!("Fail to deserialize speech request: {msg}", msg = e);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        ServerError::BadRequest(err_msg)
+    })
+}
+
+/// Check the speech options and the input of a speech request to `backend`, then normalize the
+/// input. Phoneme input is refused unless the backend takes it.
+#[cfg(feature = "gpt_sovits")]
+pub(crate) fn check_speech_request(
+    backend: &dyn TtsBackend,
+    input: &mut crate::text::Input,
+    options: &crate::audio::SpeechOptions,
+    text: &crate::text::TextOptions,
+) -> Result<(), ServerError> {
+    let bad_request = |err_msg: String| {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        ServerError::BadRequest(err_msg)
+    };
+
+    options
+        .validate()
+        .map_err(|e| bad_request(format!("Invalid speech options: {}", e)))?;
+    if text.input_format == crate::text::InputFormat::Phonemes
+        && !backend.capabilities().phoneme_input
+    {
+        return Err(bad_request(format!(
+            "Phoneme input is not supported by the {} backend.",
+            backend.name()
+        )));
+    }
+    input
+        .filter()
+        .map_err(|e| bad_request(format!("Invalid input: {}", e)))?;
+    input
+        .validate(crate::MAX_INPUT_CHARS.get().copied())
+        .map_err(|e| bad_request(format!("Invalid input: {}", e)))?;
+    input.normalize(text);
+
+    Ok(())
+}
+
+/// Synthesize the pieces of `input` one at a time with `infer` once the backend context is
+/// free, then post-process them into the audio of the response.
+#[cfg(feature = "gpt_sovits")]
+pub(crate) async fn synthesize_pieces<E: std::fmt::Display>(
+    input: &crate::text::Input,
+    options: &crate::audio::SpeechOptions,
+    mut infer: impl FnMut(&str) -> Result<Vec<u8>, E>,
+) -> Result<Synthesis, ServerError> {
+    let context = acquire_context().await?;
+    let started = Instant::now();
+
+    // timestamps are estimated per sentence, so each one is synthesized on its own
+    let mut segments = Vec::new();
+    for pieces in input.pieces(options.timestamps) {
+        let mut chunks = Vec::with_capacity(pieces.len());
+        for piece in pieces {
+            let wav = match infer(&piece) {
+                Ok(wav) => wav,
+                Err(e) => {
+                    let err_msg = format!(
+                        "Failed to synthesize a piece of {} characters. {}",
+                        piece.chars().count(),
+                        e
+                    );
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return Err(ServerError::Operation(err_msg));
+                }
+            };
+            chunks.push(crate::audio::Chunk { text: piece, wav });
+        }
+        segments.push(chunks);
+    }
+
+    match crate::audio::process(segments, options) {
+        Ok(audio) => Ok(Synthesis {
+            audio,
+            cache_hit: false,
+            cache_stored: false,
+            queue_time: context.waited,
+            queue_position: context.queue_position,
+            synthesis_time: started.elapsed(),
+        }),
+        Err(e) => {
+            let err_msg = format!("Failed to post-process the audio. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            Err(ServerError::Operation(err_msg))
+        }
+    }
+}
+
+/// Parse and validate a speech request body with the selected backend, without synthesizing it.
+pub(crate) fn validate(mut body: serde_json::Value) -> Result<(), ServerError> {
+    voices::apply_defaults(&mut body);
+    crate::text::ssml::expand(&mut body)
+        .map_err(|e| ServerError::BadRequest(format!("Invalid input: {}", e)))?;
+
+    match registry::registered() {
+        Some(backend) => backend.prepare(body),
+        None => Err(ServerError::NotImplemented(NO_BACKEND.to_string())),
+    }
+}
+
+/// Log the input text and the voice settings of a speech request at debug level.
+///
+/// The input is truncated to `--log-input-max-chars` characters, or left out entirely with
+/// `--log-redact-input`.
+pub(crate) fn log_speech_request(body: &serde_json::Value) {
+    if !log_enabled!(target: "stdout", log::Level::Debug) {
+        return;
+    }
+
+    let input = match &body["input"] {
+        serde_json::Value::String(input) => input.clone(),
+        input => input.to_string(),
+    };
+    let input_len = input.chars().count();
+    let input = match crate::LOG_REDACT_INPUT.get().copied().unwrap_or_default() {
+        true => format!("<redacted, {} characters>", input_len),
+        false => {
+            let max_chars = crate::LOG_INPUT_MAX_CHARS
+                .get()
+                .copied()
+                .unwrap_or(usize::MAX);
+            match input_len > max_chars {
+                true => format!(
+                    "{}... ({} characters)",
+                    input.chars().take(max_chars).collect::<String>(),
+                    input_len
+                ),
+                false => input,
+            }
+        }
+    };
+    let voice = body.get("voice").or_else(|| body.get("speaker"));
+    let speed = body.get("speed");
+
+    debug!(target: "stdout", "input: {:?}, voice: {}, speed: {}",
+        input,
+        voice.map(|v| v.to_string()).unwrap_or_else(|| "default".to_string()),
+        speed.map(|v| v.to_string()).unwrap_or_else(|| "default".to_string())
+    );
+}
+
+/// Build the response of the speech endpoints.
+///
+/// The encoded audio is the body, unless word timestamps were requested: then the body is a
+/// JSON object carrying the base64 encoded audio and the timestamps.
+pub(crate) fn audio_response(synthesis: Synthesis) -> Response<Body> {
+    let audio = synthesis.audio;
+    let mut builder = Response::builder()
+        .header("X-Queue-Time-Ms", synthesis.queue_time.as_millis() as u64)
+        .header("X-Queue-Position", synthesis.queue_position)
+        .header(
+            "X-Synthesis-Time-Ms",
+            synthesis.synthesis_time.as_millis() as u64,
+        );
+    if cache::enabled() {
+        let (x_cache, cache_status) = match (synthesis.cache_hit, synthesis.cache_stored) {
+            (true, _) => ("HIT", "tts-api-server; hit"),
+            (false, true) => ("MISS", "tts-api-server; fwd=miss; stored"),
+            // larger than `--cache-max-bytes`, or not written
+            (false, false) => ("MISS", "tts-api-server; fwd=miss"),
+        };
+        builder = builder
+            .header("X-Cache", x_cache)
+            .header("Cache-Status", cache_status);
+    }
+    if let Some(sample_rate) = audio.sample_rate {
+        builder = builder.header("X-Audio-Sample-Rate", sample_rate);
+    }
+
+    let result = match audio.words {
+        Some(words) => {
+            let body = serde_json::json!({
+                "audio": base64::engine::general_purpose::STANDARD.encode(&audio.data),
+                "format": audio.format.to_string(),
+                "content_type": audio.content_type(),
+                "sample_rate": audio.sample_rate,
+                "words": words,
+            });
+            builder
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+        }
+        None => builder
+            .header("Content-Type", audio.content_type())
+            .header("Content-Disposition", audio.content_disposition())
+            .body(Body::from(audio.data)),
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
         }
     }
 }
@@ -0,0 +1,79 @@
+//! `GET /v1/models` and `GET /v1/models/{model}`: the model served, in the shape of the OpenAI
+//! models API, with the backend and capabilities of the server as extra fields.
+
+use crate::{
+    audio::{self, ResponseFormat},
+    error,
+};
+use clap::ValueEnum;
+use hyper::{Body, Request, Response};
+
+pub(crate) async fn models_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming models request");
+
+    let model = model();
+    let body = match req.uri().path().strip_prefix("/v1/models/") {
+        Some(id) if Some(id) == model["id"].as_str() => model,
+        Some(id) => return error::invalid_endpoint(format!("no model named `{}`", id)),
+        None => serde_json::json!({ "object": "list", "data": [model] }),
+    };
+
+    let result = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// The response formats compiled in.
+pub(super) fn response_formats() -> Vec<String> {
+    // the formats that are compiled in pass the validation
+    ResponseFormat::value_variants()
+        .iter()
+        .filter(|format| {
+            let options = audio::SpeechOptions {
+                response_format: Some(**format),
+                ..Default::default()
+            };
+            options.validate().is_ok()
+        })
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// The model object of the `--model-name` model.
+fn model() -> serde_json::Value {
+    let backend = super::registry::registered();
+    let supports = backend
+        .map(|backend| backend.capabilities())
+        .unwrap_or_default();
+
+    serde_json::json!({
+        "id": crate::MODEL_NAME.get().cloned().unwrap_or_default(),
+        "object": "model",
+        "created": crate::START_TIME.get().copied().unwrap_or_default(),
+        "owned_by": super::info::SERVER_NAME,
+        "backend": backend.map(|backend| backend.name()),
+        "capabilities": {
+            "response_formats": response_formats(),
+            "sample_rates": audio::SUPPORTED_SAMPLE_RATES,
+            "stream_formats": ["sse", "audio"],
+            "max_input_chars": crate::MAX_INPUT_CHARS.get(),
+            "phoneme_input": supports.phoneme_input,
+            "native_speed": supports.native_speed,
+            "reference_audio": supports.reference_audio,
+            "test_synthesis": supports.test_synthesis,
+        },
+    })
+}
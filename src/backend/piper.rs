@@ -1,431 +1,327 @@
-use crate::error;
-use endpoints::{audio::speech::SpeechRequest, files::DeleteFileStatus};
-use hyper::{body::to_bytes, http::Method, Body, Request, Response};
-
-pub(crate) async fn audio_speech_handler(req: Request<Body>) -> Response<Body> {
-    // log
-    info!(target: "stdout", "Handling the coming audio speech request");
-
-    if req.method().eq(&Method::OPTIONS) {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
-
-        match result {
-            Ok(response) => return response,
-            Err(e) => {
-                let err_msg = e.to_string();
-
-                // log
-                error!(target: "stdout", "{}", &err_msg);
-
-                return error::internal_server_error(err_msg);
-            }
-        }
-    }
-
-    info!(target: "stdout", "Prepare the chat completion request.");
+use super::{
+    registry::{BoxFuture, Capabilities, TtsBackend},
+    Synthesis,
+};
+use crate::{audio, error::ServerError, text};
+use endpoints::audio::speech::SpeechRequest;
+use std::time::Instant;
+
+/// Text synthesized by a deep health check.
+const HEALTH_CHECK_INPUT: &str = "ok";
+
+/// A speech request that passed validation and is ready to be synthesized.
+pub(crate) struct PreparedRequest {
+    options: audio::SpeechOptions,
+    /// The request of every piece synthesized on its own, grouped by segment.
+    segments: Vec<Vec<SpeechRequest>>,
+}
 
-    // parse request
-    let body_bytes = match to_bytes(req.into_body()).await {
-        Ok(body_bytes) => body_bytes,
+/// Parse and validate a speech request, without synthesizing it.
+pub(crate) fn prepare(mut body: serde_json::Value) -> Result<PreparedRequest, ServerError> {
+    let mut options: audio::SpeechOptions = match serde_json::from_value(body.clone()) {
+        Ok(options) => options,
         Err(e) => {
-            let err_msg = format!("Fail to read buffer from request body. {}", e);
+            let err_msg = format!("Fail to deserialize speech options: {msg}", msg = e);
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return error::internal_server_error(err_msg);
+            return Err(ServerError::BadRequest(err_msg));
         }
     };
-    let speech_request: SpeechRequest = match serde_json::from_slice(&body_bytes) {
-        Ok(speech_request) => speech_request,
-        Err(e) => {
-            let err_msg = format!("Fail to deserialize speech request: {msg}", msg = e);
+    if let Err(e) = options.validate() {
+        let err_msg = format!("Invalid speech options: {}", e);
 
-            // log
-            error!(target: "stdout", "{}", &err_msg);
-
-            return error::bad_request(err_msg);
-        }
-    };
-
-    let audio_buffer = match llama_core::audio::create_speech(speech_request).await {
-        Ok(obj) => obj,
-        Err(e) => {
-            let err_msg = format!("Failed to transcribe the audio. {}", e);
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+        return Err(ServerError::BadRequest(err_msg));
+    }
 
-            return error::internal_server_error(err_msg);
+    // style keywords of the instructions fill in the speed and pitch the request leaves unset
+    if let Some(prosody) = options
+        .instructions
+        .as_deref()
+        .and_then(text::instructions::prosody)
+    {
+        if options.pitch_semitones.is_none() {
+            options.pitch_semitones = Some(prosody.pitch_semitones);
         }
-    };
+        if options.speed.is_none() {
+            options.speed = Some(prosody.speed);
+        }
+    }
 
-    // return response
-    let result = Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .header("Content-Type", "audio/wav")
-        .header("Content-Disposition", "attachment; filename=audio.wav")
-        .body(Body::from(audio_buffer));
-
-    let res = match result {
-        Ok(response) => response,
+    // piper always produces wav, other formats are encoded from it afterwards, and the speed is
+    // changed afterwards too
+    if let Some(body) = body.as_object_mut() {
+        body.remove("response_format");
+        body.remove("instructions");
+        body.remove("speed");
+    }
+    let text_options: text::TextOptions = match serde_json::from_value(body.clone()) {
+        Ok(text_options) => text_options,
         Err(e) => {
-            let err_msg = e.to_string();
+            let err_msg = format!("Fail to deserialize text options: {msg}", msg = e);
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            error::internal_server_error(err_msg)
+            return Err(ServerError::BadRequest(err_msg));
         }
     };
+    if text_options.input_format == text::InputFormat::Phonemes
+        && !crate::PHONEME_INPUT.get().copied().unwrap_or_default()
+    {
+        let err_msg = "Phoneme input requires a voice whose config sets `phoneme_type` to `text`.";
 
-    info!(target: "stdout", "Send the audio speech response");
-
-    res
-}
-
-/// Download, retrieve and delete a file, or list all files.
-///
-/// - `GET /v1/files`: List all files.
-/// - `GET /v1/files/{file_id}`: Retrieve a file by id.
-/// - `GET /v1/files/{file_id}/content`: Retrieve the content of a file by id.
-/// - `GET /v1/files/download/{file_id}`: Download a file by id.
-/// - `DELETE /v1/files/{file_id}`: Delete a file by id.
-///
-pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
-    // log
-    info!(target: "stdout", "Handling the coming files request");
-
-    let res = if req.method() == Method::GET {
-        let uri_path = req.uri().path().trim_end_matches('/').to_lowercase();
-
-        // Split the path into segments
-        let segments: Vec<&str> = uri_path.split('/').collect();
-
-        match segments.as_slice() {
-            ["", "v1", "files"] => list_files(),
-            ["", "v1", "files", file_id, "content"] => {
-                if !file_id.starts_with("file_") {
-                    let err_msg = format!("unsupported uri path: {}", uri_path);
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
-
-                    return error::internal_server_error(err_msg);
-                }
-
-                retrieve_file_content(file_id)
-            }
-            ["", "v1", "files", file_id] => {
-                if !file_id.starts_with("file_") {
-                    let err_msg = format!("unsupported uri path: {}", uri_path);
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+        // log
+        error!(target: "stdout", "{}", err_msg);
 
-                    return error::internal_server_error(err_msg);
-                }
+        return Err(ServerError::BadRequest(err_msg.to_string()));
+    }
 
-                retrieve_file(file_id)
-            }
-            ["", "v1", "files", "download", file_id] => download_file(file_id),
-            _ => {
-                let err_msg = format!("unsupported uri path: {}", uri_path);
+    let mut input: text::Input = match serde_json::from_value(body["input"].take()) {
+        Ok(input) => input,
+        Err(_) => {
+            let err_msg =
+                "Fail to deserialize speech request: `input` must be a string or a list of strings";
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+            // log
+            error!(target: "stdout", "{}", err_msg);
 
-                error::internal_server_error(err_msg)
-            }
+            return Err(ServerError::BadRequest(err_msg.to_string()));
         }
-    } else if req.method() == Method::DELETE {
-        let id = req.uri().path().trim_start_matches("/v1/files/");
-        let status = match llama_core::files::remove_file(id) {
-            Ok(status) => status,
-            Err(e) => {
-                let err_msg = format!("Failed to delete the target file with id {}. {}", id, e);
-
-                // log
-                error!(target: "stdout", "{}", &err_msg);
-
-                DeleteFileStatus {
-                    id: id.into(),
-                    object: "file".to_string(),
-                    deleted: false,
-                }
-            }
-        };
-
-        // serialize status
-        let s = match serde_json::to_string(&status) {
-            Ok(s) => s,
-            Err(e) => {
-                let err_msg = format!(
-                    "Failed to serialize the status of the file deletion operation. {}",
-                    e
-                );
-
-                // log
-                error!(target: "stdout", "{}", &err_msg);
-
-                return error::internal_server_error(err_msg);
-            }
-        };
-
-        // return response
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::from(s));
-
-        match result {
-            Ok(response) => response,
-            Err(e) => {
-                let err_msg = e.to_string();
+    };
+    if let Err(e) = input.filter() {
+        let err_msg = format!("Invalid input: {}", e);
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-                error::internal_server_error(err_msg)
-            }
-        }
-    } else if req.method() == Method::OPTIONS {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
-
-        match result {
-            Ok(response) => return response,
-            Err(e) => {
-                let err_msg = e.to_string();
-
-                // log
-                error!(target: "files_handler", "{}", &err_msg);
-
-                return error::internal_server_error(err_msg);
-            }
-        }
-    } else {
-        let err_msg = "Invalid HTTP Method.";
+        return Err(ServerError::BadRequest(err_msg));
+    }
+    if let Err(e) = input.validate(crate::MAX_INPUT_CHARS.get().copied()) {
+        let err_msg = format!("Invalid input: {}", e);
 
         // log
         error!(target: "stdout", "{}", &err_msg);
 
-        error::internal_server_error(err_msg)
-    };
+        return Err(ServerError::BadRequest(err_msg));
+    }
+    input.normalize(&text_options);
+    for segment in input.segments() {
+        if let Err(e) = check_input(segment) {
+            let err_msg = format!("Invalid input: {}", e);
 
-    info!(target: "stdout", "Send the files response");
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-    res
-}
+            return Err(ServerError::BadRequest(err_msg));
+        }
+    }
 
-fn list_files() -> Response<Body> {
-    match llama_core::files::list_files() {
-        Ok(file_objects) => {
-            // serialize chat completion object
-            let s = match serde_json::to_string(&file_objects) {
-                Ok(s) => s,
+    // timestamps are estimated per sentence, so each one is synthesized on its own
+    let mut segments = Vec::new();
+    for pieces in input.pieces(options.timestamps) {
+        let mut requests = Vec::with_capacity(pieces.len());
+        for piece in pieces {
+            body["input"] = piece.into();
+            let speech_request: SpeechRequest = match serde_json::from_value(body.clone()) {
+                Ok(speech_request) => speech_request,
                 Err(e) => {
-                    let err_msg = format!("Failed to serialize file list. {}", e);
+                    let err_msg = format!("Fail to deserialize speech request: {msg}", msg = e);
 
                     // log
                     error!(target: "stdout", "{}", &err_msg);
 
-                    return error::internal_server_error(err_msg);
+                    return Err(ServerError::BadRequest(err_msg));
                 }
             };
 
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", "application/json")
-                .body(Body::from(s));
-
-            match result {
-                Ok(response) => response,
-                Err(e) => {
-                    let err_msg = e.to_string();
+            requests.push(speech_request);
+        }
+        segments.push(requests);
+    }
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+    Ok(PreparedRequest { options, segments })
+}
 
-                    error::internal_server_error(err_msg)
-                }
-            }
+/// Parse and validate a speech request, then synthesize and encode the audio.
+pub(crate) async fn synthesize(body: serde_json::Value) -> Result<Synthesis, ServerError> {
+    let PreparedRequest { options, segments } = prepare(body)?;
+    let context = super::acquire_context().await?;
+    let started = Instant::now();
+
+    let mut chunks = Vec::with_capacity(segments.len());
+    for requests in segments {
+        let mut segment = Vec::with_capacity(requests.len());
+        for speech_request in requests {
+            let text = speech_request.input.clone();
+            let wav = create_speech(speech_request).await?;
+            segment.push(audio::Chunk { text, wav });
         }
+        chunks.push(segment);
+    }
+
+    match audio::process(chunks, &options) {
+        Ok(audio) => Ok(Synthesis {
+            audio,
+            cache_hit: false,
+            cache_stored: false,
+            queue_time: context.waited,
+            queue_position: context.queue_position,
+            synthesis_time: started.elapsed(),
+        }),
         Err(e) => {
-            let err_msg = format!("Failed to list all files. {}", e);
+            let err_msg = format!("Failed to post-process the audio. {}", e);
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            error::internal_server_error(err_msg)
+            Err(ServerError::Operation(err_msg))
         }
     }
 }
 
-fn retrieve_file(id: impl AsRef<str>) -> Response<Body> {
-    match llama_core::files::retrieve_file(id) {
-        Ok(fo) => {
-            // serialize chat completion object
-            let s = match serde_json::to_string(&fo) {
-                Ok(s) => s,
-                Err(e) => {
-                    let err_msg = format!("Failed to serialize file object. {}", e);
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
-
-                    return error::internal_server_error(err_msg);
-                }
-            };
-
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", "application/json")
-                .body(Body::from(s));
-
-            match result {
-                Ok(response) => response,
-                Err(e) => {
-                    let err_msg = e.to_string();
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
-
-                    error::internal_server_error(err_msg)
-                }
-            }
-        }
+/// Synthesize a single speech request, telling input errors apart from server faults.
+async fn create_speech(speech_request: SpeechRequest) -> Result<Vec<u8>, ServerError> {
+    let input_len = speech_request.input.chars().count();
+    match llama_core::audio::create_speech(speech_request).await {
+        Ok(audio_buffer) => Ok(audio_buffer),
         Err(e) => {
-            let err_msg = format!("{}", e);
-
             // log
-            error!(target: "stdout", "{}", &err_msg);
-
-            error::internal_server_error(err_msg)
+            error!(target: "stdout", "Failed to synthesize an input of {} characters. {}", input_len, e);
+
+            let reason = e.to_string();
+            match is_input_error(&reason) {
+                true => Err(ServerError::BadRequest(format!(
+                    "The input text could not be converted to speech. {}",
+                    reason
+                ))),
+                false => Err(ServerError::Operation(format!(
+                    "Failed to synthesize the speech. {}",
+                    reason
+                ))),
+            }
         }
     }
 }
 
-fn retrieve_file_content(id: impl AsRef<str>) -> Response<Body> {
-    match llama_core::files::retrieve_file_content(id) {
-        Ok(content) => {
-            // serialize chat completion object
-            let s = match serde_json::to_string(&content) {
-                Ok(s) => s,
-                Err(e) => {
-                    let err_msg = format!("Failed to serialize file content. {}", e);
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
-
-                    return error::internal_server_error(err_msg);
-                }
-            };
-
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", "application/json")
-                .body(Body::from(s));
+/// Reject input text that espeak-ng is known to choke on.
+fn check_input(input: &str) -> Result<(), String> {
+    if let Some((pos, c)) = input
+        .chars()
+        .enumerate()
+        .find(|(_, c)| c.is_control() && !c.is_whitespace())
+    {
+        return Err(format!(
+            "unsupported control character U+{:04X} at position {}",
+            c as u32, pos
+        ));
+    }
 
-            match result {
-                Ok(response) => response,
-                Err(e) => {
-                    let err_msg = e.to_string();
+    Ok(())
+}
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+/// Whether a synthesis error was caused by the input text rather than by the server.
+///
+/// Errors from the piper plugin only carry a message, so this is a best-effort match on it:
+/// problems with the model, the espeak-ng data directory or the context are server faults.
+fn is_input_error(reason: &str) -> bool {
+    let reason = reason.to_lowercase();
+    let server_fault = [
+        "directory",
+        "data dir",
+        "not found",
+        "context",
+        "model",
+        "init",
+    ]
+    .iter()
+    .any(|pattern| reason.contains(pattern));
+    let input_problem = ["phonem", "character", "utf", "text", "encod", "input"]
+        .iter()
+        .any(|pattern| reason.contains(pattern));
+
+    input_problem && !server_fault
+}
 
-                    error::internal_server_error(err_msg)
-                }
-            }
-        }
-        Err(e) => {
-            let err_msg = format!("{}", e);
+/// The piper backend.
+pub(crate) struct Piper;
 
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+impl TtsBackend for Piper {
+    fn name(&self) -> &'static str {
+        "piper"
+    }
 
-            error::internal_server_error(err_msg)
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            phoneme_input: crate::PHONEME_INPUT.get().copied().unwrap_or_default(),
+            test_synthesis: true,
+            ..Default::default()
         }
     }
-}
-
-fn download_file(id: impl AsRef<str>) -> Response<Body> {
-    match llama_core::files::download_file(id) {
-        Ok((filename, buffer)) => {
-            // get the extension of the file
-            let extension = filename.split('.').last().unwrap_or("unknown");
-            let content_type = match extension {
-                "txt" => "text/plain",
-                "json" => "application/json",
-                "png" => "image/png",
-                "jpg" => "image/jpeg",
-                "jpeg" => "image/jpeg",
-                "wav" => "audio/wav",
-                "mp3" => "audio/mpeg",
-                "mp4" => "video/mp4",
-                "md" => "text/markdown",
-                _ => {
-                    let err_msg = format!("Unsupported file extension: {}", extension);
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+    fn prepare(&self, body: serde_json::Value) -> Result<(), ServerError> {
+        prepare(body).map(|_| ())
+    }
 
-                    return error::internal_server_error(err_msg);
-                }
-            };
-            let content_disposition = format!("attachment; filename={}", filename);
-
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", content_type)
-                .header("Content-Disposition", content_disposition)
-                .body(Body::from(buffer));
-
-            match result {
-                Ok(response) => response,
-                Err(e) => {
-                    let err_msg = e.to_string();
+    fn synthesize(&self, body: serde_json::Value) -> BoxFuture<Result<Synthesis, ServerError>> {
+        Box::pin(synthesize(body))
+    }
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+    /// A single voice, or one voice per speaker of the `speaker_id_map` of the voice config, with
+    /// the language and sample rate of the config.
+    fn voices(&self) -> Vec<serde_json::Value> {
+        let config = crate::VOICE_CONFIG.get().cloned().unwrap_or_default();
+        let voice = |id: &str, speaker_id: Option<&serde_json::Value>| {
+            serde_json::json!({
+                "id": id,
+                "speaker_id": speaker_id,
+                "language": config["language"]["code"],
+                "gender": null,
+                "sample_rate": config["audio"]["sample_rate"],
+            })
+        };
 
-                    error::internal_server_error(err_msg)
-                }
+        match config["speaker_id_map"].as_object() {
+            Some(speakers) if !speakers.is_empty() => {
+                let mut speakers: Vec<_> = speakers.iter().collect();
+                speakers.sort_by_key(|(_, speaker_id)| speaker_id.as_u64());
+                speakers
+                    .into_iter()
+                    .map(|(name, speaker_id)| voice(name, Some(speaker_id)))
+                    .collect()
+            }
+            _ => {
+                let model_name = crate::MODEL_NAME.get().cloned().unwrap_or_default();
+                let id = config["dataset"].as_str().unwrap_or(&model_name);
+                vec![voice(id, None)]
             }
         }
-        Err(e) => {
-            let err_msg = format!("{}", e);
+    }
 
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+    /// The sample rate of the voice config.
+    fn sample_rate(&self) -> Option<u32> {
+        crate::VOICE_CONFIG
+            .get()
+            .and_then(|config| config["audio"]["sample_rate"].as_u64())
+            .map(|sample_rate| sample_rate as u32)
+    }
 
-            error::internal_server_error(err_msg)
-        }
+    fn health(&self) -> BoxFuture<Result<(), String>> {
+        let body = serde_json::json!({
+            "model": crate::MODEL_NAME.get().cloned().unwrap_or_default(),
+            "input": HEALTH_CHECK_INPUT,
+        });
+
+        Box::pin(async move {
+            match synthesize(body).await {
+                Ok(synthesis) if !synthesis.audio.data.is_empty() => Ok(()),
+                Ok(_) => Err("the synthesized audio is empty".to_string()),
+                Err(e) => Err(e.to_string()),
+            }
+        })
     }
 }
@@ -0,0 +1,85 @@
+//! The [`TtsBackend`] trait, implemented by every synthesis backend, and the registry of the
+//! backend serving the speech endpoints.
+//!
+//! A backend is a module of its own, which parses and synthesizes speech requests, lists its
+//! voices and tells what it supports. The router, the cache and the batch, job and streaming
+//! endpoints only go through the registered backend, so adding one takes its module, its variant
+//! of [`Backend`](super::Backend) and its arm in [`Backend::engine`](super::Backend::engine).
+
+use super::Synthesis;
+use crate::error::ServerError;
+use hyper::HeaderMap;
+use once_cell::sync::OnceCell;
+use std::{future::Future, pin::Pin};
+
+/// A future boxed to be returned by a trait object.
+pub(crate) type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// The backend serving the speech endpoints.
+static REGISTERED: OnceCell<&'static dyn TtsBackend> = OnceCell::new();
+
+/// What a backend supports, listed in the capabilities of `GET /v1/models`.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Capabilities {
+    /// Whether `"input_format": "phonemes"` is accepted.
+    pub phoneme_input: bool,
+    /// Whether the model speaks at the requested `speed` itself, instead of its audio being
+    /// time-stretched.
+    pub native_speed: bool,
+    /// Whether the voice can be taken from a reference audio.
+    pub reference_audio: bool,
+    /// Whether a test synthesis can check the synthesizer, for the self-test and the deep health
+    /// checks.
+    pub test_synthesis: bool,
+}
+
+/// A synthesis backend.
+pub(crate) trait TtsBackend: Send + Sync {
+    /// Name of the backend, as given to `--backend`.
+    fn name(&self) -> &'static str;
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// Parse and validate a speech request body, without synthesizing it.
+    fn prepare(&self, body: serde_json::Value) -> Result<(), ServerError>;
+
+    /// Parse and validate a speech request body, then synthesize and encode the audio.
+    fn synthesize(&self, body: serde_json::Value) -> BoxFuture<Result<Synthesis, ServerError>>;
+
+    /// The voices listed by `GET /v1/audio/voices`, by default the ones of `--voices-config`.
+    fn voices(&self) -> Vec<serde_json::Value> {
+        super::voices::configured()
+    }
+
+    /// Sample rate of the audio the model synthesizes, if the backend knows it.
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Synthesize a short text end to end, bypassing the cache, and fail if the synthesizer is
+    /// broken. Only called when [`Capabilities::test_synthesis`] is set.
+    fn health(&self) -> BoxFuture<Result<(), String>> {
+        let err_msg = format!(
+            "test syntheses are not supported by the {} backend",
+            self.name()
+        );
+        Box::pin(async move { Err(err_msg) })
+    }
+
+    /// The speech request sent to `/v1/audio/speech`, by default a JSON body.
+    fn speech_body(&self, _headers: &HeaderMap, body: &[u8]) -> Result<serde_json::Value, String> {
+        serde_json::from_slice(body).map_err(|e| e.to_string())
+    }
+}
+
+/// Register the backend serving the speech endpoints, which can only be done once.
+pub(crate) fn register(backend: &'static dyn TtsBackend) -> Result<(), String> {
+    REGISTERED
+        .set(backend)
+        .map_err(|_| format!("Failed to register the {} backend.", backend.name()))
+}
+
+/// The backend serving the speech endpoints, if one is registered.
+pub(crate) fn registered() -> Option<&'static dyn TtsBackend> {
+    REGISTERED.get().copied()
+}
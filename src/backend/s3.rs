@@ -0,0 +1,463 @@
+//! Storage of the `/v1/files` files in an S3-compatible bucket, for deployments of several
+//! replicas which cannot share a local directory.
+//!
+//! Objects are stored as `{prefix}{file_id}/{filename}` and requests are signed with AWS
+//! Signature Version 4 using the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and optional
+//! `AWS_SESSION_TOKEN` environment variables. Buckets are addressed path-style,
+//! `{endpoint}/{bucket}/{key}`, which every S3-compatible service supports.
+
+use hmac::{Hmac, Mac};
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime};
+
+/// Hash of an empty payload.
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+// the bucket given on the command line
+static BUCKET: OnceCell<Bucket> = OnceCell::new();
+
+pub(crate) struct Bucket {
+    /// URL of the service, without a trailing slash, e.g. `https://s3.eu-west-1.amazonaws.com`.
+    endpoint: String,
+    name: String,
+    region: String,
+    /// Prefix of the object keys, e.g. `tts/`.
+    prefix: String,
+    /// Lifetime of the presigned URLs.
+    url_expires: Duration,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    client: reqwest::Client,
+}
+
+/// An object of the bucket.
+pub(crate) struct Object {
+    pub file_id: String,
+    pub filename: String,
+    pub size: u64,
+    /// Unix time of the last modification, in seconds.
+    pub last_modified: u64,
+}
+
+/// Store the files in the bucket `name` of `endpoint` rather than on the local disk.
+pub(crate) fn init(
+    endpoint: &str,
+    name: String,
+    region: String,
+    prefix: String,
+    url_expires: Duration,
+) -> Result<(), String> {
+    let access_key_id = std::env::var("AWS_ACCESS_KEY_ID")
+        .map_err(|_| "`AWS_ACCESS_KEY_ID` must be set to store the files in S3".to_string())?;
+    let secret_access_key = std::env::var("AWS_SECRET_ACCESS_KEY")
+        .map_err(|_| "`AWS_SECRET_ACCESS_KEY` must be set to store the files in S3".to_string())?;
+    let session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+
+    let bucket = Bucket {
+        endpoint: endpoint.trim_end_matches('/').to_string(),
+        name,
+        region,
+        prefix,
+        url_expires,
+        access_key_id,
+        secret_access_key,
+        session_token,
+        client: reqwest::Client::new(),
+    };
+    BUCKET
+        .set(bucket)
+        .map_err(|_| "Failed to set `BUCKET`.".to_string())
+}
+
+/// The bucket the files are stored in, `None` if they are stored on the local disk.
+pub(crate) fn bucket() -> Option<&'static Bucket> {
+    BUCKET.get()
+}
+
+impl Bucket {
+    pub(crate) async fn put(
+        &self,
+        file_id: &str,
+        filename: &str,
+        data: Vec<u8>,
+    ) -> Result<(), String> {
+        let key = self.key(file_id, filename);
+        let payload_hash = hex::encode(Sha256::digest(&data));
+        let response = self
+            .send(reqwest::Method::PUT, &key, &[], &payload_hash, data)
+            .await?;
+        check(response).await.map(|_| ())
+    }
+
+    pub(crate) async fn get(&self, object: &Object) -> Result<Vec<u8>, String> {
+        let key = self.key(&object.file_id, &object.filename);
+        let response = self
+            .send(reqwest::Method::GET, &key, &[], EMPTY_SHA256, vec![])
+            .await?;
+        let response = check(response).await?;
+        response
+            .bytes()
+            .await
+            .map(|bytes| bytes.to_vec())
+            .map_err(|e| format!("Failed to read the object {}. {}", key, e))
+    }
+
+    pub(crate) async fn delete(&self, object: &Object) -> Result<(), String> {
+        let key = self.key(&object.file_id, &object.filename);
+        let response = self
+            .send(reqwest::Method::DELETE, &key, &[], EMPTY_SHA256, vec![])
+            .await?;
+        check(response).await.map(|_| ())
+    }
+
+    /// The object of `file_id`, or `None` if there is none.
+    pub(crate) async fn find(&self, file_id: &str) -> Result<Option<Object>, String> {
+        let objects = self.list(&format!("{}/", file_id)).await?;
+        Ok(objects.into_iter().next())
+    }
+
+    /// Every object whose key starts with the prefix and `prefix`.
+    pub(crate) async fn list(&self, prefix: &str) -> Result<Vec<Object>, String> {
+        let mut objects = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let mut query = vec![
+                ("list-type".to_string(), "2".to_string()),
+                ("prefix".to_string(), format!("{}{}", self.prefix, prefix)),
+            ];
+            if let Some(continuation_token) = continuation_token.take() {
+                query.push(("continuation-token".to_string(), continuation_token));
+            }
+
+            let response = self
+                .send(reqwest::Method::GET, "", &query, EMPTY_SHA256, vec![])
+                .await?;
+            let xml = check(response)
+                .await?
+                .text()
+                .await
+                .map_err(|e| format!("Failed to read the object list. {}", e))?;
+
+            for contents in elements(&xml, "Contents") {
+                let key = match element(contents, "Key") {
+                    Some(key) => unescape(key),
+                    None => continue,
+                };
+                let Some((file_id, filename)) = key
+                    .strip_prefix(&self.prefix)
+                    .and_then(|key| key.split_once('/'))
+                else {
+                    continue;
+                };
+                objects.push(Object {
+                    file_id: file_id.to_string(),
+                    filename: filename.to_string(),
+                    size: element(contents, "Size")
+                        .and_then(|size| size.parse().ok())
+                        .unwrap_or_default(),
+                    last_modified: element(contents, "LastModified")
+                        .and_then(parse_timestamp)
+                        .unwrap_or_default(),
+                });
+            }
+
+            match element(&xml, "IsTruncated") == Some("true") {
+                true => continuation_token = element(&xml, "NextContinuationToken").map(unescape),
+                false => break,
+            }
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// A URL the object can be downloaded from without credentials, until it expires.
+    pub(crate) fn presigned_url(&self, file_id: &str, filename: &str) -> String {
+        let key = self.key(file_id, filename);
+        let now = SystemTime::now();
+        let (date, time) = (date_stamp(now), amz_date(now));
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+
+        let mut query = vec![
+            (
+                "X-Amz-Algorithm".to_string(),
+                "AWS4-HMAC-SHA256".to_string(),
+            ),
+            (
+                "X-Amz-Credential".to_string(),
+                format!("{}/{}", self.access_key_id, scope),
+            ),
+            ("X-Amz-Date".to_string(), time.clone()),
+            (
+                "X-Amz-Expires".to_string(),
+                self.url_expires.as_secs().to_string(),
+            ),
+            ("X-Amz-SignedHeaders".to_string(), "host".to_string()),
+        ];
+        if let Some(session_token) = &self.session_token {
+            query.push(("X-Amz-Security-Token".to_string(), session_token.clone()));
+        }
+
+        let path = self.path(&key);
+        let query_string = canonical_query(&query);
+        let canonical_request = format!(
+            "GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            path,
+            query_string,
+            self.host()
+        );
+        let signature = self.signature(&date, &time, &canonical_request);
+
+        format!(
+            "{}{}?{}&X-Amz-Signature={}",
+            self.endpoint, path, query_string, signature
+        )
+    }
+
+    fn key(&self, file_id: &str, filename: &str) -> String {
+        format!("{}{}/{}", self.prefix, file_id, filename)
+    }
+
+    /// The canonical path of `key`, the bucket itself when empty.
+    fn path(&self, key: &str) -> String {
+        match key.is_empty() {
+            true => format!("/{}", uri_encode(&self.name, false)),
+            false => format!(
+                "/{}/{}",
+                uri_encode(&self.name, false),
+                uri_encode(key, true)
+            ),
+        }
+    }
+
+    fn host(&self) -> &str {
+        let host = self
+            .endpoint
+            .split_once("://")
+            .map_or(self.endpoint.as_str(), |(_, host)| host);
+        host.split('/').next().unwrap_or(host)
+    }
+
+    /// Send a request signed with the `Authorization` header.
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(String, String)],
+        payload_hash: &str,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, String> {
+        let now = SystemTime::now();
+        let (date, time) = (date_stamp(now), amz_date(now));
+
+        let mut headers = vec![
+            ("host".to_string(), self.host().to_string()),
+            ("x-amz-content-sha256".to_string(), payload_hash.to_string()),
+            ("x-amz-date".to_string(), time.clone()),
+        ];
+        if let Some(session_token) = &self.session_token {
+            headers.push(("x-amz-security-token".to_string(), session_token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let path = self.path(key);
+        let query_string = canonical_query(query);
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            path,
+            query_string,
+            headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed_headers,
+            payload_hash
+        );
+        let signature = self.signature(&date, &time, &canonical_request);
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}/{}/s3/aws4_request, SignedHeaders={}, Signature={}",
+            self.access_key_id, date, self.region, signed_headers, signature
+        );
+
+        let url = match query_string.is_empty() {
+            true => format!("{}{}", self.endpoint, path),
+            false => format!("{}{}?{}", self.endpoint, path, query_string),
+        };
+        let mut request = self
+            .client
+            .request(method, &url)
+            .header("authorization", authorization)
+            .body(body);
+        for (name, value) in headers.into_iter().filter(|(name, _)| name != "host") {
+            request = request.header(name, value);
+        }
+
+        request
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach the S3 endpoint {}. {}", self.endpoint, e))
+    }
+
+    /// The hex signature of `canonical_request`, made at `time` of `date`.
+    fn signature(&self, date: &str, time: &str, canonical_request: &str) -> String {
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}/{}/s3/aws4_request\n{}",
+            time,
+            date,
+            self.region,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = [date, self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.secret_access_key).into_bytes(),
+                |key, part| hmac(&key, part.as_bytes()),
+            );
+        hex::encode(hmac(&key, string_to_sign.as_bytes()))
+    }
+}
+
+/// The response, or its status and body as an error if it failed.
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, String> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    let message = element(&body, "Message").unwrap_or(&body);
+    Err(format!(
+        "The S3 request failed with {}. {}",
+        status, message
+    ))
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode every byte of `value` but the unreserved characters, and `/` if `keep_slash`.
+fn uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// The query string of `query`, encoded and sorted as signatures require.
+fn canonical_query(query: &[(String, String)]) -> String {
+    let mut pairs: Vec<(String, String)> = query
+        .iter()
+        .map(|(name, value)| (uri_encode(name, false), uri_encode(value, false)))
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The contents of the `<name>` elements of `xml`.
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        found.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    found
+}
+
+/// The contents of the first `<name>` element of `xml`.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    elements(xml, name).into_iter().next()
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// `YYYYMMDD` of `time`, in UTC.
+fn date_stamp(time: SystemTime) -> String {
+    amz_date(time)[..8].to_string()
+}
+
+/// `YYYYMMDDTHHMMSSZ` of `time`, in UTC.
+fn amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Unix time of an ISO 8601 UTC timestamp, e.g. `2026-10-15T08:30:00.000Z`.
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let number = |range: std::ops::Range<usize>| timestamp.get(range)?.parse::<i64>().ok();
+    let days = days_from_civil(number(0..4)?, number(5..7)?, number(8..10)?);
+    let secs = days * 86_400 + number(11..13)? * 3600 + number(14..16)? * 60 + number(17..19)?;
+    u64::try_from(secs).ok()
+}
+
+/// Year, month and day of the `days`th day since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Days since 1970-01-01 of `year`-`month`-`day`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
@@ -0,0 +1,116 @@
+//! Server-sent events mode of `/v1/audio/speech`, for clients that want progress updates.
+//!
+//! It is selected with an `Accept: text/event-stream` header, `"stream": true` or
+//! `"stream_format": "sse"` in the body.
+//! The input is split into sentences, which are synthesized one after the other. Every sentence
+//! produces an `audio` event with its base64 encoded audio, followed by a `progress` event with
+//! the share of the sentences done so far. The stream ends with a `done` event, or an `error`
+//! event if a sentence fails. The synthesis stops as soon as the client disconnects.
+
+use crate::{error, text};
+use base64::Engine;
+use hyper::{body::Bytes, header, Body, HeaderMap, Response};
+
+/// Whether the client asked for the audio as a stream of events.
+pub(crate) fn requested(headers: &HeaderMap, body: &serde_json::Value) -> bool {
+    let accepts_events = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("text/event-stream"))
+        .unwrap_or_default();
+
+    accepts_events
+        || body["stream"].as_bool().unwrap_or_default()
+        || body["stream_format"].as_str() == Some("sse")
+}
+
+/// Validate a speech request, then answer with an event stream fed as its sentences are
+/// synthesized.
+pub(crate) fn audio_speech_events(mut body: serde_json::Value) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Stream the audio speech as server-sent events");
+
+    // errors in the request itself are still answered with a plain error response
+    if let Err(e) = super::validate(body.clone()) {
+        return error::server_error(e);
+    }
+    // already checked by the validation
+    let _ = text::ssml::expand(&mut body);
+    let input: text::Input = match serde_json::from_value(body["input"].clone()) {
+        Ok(input) => input,
+        Err(e) => return error::bad_request(e.to_string()),
+    };
+    let sentences: Vec<String> = input
+        .segments()
+        .iter()
+        .flat_map(|segment| text::stream_sentences(segment))
+        .collect();
+    if let Some(body) = body.as_object_mut() {
+        body.remove("stream");
+        body.remove("stream_format");
+    }
+
+    let (mut sender, events) = Body::channel();
+    tokio::spawn(async move {
+        let total = sentences.len();
+        for (index, sentence) in sentences.into_iter().enumerate() {
+            let mut body = body.clone();
+            body["input"] = sentence.into();
+
+            let events = match super::synthesize(body).await {
+                Ok(audio) => {
+                    let audio_event = serde_json::json!({
+                        "index": index,
+                        "audio": base64::engine::general_purpose::STANDARD.encode(&audio.data),
+                        "format": audio.format.to_string(),
+                        "content_type": audio.content_type(),
+                        "sample_rate": audio.sample_rate,
+                        "words": audio.words,
+                    });
+                    let progress_event = serde_json::json!({
+                        "completed": index + 1,
+                        "total": total,
+                        "percent": (index + 1) * 100 / total,
+                    });
+                    event("audio", &audio_event) + &event("progress", &progress_event)
+                }
+                Err(e) => {
+                    let error_event = serde_json::json!({ "message": e.to_string() });
+                    let _ = sender.send_data(event("error", &error_event).into()).await;
+                    return;
+                }
+            };
+
+            if sender.send_data(Bytes::from(events)).await.is_err() {
+                info!(target: "stdout", "Event stream client disconnected, abort the synthesis");
+                return;
+            }
+        }
+
+        let _ = sender
+            .send_data(event("done", &serde_json::json!({})).into())
+            .await;
+    });
+
+    let result = Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(events);
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// Format a server-sent event.
+fn event(name: &str, data: &serde_json::Value) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
@@ -0,0 +1,75 @@
+//! `POST /v1/audio/speech/validate`: check a speech request without synthesizing it.
+
+use crate::error;
+use hyper::{body::to_bytes, http::Method, Body, Request, Response};
+
+/// Run the parsing and validation of `/v1/audio/speech` on the request body.
+///
+/// Answers `{"valid": true}`, or the 400 error the speech endpoint would have answered with.
+pub(crate) async fn audio_speech_validate_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming audio speech validate request");
+
+    if req.method().eq(&Method::OPTIONS) {
+        let result = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
+
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        }
+    }
+
+    // parse request
+    let body_bytes = match to_bytes(req.into_body()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+    let body: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+        Ok(body) => body,
+        Err(e) => {
+            let err_msg = format!("Fail to deserialize speech request: {msg}", msg = e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    if let Err(e) = super::validate(body) {
+        return error::server_error(e);
+    }
+
+    // return response
+    let result = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::json!({ "valid": true }).to_string()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Default request parameters per voice, read from `--voices-config`, and the voice names
+//! translated to local voices, read from `--voice-map`.
+//!
+//! The voices config is a JSON object mapping voice names to the parameters used when a request
+//! for that voice leaves them out, e.g. `{"amy": {"speed": 0.9, "noise_scale": 0.5}}`. Parameters
+//! set in the request win over the voice defaults, which win over the defaults of the backend.
+//!
+//! The voice map is a TOML table mapping the voice names clients send, typically the OpenAI ones,
+//! to the voices of the backend, e.g. `alloy = "amy"`. The voice of a request is translated before
+//! the defaults of the voices config are applied, so these are the ones of the local voice.
+//!
+//! `GET /v1/audio/voices` lists the voices clients can pick from.
+
+use crate::error;
+use hyper::{Body, Request, Response};
+use once_cell::sync::OnceCell;
+use std::{collections::HashMap, path::Path};
+
+type Parameters = serde_json::Map<String, serde_json::Value>;
+
+// default parameters by voice name
+static VOICES: OnceCell<HashMap<String, Parameters>> = OnceCell::new();
+
+// local voices by the name clients send
+static VOICE_MAP: OnceCell<HashMap<String, String>> = OnceCell::new();
+
+/// Fields that describe the request rather than the voice, so they cannot have defaults.
+const RESERVED_FIELDS: [&str; 4] = ["input", "model", "voice", "speaker"];
+
+/// Fields that select the voice of a request, without which `--default-voice` is used.
+const VOICE_FIELDS: [&str; 3] = ["voice", "speaker", "reference_audio"];
+
+/// Load the voice defaults of `path`, returning the number of voices.
+pub(crate) fn init(path: &Path) -> Result<usize, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        format!(
+            "Failed to read the voices config {}. {}",
+            path.to_string_lossy(),
+            e
+        )
+    })?;
+    let voices: HashMap<String, Parameters> = serde_json::from_str(&content).map_err(|e| {
+        format!(
+            "Invalid voices config, expected an object of objects. {}",
+            e
+        )
+    })?;
+
+    for (voice, parameters) in &voices {
+        if let Some(field) = RESERVED_FIELDS
+            .iter()
+            .find(|field| parameters.contains_key(**field))
+        {
+            return Err(format!(
+                "Invalid voices config, `{}` cannot have a default `{}`.",
+                voice, field
+            ));
+        }
+    }
+
+    let count = voices.len();
+    VOICES
+        .set(voices)
+        .map_err(|_| "Failed to set `VOICES`.".to_string())?;
+
+    Ok(count)
+}
+
+/// Load the voice map of `path`, returning the number of voices mapped.
+pub(crate) fn init_map(path: &Path) -> Result<usize, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        format!(
+            "Failed to read the voice map {}. {}",
+            path.to_string_lossy(),
+            e
+        )
+    })?;
+    let map: HashMap<String, String> = toml::from_str(&content).map_err(|e| {
+        format!(
+            "Invalid voice map, expected a table of voice names, e.g. `alloy = \"amy\"`. {}",
+            e
+        )
+    })?;
+
+    if let Some((name, _)) = map.iter().find(|(_, voice)| voice.is_empty()) {
+        return Err(format!("Invalid voice map, `{}` maps to no voice.", name));
+    }
+
+    let count = map.len();
+    VOICE_MAP
+        .set(map)
+        .map_err(|_| "Failed to set `VOICE_MAP`.".to_string())?;
+
+    Ok(count)
+}
+
+/// Fill in the defaults of a speech request body: the voice of `--default-voice` if it sets
+/// neither a voice nor a reference audio, translated through the voice map, then the parameters
+/// it leaves out from the defaults of its voice, and the speed of `--default-speed` if neither
+/// sets one.
+pub(crate) fn apply_defaults(body: &mut serde_json::Value) {
+    let body = match body.as_object_mut() {
+        Some(body) => body,
+        None => return,
+    };
+    let is_set =
+        |body: &Parameters, name: &str| body.get(name).filter(|value| !value.is_null()).is_some();
+
+    // the reference audio of a cloning request is its voice
+    if !VOICE_FIELDS.iter().any(|field| is_set(body, field)) {
+        if let Some(voice) = crate::DEFAULT_VOICE.get() {
+            body.insert("voice".to_string(), voice.clone().into());
+        }
+    }
+
+    map_voice(body);
+
+    let voice = ["voice", "speaker"]
+        .into_iter()
+        .find_map(|field| body.get(field).and_then(|voice| voice.as_str()));
+    if let Some(defaults) = voice.and_then(|voice| VOICES.get()?.get(voice)) {
+        for (name, value) in defaults {
+            if !is_set(body, name) {
+                body.insert(name.clone(), value.clone());
+            }
+        }
+    }
+
+    if !is_set(body, "speed") {
+        if let Some(&speed) = crate::DEFAULT_SPEED.get() {
+            body.insert("speed".to_string(), speed.into());
+        }
+    }
+}
+
+/// Replace the voice of a speech request body, or its speaker, with the local voice it maps to.
+fn map_voice(body: &mut Parameters) {
+    let map = match VOICE_MAP.get() {
+        Some(map) => map,
+        None => return,
+    };
+    for field in ["voice", "speaker"] {
+        let voice = match body.get(field).and_then(|voice| voice.as_str()) {
+            Some(voice) => voice,
+            None => continue,
+        };
+        if let Some(local) = map.get(voice) {
+            // log
+            debug!(target: "stdout", "Map the voice {} to {}", voice, local);
+
+            body.insert(field.to_string(), local.clone().into());
+        }
+        return;
+    }
+}
+
+pub(crate) async fn voices_handler(_req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming voices request");
+
+    let body = serde_json::json!({ "object": "list", "data": list() });
+
+    let result = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// The voices of the selected backend, or the ones of `--voices-config` without a backend.
+pub(crate) fn list() -> Vec<serde_json::Value> {
+    match super::registry::registered() {
+        Some(backend) => backend.voices(),
+        None => configured(),
+    }
+}
+
+/// The voices of `--voices-config`, without details, for the backends whose voices are only
+/// known to their plugin.
+pub(crate) fn configured() -> Vec<serde_json::Value> {
+    let mut names: Vec<&String> = VOICES
+        .get()
+        .map(|voices| voices.keys().collect())
+        .unwrap_or_default();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            serde_json::json!({
+                "id": name,
+                "speaker_id": null,
+                "language": null,
+                "gender": null,
+                "sample_rate": null,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_defaults(mut body: serde_json::Value) -> serde_json::Value {
+        let _ = crate::DEFAULT_VOICE.set("amy".to_string());
+        apply_defaults(&mut body);
+        body
+    }
+
+    #[test]
+    fn apply_defaults_fills_in_the_default_voice() {
+        let body = with_defaults(serde_json::json!({ "input": "Hello" }));
+        assert_eq!(body["voice"], "amy");
+    }
+
+    #[test]
+    fn apply_defaults_keeps_the_voice_of_the_request() {
+        let body = with_defaults(serde_json::json!({ "input": "Hello", "speaker": "joe" }));
+        assert_eq!(body.get("voice"), None);
+        assert_eq!(body["speaker"], "joe");
+    }
+
+    #[test]
+    fn apply_defaults_keeps_the_reference_audio_of_the_request() {
+        let body =
+            with_defaults(serde_json::json!({ "input": "Hello", "reference_audio": "file-abc" }));
+        assert_eq!(body.get("voice"), None);
+    }
+}
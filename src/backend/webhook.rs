@@ -0,0 +1,132 @@
+//! Delivery of the outcome of the `/v1/audio/speech/jobs` jobs to the `callback_url` of their
+//! request.
+//!
+//! A callback is a `POST` of the job object, or of the audio, retried with exponential backoff
+//! while the endpoint cannot be reached or answers `408`, `429` or a `5xx` status. Requires the
+//! `webhooks` feature.
+
+/// Reported when a job asks for a callback to a build without the `webhooks` feature.
+pub(crate) const UNSUPPORTED: &str =
+    "callbacks are not supported by this build, rebuild with `--features webhooks` or leave out `callback_url`";
+
+#[cfg(feature = "webhooks")]
+mod client {
+    use once_cell::sync::Lazy;
+    use std::time::Duration;
+
+    /// Number of attempts before a callback is given up.
+    const MAX_ATTEMPTS: u32 = 5;
+
+    /// Delay before the first retry, doubled for every following one.
+    const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+    /// Longest delay between two attempts, also the cap of the `Retry-After` of the endpoint.
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// Time allowed to an attempt, from connecting to the end of the response.
+    const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+    /// `POST` `body` to `url` until it is accepted.
+    ///
+    /// Returns the number of attempts made, along with the error of the last one if the callback
+    /// was given up.
+    pub(crate) async fn deliver(
+        url: &str,
+        job_id: &str,
+        status: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> (u32, Option<String>) {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+
+            let request = CLIENT
+                .post(url)
+                .header("content-type", content_type)
+                .header(
+                    "user-agent",
+                    concat!("tts-api-server/", env!("CARGO_PKG_VERSION")),
+                )
+                .header("x-job-id", job_id)
+                .header("x-job-status", status)
+                .header("x-webhook-attempt", attempt.to_string())
+                .body(body.clone())
+                .send();
+            let (err_msg, retry_after) = match tokio::time::timeout(ATTEMPT_TIMEOUT, request).await
+            {
+                Ok(Ok(response)) if response.status().is_success() => return (attempt, None),
+                Ok(Ok(response)) => {
+                    let status = response.status();
+                    let err_msg = format!("The callback endpoint answered {}.", status);
+                    if !(status.is_server_error()
+                        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+                        || status == reqwest::StatusCode::TOO_MANY_REQUESTS)
+                    {
+                        return (attempt, Some(err_msg));
+                    }
+                    let retry_after = response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse().ok())
+                        .map(Duration::from_secs);
+                    (err_msg, retry_after)
+                }
+                Ok(Err(e)) => (
+                    format!("Failed to reach the callback endpoint. {}", e),
+                    None,
+                ),
+                Err(_) => (
+                    format!(
+                        "The callback endpoint did not answer within {} s.",
+                        ATTEMPT_TIMEOUT.as_secs()
+                    ),
+                    None,
+                ),
+            };
+
+            if attempt >= MAX_ATTEMPTS {
+                return (attempt, Some(err_msg));
+            }
+
+            let delay = retry_after.unwrap_or(backoff).max(backoff).min(MAX_BACKOFF);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+
+            // log
+            warn!(target: "stdout", "Callback of the job {}, attempt {} of {}: {} Retry in {} s", job_id, attempt, MAX_ATTEMPTS, err_msg, delay.as_secs());
+
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(feature = "webhooks")]
+pub(crate) use client::deliver;
+
+#[cfg(not(feature = "webhooks"))]
+pub(crate) async fn deliver(
+    _url: &str,
+    _job_id: &str,
+    _status: &str,
+    _content_type: &str,
+    _body: Vec<u8>,
+) -> (u32, Option<String>) {
+    (0, Some(UNSUPPORTED.to_string()))
+}
+
+/// Check that `url` is an `http` or `https` URL a callback can be posted to.
+pub(crate) fn check_url(url: &str) -> Result<(), String> {
+    let host = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .map(|rest| rest.split(['/', '?', '#']).next().unwrap_or_default())
+        .ok_or_else(|| format!("`callback_url` must be an http or https URL, got `{}`", url))?;
+    match host.is_empty() || url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        true => Err(format!("`callback_url` is not a valid URL, got `{}`", url)),
+        false => Ok(()),
+    }
+}
@@ -0,0 +1,341 @@
+//! Incremental synthesis over a WebSocket.
+//!
+//! On `/v1/audio/speech/stream`, every text message is either a JSON speech request, shaped like
+//! a `/v1/audio/speech` body, or plain text which is synthesized with the settings of the last
+//! JSON request. The input is split into sentences and each one is sent back as a binary message
+//! as soon as it is ready, followed by a `{"type": "done"}` text message. When `timestamps` is
+//! requested, every binary message is preceded by a `{"type": "words", "words": [...]}` text
+//! message with the word timings of that sentence. Failures are reported as
+//! `{"type": "error", "message": ...}` and the connection stays open.
+//!
+//! On `/v1/audio/speech/ws`, plain text messages are fragments of a text which arrives piece by
+//! piece, such as the tokens of an LLM. The fragments are appended to each other and every
+//! sentence is synthesized as soon as the next one starts. A JSON object sets the speech
+//! settings of the following sentences, its `input` being appended as a fragment, and
+//! `{"type": "flush"}` synthesizes the text left over, then answers `{"type": "done"}`.
+
+use crate::{error, text};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use hyper::{header, upgrade::Upgraded, Body, Request, Response, StatusCode};
+use std::{collections::VecDeque, future::Future};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+pub(crate) async fn audio_speech_stream_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming audio speech stream request");
+
+    upgrade(req, serve).await
+}
+
+pub(crate) async fn audio_speech_ws_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming incremental audio speech request");
+
+    upgrade(req, serve_incremental).await
+}
+
+/// Switch the connection of `req` to the WebSocket protocol, and hand the socket to `serve`.
+async fn upgrade<F, Fut>(req: Request<Body>, serve: F) -> Response<Body>
+where
+    F: FnOnce(WebSocketStream<Upgraded>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let is_upgrade = req
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or_default();
+    let accept_key = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) if is_upgrade => derive_accept_key(key.as_bytes()),
+        _ => return error::bad_request("Expected a WebSocket upgrade request."),
+    };
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                serve(ws).await;
+            }
+            Err(e) => {
+                error!(target: "stdout", "Failed to upgrade the connection to a WebSocket. {}", e)
+            }
+        }
+    });
+
+    let result = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept_key)
+        .body(Body::empty());
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+async fn serve(ws: WebSocketStream<Upgraded>) {
+    let (mut sink, mut stream) = ws.split();
+    // messages received while a previous one was being synthesized
+    let mut pending = VecDeque::new();
+    // settings applied to plain text messages
+    let mut template = serde_json::json!({});
+
+    loop {
+        let message = match pending.pop_front() {
+            Some(message) => message,
+            None => match stream.next().await {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => {
+                    error!(target: "stdout", "WebSocket error: {}", e);
+                    return;
+                }
+                None => return,
+            },
+        };
+
+        let content = match message {
+            Message::Text(content) => content,
+            Message::Close(_) => return,
+            Message::Binary(_) => {
+                let control = control_message("error", "Binary messages are not supported.");
+                if sink.send(control).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+
+        let mut request = match content.trim_start().starts_with('{') {
+            true => match serde_json::from_str::<serde_json::Value>(&content) {
+                Ok(request) => {
+                    template = request.clone();
+                    request
+                }
+                Err(e) => {
+                    let err_msg = format!("Fail to deserialize speech request: {}", e);
+                    if sink.send(control_message("error", &err_msg)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            },
+            false => {
+                let mut request = template.clone();
+                request["input"] = content.into();
+                request
+            }
+        };
+        if let Err(e) = text::ssml::expand(&mut request) {
+            let err_msg = format!("Invalid input: {}", e);
+            if sink.send(control_message("error", &err_msg)).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        let input: text::Input = match serde_json::from_value(request["input"].clone()) {
+            Ok(input) => input,
+            Err(_) => {
+                let err_msg = "`input` must be a string or a list of strings";
+                if sink.send(control_message("error", err_msg)).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        if let Err(e) = input.validate(crate::MAX_INPUT_CHARS.get().copied()) {
+            let err_msg = format!("Invalid input: {}", e);
+            if sink.send(control_message("error", &err_msg)).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        let sentences = input
+            .segments()
+            .iter()
+            .flat_map(|segment| text::stream_sentences(segment));
+
+        for sentence in sentences {
+            let mut body = request.clone();
+            body["input"] = sentence.into();
+            if !send_audio(&mut sink, &mut stream, &mut pending, body).await {
+                return;
+            }
+        }
+
+        if sink.send(control_message("done", "")).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn serve_incremental(ws: WebSocketStream<Upgraded>) {
+    let (mut sink, mut stream) = ws.split();
+    // messages received while a sentence was being synthesized
+    let mut pending = VecDeque::new();
+    // settings applied to the sentences
+    let mut settings = serde_json::json!({});
+    // text received and not synthesized yet
+    let mut buffer = String::new();
+
+    loop {
+        let message = match pending.pop_front() {
+            Some(message) => message,
+            None => match stream.next().await {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => {
+                    error!(target: "stdout", "WebSocket error: {}", e);
+                    return;
+                }
+                None => return,
+            },
+        };
+
+        let content = match message {
+            Message::Text(content) => content,
+            Message::Close(_) => return,
+            Message::Binary(_) => {
+                let control = control_message("error", "Binary messages are not supported.");
+                if sink.send(control).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+
+        let mut flush = false;
+        match content.trim_start().starts_with('{') {
+            true => match serde_json::from_str::<serde_json::Value>(&content) {
+                Ok(control) if control["type"] == "flush" => flush = true,
+                Ok(mut request) if request.is_object() => {
+                    if let Some(fragment) = request["input"].take().as_str() {
+                        buffer.push_str(fragment);
+                    }
+                    if let Some(request) = request.as_object_mut() {
+                        request.remove("input");
+                    }
+                    settings = request;
+                }
+                Ok(_) => {
+                    let err_msg = "Fail to deserialize speech request: expected an object";
+                    if sink.send(control_message("error", err_msg)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    let err_msg = format!("Fail to deserialize speech request: {}", e);
+                    if sink.send(control_message("error", &err_msg)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            },
+            false => buffer.push_str(&content),
+        }
+
+        if let Some(max_chars) = crate::MAX_INPUT_CHARS.get().copied() {
+            let chars = buffer.chars().count();
+            if chars > max_chars {
+                buffer.clear();
+                let err_msg = format!(
+                    "Invalid input: the text waiting for the end of its sentence is limited to {} characters, got {}",
+                    max_chars, chars
+                );
+                if sink.send(control_message("error", &err_msg)).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        }
+
+        // the last sentence may go on in the next fragment, unless a line break ends it
+        let mut sentences = text::stream_sentences(&buffer);
+        let rest = match flush || buffer.ends_with('\n') {
+            true => None,
+            false => sentences.pop(),
+        };
+        // kept as received, with the whitespace the next fragment may rely on
+        buffer = match rest.and_then(|rest| buffer.rfind(&rest)) {
+            Some(start) => buffer[start..].to_string(),
+            None => String::new(),
+        };
+
+        for sentence in sentences {
+            let mut body = settings.clone();
+            body["input"] = sentence.into();
+            if !send_audio(&mut sink, &mut stream, &mut pending, body).await {
+                return;
+            }
+        }
+
+        if flush && sink.send(control_message("done", "")).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Synthesize `body` and send its audio, keeping the messages received meanwhile in `pending`.
+///
+/// Returns `false` when the connection is gone.
+async fn send_audio(
+    sink: &mut SplitSink<WebSocketStream<Upgraded>, Message>,
+    stream: &mut SplitStream<WebSocketStream<Upgraded>>,
+    pending: &mut VecDeque<Message>,
+    body: serde_json::Value,
+) -> bool {
+    let synthesis = super::synthesize(body);
+    tokio::pin!(synthesis);
+    let result = loop {
+        tokio::select! {
+            result = &mut synthesis => break Some(result),
+            incoming = stream.next() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break None,
+                Some(Ok(message)) => pending.push_back(message),
+            },
+        }
+    };
+
+    let sent = match result {
+        Some(Ok(audio)) => {
+            if let Some(words) = audio.words {
+                let words = serde_json::json!({ "type": "words", "words": words });
+                if sink.send(Message::Text(words.to_string())).await.is_err() {
+                    return false;
+                }
+            }
+            sink.send(Message::Binary(audio.data)).await
+        }
+        Some(Err(e)) => sink.send(control_message("error", &e.to_string())).await,
+        None => {
+            info!(target: "stdout", "WebSocket client disconnected, abort the synthesis");
+            return false;
+        }
+    };
+    sent.is_ok()
+}
+
+fn control_message(kind: &str, message: &str) -> Message {
+    let value = match message.is_empty() {
+        true => serde_json::json!({ "type": kind }),
+        false => serde_json::json!({ "type": kind, "message": message }),
+    };
+    Message::Text(value.to_string())
+}
@@ -0,0 +1,142 @@
+//! [`ServerBuilder`]: the server embedded in another application, set up in code instead of on
+//! the command line.
+
+use crate::{backend::Backend, error::ServerError, Cli};
+use clap::Parser;
+use std::{
+    ffi::OsString,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+/// Builder of a server embedded in another application.
+///
+/// The setters map onto the options of the command line, and [`ServerBuilder::arg`] sets any
+/// other one, so the server behaves as the binary started with the same options. The config
+/// file of `--config-file` and the environment variables are not read, but for `TTS_LOG`.
+///
+/// The state of the server is global, so a process serves at most one server.
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    /// The command line the options are parsed from.
+    args: Vec<OsString>,
+    api_key: Option<String>,
+}
+
+impl ServerBuilder {
+    /// A server of the model `model_name`, loaded from `model` and its `config`, with the
+    /// espeak-ng data of `espeak_ng_dir`.
+    pub fn new(
+        model_name: impl Into<String>,
+        model: impl Into<PathBuf>,
+        config: impl Into<PathBuf>,
+        espeak_ng_dir: impl Into<PathBuf>,
+    ) -> Self {
+        ServerBuilder {
+            args: vec![env!("CARGO_PKG_NAME").into()],
+            api_key: None,
+        }
+        .arg("model-name", model_name.into())
+        .arg("model", model.into())
+        .arg("config", config.into())
+        .arg("espeak-ng-dir", espeak_ng_dir.into())
+    }
+
+    /// The synthesis backend, the first one compiled in if not set.
+    pub fn backend(self, backend: Backend) -> Self {
+        self.arg("backend", backend.to_string())
+    }
+
+    /// The default request parameters per voice.
+    pub fn voices_config(self, path: impl AsRef<Path>) -> Self {
+        self.arg("voices-config", path.as_ref())
+    }
+
+    /// The voice names clients send, mapped to the voices of the backend.
+    pub fn voice_map(self, path: impl AsRef<Path>) -> Self {
+        self.arg("voice-map", path.as_ref())
+    }
+
+    /// The API key the requests must carry, as the `TTS_API_KEY` environment variable.
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// A file of accepted API keys, one per line.
+    pub fn api_key_file(self, path: impl AsRef<Path>) -> Self {
+        self.arg("api-key-file", path.as_ref())
+    }
+
+    /// The key authorizing the `/v1/admin` endpoints.
+    pub fn admin_key(self, admin_key: impl Into<String>) -> Self {
+        self.arg("admin-key", admin_key.into())
+    }
+
+    /// Accept every request without checking its API key.
+    pub fn no_auth(self) -> Self {
+        self.flag("no-auth")
+    }
+
+    /// The maximum number of characters in the input of a speech request.
+    pub fn max_input_chars(self, max_input_chars: usize) -> Self {
+        self.arg("max-input-chars", max_input_chars.to_string())
+    }
+
+    /// The maximum number of bytes of a request body.
+    pub fn max_request_bytes(self, max_request_bytes: u64) -> Self {
+        self.arg("max-request-bytes", max_request_bytes.to_string())
+    }
+
+    /// The maximum number of requests waiting for the synthesizer.
+    pub fn max_queue_size(self, max_queue_size: usize) -> Self {
+        self.arg("max-queue-size", max_queue_size.to_string())
+    }
+
+    /// The maximum number of open connections.
+    pub fn max_connections(self, max_connections: usize) -> Self {
+        self.arg("max-connections", max_connections.to_string())
+    }
+
+    /// The maximum number of speech requests per minute of a client.
+    pub fn rate_limit_rpm(self, rate_limit_rpm: u32) -> Self {
+        self.arg("rate-limit-rpm", rate_limit_rpm.to_string())
+    }
+
+    /// The maximum number of input characters synthesized per day for a client.
+    pub fn rate_limit_chars_per_day(self, rate_limit_chars_per_day: u64) -> Self {
+        self.arg(
+            "rate-limit-chars-per-day",
+            rate_limit_chars_per_day.to_string(),
+        )
+    }
+
+    /// Set an option of the command line by its long name, e.g. `("cache-size", "100")`.
+    pub fn arg(mut self, name: &str, value: impl Into<OsString>) -> Self {
+        self.args.push(format!("--{}", name).into());
+        self.args.push(value.into());
+        self
+    }
+
+    /// Set a flag of the command line by its long name, e.g. `"enable-echo"`.
+    pub fn flag(mut self, name: &str) -> Self {
+        self.args.push(format!("--{}", name).into());
+        self
+    }
+
+    /// Load the model and serve on `addr` until [`shutdown`](crate::shutdown) is called or the
+    /// process is terminated.
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), ServerError> {
+        let ServerBuilder { args, api_key } = self.arg("socket-addr", addr.to_string());
+        let cli = Cli::try_parse_from(args).map_err(|e| ServerError::Operation(e.to_string()))?;
+
+        let log_level = crate::log_level();
+
+        // the application may have installed its own logger, which then gets the records
+        if crate::logs::install().is_ok() {
+            log::set_max_level(log_level.into());
+        }
+
+        crate::start(cli, log_level, api_key).await
+    }
+}
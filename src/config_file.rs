@@ -0,0 +1,200 @@
+//! Options read from the `--config-file` TOML or YAML file, and from `TTS_*` environment
+//! variables.
+//!
+//! Every command line option can be set in the file under its name, in snake or kebab case,
+//! either at the top level or in a table grouping it with others, e.g. `max_input_chars` in a
+//! `[limits]` table. The file can also set the `api_key` otherwise given by the `TTS_API_KEY`
+//! environment variable. The options given on the command line win over the
+//! `TTS_<OPTION>` environment variables, e.g. `TTS_MAX_INPUT_CHARS`, which win over the file.
+
+use clap::Command;
+use std::path::{Path, PathBuf};
+
+/// Prefix of the environment variables setting the options.
+const ENV_PREFIX: &str = "TTS_";
+
+/// Arguments that cannot be set from the file or the environment.
+const NOT_CONFIGURABLE: [&str; 3] = ["config_file", "help", "version"];
+
+/// Settings read from a config file.
+#[derive(Debug, Default)]
+pub(crate) struct ConfigFile {
+    /// Values of the command line options, by argument id.
+    options: Vec<(String, Vec<String>)>,
+    /// Key accepted in addition to the ones of `--api-key-file`, when `TTS_API_KEY` is not set.
+    pub api_key: Option<String>,
+}
+
+/// The `--config-file` given on the command line, or the `TTS_CONFIG_FILE` environment variable.
+///
+/// It is read before the command line is parsed, as the file provides the defaults of the parse.
+pub(crate) fn path_from_args() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--config-file" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config-file=") {
+            return Some(PathBuf::from(path));
+        }
+        if arg == "--" {
+            break;
+        }
+    }
+
+    std::env::var_os(format!("{}CONFIG_FILE", ENV_PREFIX)).map(PathBuf::from)
+}
+
+/// Read the config file at `path`, TOML or YAML according to its extension.
+pub(crate) fn load(path: &Path) -> Result<ConfigFile, String> {
+    let display = path.to_string_lossy();
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read the config file {}. {}", display, e))?;
+
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let value: serde_json::Value = match extension.as_deref() {
+        Some("toml") => toml::from_str(&content)
+            .map_err(|e| format!("Invalid config file {}. {}", display, e))?,
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&content)
+            .map_err(|e| format!("Invalid config file {}. {}", display, e))?,
+        _ => {
+            return Err(format!(
+                "Unsupported config file {}: expected a `.toml`, `.yaml` or `.yml` file",
+                display
+            ))
+        }
+    };
+
+    let mut config = ConfigFile::default();
+    match value {
+        serde_json::Value::Object(table) => flatten(table, "", &mut config)
+            .map_err(|e| format!("Invalid config file {}. {}", display, e))?,
+        // an empty YAML document
+        serde_json::Value::Null => {}
+        _ => {
+            return Err(format!(
+                "Invalid config file {}. Expected a table of options",
+                display
+            ))
+        }
+    }
+
+    Ok(config)
+}
+
+/// Collect the options of `table`, and of the tables nested in it, `path` naming the table in
+/// the error messages.
+fn flatten(
+    table: serde_json::Map<String, serde_json::Value>,
+    path: &str,
+    config: &mut ConfigFile,
+) -> Result<(), String> {
+    for (key, value) in table {
+        let name = format!("{}{}", path, key);
+        let id = key.replace('-', "_");
+
+        let values = match value {
+            serde_json::Value::Object(table) => {
+                flatten(table, &format!("{}.", name), config)?;
+                continue;
+            }
+            serde_json::Value::Array(items) => items
+                .into_iter()
+                .map(|item| {
+                    scalar(item).ok_or_else(|| format!("`{}` must be a list of values", name))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            value => match scalar(value) {
+                Some(value) => vec![value],
+                None => return Err(format!("`{}` has no value", name)),
+            },
+        };
+
+        if id == "api_key" {
+            match values.as_slice() {
+                [api_key] if !api_key.is_empty() => config.api_key = Some(api_key.clone()),
+                _ => return Err(format!("`{}` must be a single non-empty key", name)),
+            }
+            continue;
+        }
+
+        if config.options.iter().any(|(other, _)| *other == id) {
+            return Err(format!("`{}` is set more than once", name));
+        }
+        config.options.push((id, values));
+    }
+
+    Ok(())
+}
+
+/// The command line form of a string, number or boolean.
+fn scalar(value: serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(value) => Some(value),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        serde_json::Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Set the defaults of the options of `command` from `config`, then from the environment.
+///
+/// The options given a value this way are no longer required on the command line.
+pub(crate) fn apply(mut command: Command, config: Option<&ConfigFile>) -> Result<Command, String> {
+    let ids: Vec<String> = command
+        .get_arguments()
+        .map(|arg| arg.get_id().to_string())
+        .filter(|id| !NOT_CONFIGURABLE.contains(&id.as_str()))
+        .collect();
+
+    let mut defaults: Vec<(String, Vec<String>)> = Vec::new();
+    for (id, values) in config
+        .map(|config| config.options.as_slice())
+        .unwrap_or_default()
+    {
+        if !ids.contains(id) {
+            return Err(format!(
+                "Unknown option `{}` in the config file, see `--help` for the options",
+                id
+            ));
+        }
+        defaults.push((id.clone(), split(&command, id, values.clone())));
+    }
+
+    for id in &ids {
+        let name = format!("{}{}", ENV_PREFIX, id.to_uppercase());
+        let value = match std::env::var(&name) {
+            Ok(value) => value,
+            Err(std::env::VarError::NotPresent) => continue,
+            Err(e) => return Err(format!("Invalid environment variable `{}`. {}", name, e)),
+        };
+
+        defaults.retain(|(other, _)| other != id);
+        defaults.push((id.clone(), split(&command, id, vec![value])));
+    }
+
+    for (id, values) in defaults {
+        command = command.mut_arg(id, |arg| arg.default_values(values).required(false));
+    }
+
+    Ok(command)
+}
+
+/// Split `values` at the value delimiter of the `id` option, as the command line values are.
+fn split(command: &Command, id: &str, values: Vec<String>) -> Vec<String> {
+    let delimiter = command
+        .get_arguments()
+        .find(|arg| arg.get_id().as_str() == id)
+        .and_then(|arg| arg.get_value_delimiter());
+    match delimiter {
+        Some(delimiter) => values
+            .iter()
+            .flat_map(|value| value.split(delimiter))
+            .map(str::to_string)
+            .collect(),
+        None => values,
+    }
+}
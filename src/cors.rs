@@ -0,0 +1,93 @@
+//! CORS for browser clients: preflight requests are answered before authorization, and the
+//! `Access-Control-Allow-Origin` header of every response is set for the origins of
+//! `--cors-allow-origin`.
+
+use hyper::{
+    header::{self, HeaderValue},
+    http::Method,
+    Body, Request, Response, StatusCode,
+};
+
+/// Methods the endpoints answer to.
+const ALLOW_METHODS: &str = "GET, POST, DELETE, OPTIONS";
+
+/// Response headers scripts may read besides the CORS-safelisted ones.
+const EXPOSE_HEADERS: &str =
+    "Cache-Status, Content-Disposition, Retry-After, X-Audio-Sample-Rate, X-Request-Id, \
+    X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset, \
+    X-RateLimit-Limit-Chars, X-RateLimit-Remaining-Chars, X-RateLimit-Reset-Chars";
+
+/// Time in seconds browsers may cache a preflight response.
+const MAX_AGE_SECS: &str = "86400";
+
+/// The answer to a preflight request, or `None` if `req` is not one.
+pub(crate) fn preflight(req: &Request<Body>) -> Option<Response<Body>> {
+    if req.method() != Method::OPTIONS
+        || !req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return None;
+    }
+
+    // a wildcard does not cover `Authorization`, so the requested headers are allowed by name
+    let allow_headers = req
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_static("*"));
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    let headers = response.headers_mut();
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static(ALLOW_METHODS),
+    );
+    headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+    headers.insert(
+        header::ACCESS_CONTROL_MAX_AGE,
+        HeaderValue::from_static(MAX_AGE_SECS),
+    );
+
+    Some(response)
+}
+
+/// Set the `Access-Control-Allow-Origin` header of `response` to the allowed `origin` of the
+/// request, or remove it if that origin is not allowed.
+pub(crate) fn apply(origin: Option<&HeaderValue>, response: &mut Response<Body>) {
+    let allowed = crate::CORS_ALLOW_ORIGINS.get();
+    let any_origin = match allowed {
+        Some(allowed) => allowed.iter().any(|allowed| allowed == "*"),
+        None => true,
+    };
+    let allow_origin = match allowed {
+        Some(allowed) if !any_origin => origin
+            .filter(|origin| {
+                allowed
+                    .iter()
+                    .any(|allowed| allowed.as_bytes() == origin.as_bytes())
+            })
+            .cloned(),
+        _ => Some(HeaderValue::from_static("*")),
+    };
+
+    let headers = response.headers_mut();
+    match allow_origin {
+        Some(allow_origin) => {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(EXPOSE_HEADERS),
+            );
+        }
+        None => {
+            headers.remove(header::ACCESS_CONTROL_ALLOW_ORIGIN);
+        }
+    }
+
+    // the answer depends on the origin of the request, caches must tell them apart
+    if !any_origin {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
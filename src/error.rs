@@ -1,97 +1,177 @@
-use hyper::{Body, Response};
-use thiserror::Error;
+//! Error responses, with a body in the error format of the OpenAI API, which its SDKs parse:
+//!
+//! ```json
+//! {"error": {"message": "...", "type": "invalid_request_error", "param": "input", "code": "missing_required_parameter"}}
+//! ```
+//!
+//! `type` is `invalid_request_error` for the 4xx statuses and `server_error` for the 5xx ones.
+//! `param` names the field of the request at fault when it is known, and `code` tells the error
+//! apart for programs.
 
-#[allow(dead_code)]
-pub(crate) fn not_implemented() -> Response<Body> {
-    // log error
-    error!(target: "stdout", "501 Not Implemented");
+use hyper::{header::HeaderValue, Body, Response, StatusCode};
+use thiserror::Error;
 
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::NOT_IMPLEMENTED)
-        .body(Body::from("501 Not Implemented"))
-        .unwrap()
-}
+/// Seconds clients refused for a full queue are told to wait, in the `Retry-After` header.
+const RETRY_AFTER_SECS: u64 = 1;
 
-pub(crate) fn internal_server_error(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "500 Internal Server Error".to_string(),
-        false => format!("500 Internal Server Error: {}", msg.as_ref()),
+/// A response of `status` with an error object in the OpenAI format, whose message is `msg`, or
+/// the reason of the status if it is empty.
+pub(crate) fn error_response(
+    status: StatusCode,
+    code: &str,
+    param: Option<&str>,
+    msg: impl AsRef<str>,
+) -> Response<Body> {
+    let msg = match msg.as_ref().is_empty() {
+        true => status.canonical_reason().unwrap_or_default(),
+        false => msg.as_ref(),
     };
 
     // log error
-    error!(target: "stdout", "{}", &err_msg);
+    error!(target: "stdout", "{}: {}", status, msg);
+
+    let err_type = match status.is_client_error() {
+        true => "invalid_request_error",
+        false => "server_error",
+    };
+    let body = serde_json::json!({
+        "error": {
+            "message": msg,
+            "type": err_type,
+            "param": param,
+            "code": code,
+        }
+    });
 
     Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-        .body(Body::from(err_msg))
+        .header("Content-Type", "application/json")
+        .status(status)
+        .body(Body::from(body.to_string()))
         .unwrap()
 }
 
+pub(crate) fn not_implemented(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(StatusCode::NOT_IMPLEMENTED, "not_implemented", None, msg)
+}
+
+pub(crate) fn internal_server_error(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal_error",
+        None,
+        msg,
+    )
+}
+
+/// `400 Bad Request`, whose `param` and `code` are told from the deserialization error of the
+/// request body it reports, if any, e.g. `missing field `input``.
 pub(crate) fn bad_request(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "400 Bad Request".to_string(),
-        false => format!("400 Bad Request: {}", msg.as_ref()),
+    let msg = msg.as_ref();
+    let field = |pattern: &str| {
+        msg.split_once(pattern)
+            .and_then(|(_, rest)| rest.split('`').next())
     };
 
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
+    let (code, param) = if let Some(field) = field("missing field `") {
+        ("missing_required_parameter", Some(field))
+    } else if let Some(field) = field("unknown field `") {
+        ("unknown_parameter", Some(field))
+    } else if msg.contains("deserialize") {
+        ("invalid_request_body", None)
+    } else {
+        ("invalid_request", None)
+    };
 
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::BAD_REQUEST)
-        .body(Body::from(err_msg))
-        .unwrap()
+    error_response(StatusCode::BAD_REQUEST, code, param, msg)
 }
 
 pub(crate) fn unauthorized(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "401 Unauthorized".to_string(),
-        false => format!("401 Unauthorized: {}", msg.as_ref()),
-    };
-
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
-
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::UNAUTHORIZED)
-        .body(Body::from(err_msg))
-        .unwrap()
+    error_response(StatusCode::UNAUTHORIZED, "invalid_api_key", None, msg)
 }
 
 pub(crate) fn invalid_endpoint(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "404 The requested service endpoint is not found".to_string(),
+    let msg = match msg.as_ref().is_empty() {
+        true => "The requested service endpoint is not found".to_string(),
         false => format!(
-            "404 The requested service endpoint is not found: {}",
+            "The requested service endpoint is not found: {}",
             msg.as_ref()
         ),
     };
 
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
+    error_response(StatusCode::NOT_FOUND, "not_found", None, msg)
+}
 
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::NOT_FOUND)
-        .body(Body::from(err_msg))
-        .unwrap()
+pub(crate) fn payload_too_large(msg: impl AsRef<str>) -> Response<Body> {
+    let mut response = error_response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "request_too_large",
+        None,
+        msg,
+    );
+
+    // the rest of the body is not read, so the connection cannot be reused
+    response
+        .headers_mut()
+        .insert(hyper::header::CONNECTION, HeaderValue::from_static("close"));
+    response
+}
+
+pub(crate) fn service_unavailable(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "service_unavailable",
+        None,
+        msg,
+    )
+}
+
+pub(crate) fn too_many_requests(msg: impl AsRef<str>) -> Response<Body> {
+    let mut response = error_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        "rate_limit_exceeded",
+        None,
+        msg,
+    );
+
+    response.headers_mut().insert(
+        hyper::header::RETRY_AFTER,
+        HeaderValue::from(RETRY_AFTER_SECS),
+    );
+    response
+}
+
+pub(crate) fn gateway_timeout(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(StatusCode::GATEWAY_TIMEOUT, "timeout", None, msg)
+}
+
+pub(crate) fn server_error(err: ServerError) -> Response<Body> {
+    match err {
+        ServerError::BadRequest(msg) => bad_request(msg),
+        ServerError::Busy(msg) => service_unavailable(msg),
+        ServerError::QueueFull(msg) => too_many_requests(msg),
+        ServerError::NotImplemented(msg) => not_implemented(msg),
+        ServerError::Timeout(msg) => gateway_timeout(msg),
+        ServerError::Operation(msg) => internal_server_error(msg),
+    }
 }
 
 #[derive(Error, Clone, Debug, PartialEq, Eq)]
 pub enum ServerError {
+    /// Error returned when the request sent by the client is invalid
+    #[error("{0}")]
+    BadRequest(String),
+    /// Error returned when the server is too busy to handle the request for now
+    #[error("{0}")]
+    Busy(String),
+    /// Error returned when too many requests are already waiting for the server
+    #[error("{0}")]
+    QueueFull(String),
+    /// Error returned when the server is not built with what the request needs
+    #[error("{0}")]
+    NotImplemented(String),
+    /// Error returned when the synthesis takes longer than allowed
+    #[error("{0}")]
+    Timeout(String),
     /// Generic error returned while performing an operation
     #[error("{0}")]
     Operation(String),
@@ -0,0 +1,1750 @@
+//! An OpenAI compatible text to speech server, run by the `tts-api-server` binary or embedded in
+//! another application with [`ServerBuilder`].
+
+#[macro_use]
+extern crate log;
+
+mod access_log;
+mod audio;
+mod auth;
+mod backend;
+mod builder;
+mod config_file;
+mod cors;
+mod error;
+mod logs;
+mod metrics;
+mod rate_limit;
+mod text;
+#[cfg(feature = "tls")]
+mod tls;
+
+use anyhow::Result;
+use clap::{ArgGroup, CommandFactory, FromArgMatches, Parser};
+use hyper::{
+    body::HttpBody,
+    server::{
+        accept::Accept,
+        conn::{AddrIncoming, AddrStream},
+    },
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+#[cfg(feature = "piper")]
+use llama_core::metadata::piper::PiperMetadata;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, future::Future, pin::Pin, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpSocket,
+    sync::{Notify, Semaphore},
+};
+
+pub use backend::Backend;
+pub use builder::ServerBuilder;
+pub use error::ServerError;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+// default port
+const DEFAULT_PORT: &str = "8080";
+
+// default length of the queue of pending connections
+const DEFAULT_TCP_BACKLOG: &str = "1024";
+
+// default idle time in seconds before TCP keepalive probes are sent
+const DEFAULT_TCP_KEEPALIVE: &str = "60";
+
+// default time in seconds the pending requests are given to finish on shutdown
+const DEFAULT_SHUTDOWN_TIMEOUT: &str = "30";
+
+// phrase synthesized by `--self-test`
+const SELF_TEST_INPUT: &str = "This is a self-test of the text to speech server.";
+
+// API key
+pub(crate) static API_KEY: OnceCell<String> = OnceCell::new();
+
+// synthesis backend serving the speech endpoints
+pub(crate) static BACKEND: OnceCell<backend::Backend> = OnceCell::new();
+
+// model name given on the command line
+pub(crate) static MODEL_NAME: OnceCell<String> = OnceCell::new();
+
+// voice config of the piper model, read for the settings the server needs to know about
+#[cfg(feature = "piper")]
+pub(crate) static VOICE_CONFIG: OnceCell<serde_json::Value> = OnceCell::new();
+
+// whether the voice reads its input as phonemes, see `phoneme_type` in the voice config
+#[cfg(feature = "piper")]
+pub(crate) static PHONEME_INPUT: OnceCell<bool> = OnceCell::new();
+
+// unix time the server started at, in seconds
+pub(crate) static START_TIME: OnceCell<u64> = OnceCell::new();
+
+// whether the `/echo` endpoint is enabled
+pub(crate) static ECHO_ENABLED: OnceCell<bool> = OnceCell::new();
+
+// longest input accepted by the speech endpoints, in characters
+pub(crate) static MAX_INPUT_CHARS: OnceCell<usize> = OnceCell::new();
+
+// largest request body accepted, in bytes
+pub(crate) static MAX_REQUEST_BYTES: OnceCell<u64> = OnceCell::new();
+
+// longest piece of input synthesized in one call, in characters, not split if not set
+pub(crate) static MAX_CHUNK_CHARS: OnceCell<usize> = OnceCell::new();
+
+// what happens to the input characters of the filtered classes, not filtered if not set
+pub(crate) static INPUT_FILTER: OnceCell<text::filter::InputFilter> = OnceCell::new();
+
+// classes of input characters the input filter applies to
+pub(crate) static INPUT_FILTER_CLASSES: OnceCell<Vec<text::filter::CharClass>> = OnceCell::new();
+
+// response format of the requests that do not set one
+pub(crate) static DEFAULT_RESPONSE_FORMAT: OnceCell<audio::ResponseFormat> = OnceCell::new();
+
+// voice of the requests that set neither `voice` nor `speaker`
+pub(crate) static DEFAULT_VOICE: OnceCell<String> = OnceCell::new();
+
+// speed of the requests that do not set `speed`, unless their voice has a default one
+pub(crate) static DEFAULT_SPEED: OnceCell<f32> = OnceCell::new();
+
+// loudness the audio of the requests that do not set `target_lufs` is normalized to
+pub(crate) static DEFAULT_TARGET_LUFS: OnceCell<f32> = OnceCell::new();
+
+// language of the text normalization when it cannot be detected
+pub(crate) static DEFAULT_LANGUAGE: OnceCell<text::normalize::Language> = OnceCell::new();
+
+// longest input text logged at debug level, in characters
+pub(crate) static LOG_INPUT_MAX_CHARS: OnceCell<usize> = OnceCell::new();
+
+// whether the input text is left out of the logs
+pub(crate) static LOG_REDACT_INPUT: OnceCell<bool> = OnceCell::new();
+
+// number of retries when the synthesizer is busy
+pub(crate) static BUSY_RETRIES: OnceCell<u32> = OnceCell::new();
+
+// delay before the first retry when the synthesizer is busy, doubled on every retry
+pub(crate) static BUSY_BACKOFF_MS: OnceCell<u64> = OnceCell::new();
+
+// time in milliseconds allowed to synthesize a request, before the allowance for its input length
+pub(crate) static SYNTHESIS_TIMEOUT_BASE_MS: OnceCell<u64> = OnceCell::new();
+
+// time in milliseconds added to the synthesis timeout for every input character
+pub(crate) static SYNTHESIS_TIMEOUT_PER_CHAR_MS: OnceCell<u64> = OnceCell::new();
+
+// longest synthesis timeout in milliseconds
+pub(crate) static SYNTHESIS_TIMEOUT_MAX_MS: OnceCell<u64> = OnceCell::new();
+
+// time allowed to handle a request before it is cancelled, unlimited if not set
+pub(crate) static REQUEST_TIMEOUT: OnceCell<Duration> = OnceCell::new();
+
+// most requests allowed to wait for the synthesizer at once
+pub(crate) static MAX_QUEUE_SIZE: OnceCell<usize> = OnceCell::new();
+
+// whether API keys are logged in full instead of masked
+pub(crate) static LOG_API_KEY: OnceCell<bool> = OnceCell::new();
+
+// whether the `api_key` query parameter is ignored
+pub(crate) static QUERY_KEY_DISABLED: OnceCell<bool> = OnceCell::new();
+
+// whether the API keys of the requests go unchecked, for local development
+pub(crate) static AUTH_DISABLED: OnceCell<bool> = OnceCell::new();
+
+// key authorizing the `/v1/admin` endpoints, which are disabled without it
+pub(crate) static ADMIN_KEY: OnceCell<String> = OnceCell::new();
+
+// origins browsers may call the server from, `*` for any
+pub(crate) static CORS_ALLOW_ORIGINS: OnceCell<Vec<String>> = OnceCell::new();
+
+// effective configuration reported by `/v1/admin/config`, without secrets
+pub(crate) static CONFIG: OnceCell<serde_json::Value> = OnceCell::new();
+
+// notified to stop accepting connections and exit once the pending ones are served
+pub(crate) static SHUTDOWN: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(Debug, Parser)]
+#[command(name = "TTS API Server", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = "TTS API Server: an OpenAI compatible text to speech server")]
+#[command(group = ArgGroup::new("socket_address_group").multiple(false).args(&["socket_addr", "port"]))]
+struct Cli {
+    /// Path to a TOML or YAML file setting the options not given on the command line, which `TTS_<OPTION>` environment variables override
+    #[arg(long)]
+    config_file: Option<PathBuf>,
+    /// Synthesis backend. The first one compiled in if not set
+    #[arg(long, visible_alias = "tts-backend", value_enum)]
+    backend: Option<backend::Backend>,
+    /// Model name.
+    #[arg(short, long, required = true)]
+    model_name: String,
+    /// Path to the text to speech model file
+    #[arg(long)]
+    model: PathBuf,
+    /// Path to the voice config file
+    #[arg(long)]
+    config: PathBuf,
+    /// Path to the espeak-ng data directory
+    #[arg(long)]
+    espeak_ng_dir: PathBuf,
+    /// Socket address of the server. For example, `0.0.0.0:8080`.
+    #[arg(long, default_value = None, value_parser = clap::value_parser!(SocketAddr), group = "socket_address_group")]
+    socket_addr: Option<SocketAddr>,
+    /// Port number
+    #[arg(long, default_value = DEFAULT_PORT, value_parser = clap::value_parser!(u16), group = "socket_address_group")]
+    port: u16,
+    /// Maximum number of pending connections waiting to be accepted
+    #[arg(long, default_value = DEFAULT_TCP_BACKLOG)]
+    tcp_backlog: u32,
+    /// Idle time in seconds before TCP keepalive probes are sent on a connection. 0 disables them
+    #[arg(long, default_value = DEFAULT_TCP_KEEPALIVE)]
+    tcp_keepalive: u64,
+    /// Maximum number of open connections. Connections over it are answered with 503 and closed. Unlimited if not set
+    #[arg(long)]
+    max_connections: Option<usize>,
+    /// Path to the PEM certificate chain to serve HTTPS with, along with `--tls-key`. Plain HTTP if not set
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// Path to the PEM private key of `--tls-cert`
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Origins browsers may call the server from, separated by commas, e.g. `https://app.example.com`. `*` allows any origin
+    #[arg(long, value_delimiter = ',', default_value = "*")]
+    cors_allow_origin: Vec<String>,
+    /// Time in seconds the pending requests are given to finish on shutdown, before exiting anyway. 0 waits for them without limit
+    #[arg(long, default_value = DEFAULT_SHUTDOWN_TIMEOUT)]
+    shutdown_timeout: u64,
+    /// Enable the `/echo` test endpoint
+    #[arg(long)]
+    enable_echo: bool,
+    /// Path to a JSON file of default request parameters per voice
+    #[arg(long)]
+    voices_config: Option<PathBuf>,
+    /// Path to a TOML file mapping the voice names clients send, e.g. the OpenAI `alloy`, to the voices of the backend
+    #[arg(long)]
+    voice_map: Option<PathBuf>,
+    /// Maximum number of characters accepted in the input of a speech request. Unlimited if not set
+    #[arg(long)]
+    max_input_chars: Option<usize>,
+    /// Maximum number of bytes of a request body. Larger requests get 413 before their body is read. Unlimited if not set
+    #[arg(long)]
+    max_request_bytes: Option<u64>,
+    /// Maximum number of characters synthesized in one call. Longer inputs are split into chunks of whole sentences, synthesized in turn and joined. Not split if not set
+    #[arg(long)]
+    max_chunk_chars: Option<usize>,
+    /// Strip the input characters of `--input-filter-classes`, or reject the requests containing them with 400. Not filtered if not set
+    #[arg(long, value_enum)]
+    input_filter: Option<text::filter::InputFilter>,
+    /// Classes of input characters filtered by `--input-filter`, separated by commas
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "control")]
+    input_filter_classes: Vec<text::filter::CharClass>,
+    /// Response format of the speech requests that do not set `response_format`. `wav` if not set
+    #[arg(long, value_enum)]
+    default_response_format: Option<audio::ResponseFormat>,
+    /// Voice of the speech requests that set neither `voice`, `speaker` nor a reference audio. The default of the backend, if it has one, if not set
+    #[arg(long)]
+    default_voice: Option<String>,
+    /// Speed of the speech requests that do not set `speed`, unless their voice has a default one. 1.0 if not set
+    #[arg(long)]
+    default_speed: Option<f32>,
+    /// Integrated loudness in LUFS the audio of the speech requests that do not set `target_lufs` is normalized to. Not normalized if not set
+    #[arg(long, allow_negative_numbers = true)]
+    default_target_lufs: Option<f32>,
+    /// Language of the text normalization when it cannot be detected from the input
+    #[arg(long, value_enum, default_value = "en")]
+    default_language: text::normalize::Language,
+    /// Maximum number of characters of the input text logged at debug level
+    #[arg(long, default_value = "200")]
+    log_input_max_chars: usize,
+    /// Leave the input text out of the logs
+    #[arg(long)]
+    log_redact_input: bool,
+    /// Number of retries of a request while the synthesizer is busy, before answering 503
+    #[arg(long, default_value = "3")]
+    busy_retries: u32,
+    /// Delay in milliseconds before the first busy retry, doubled on every retry
+    #[arg(long, default_value = "50")]
+    busy_backoff_ms: u64,
+    /// Time in milliseconds allowed to synthesize a request, before the allowance for its input length
+    #[arg(long, default_value = "10000")]
+    synthesis_timeout_base_ms: u64,
+    /// Time in milliseconds added to the synthesis timeout for every input character
+    #[arg(long, default_value = "100")]
+    synthesis_timeout_per_char_ms: u64,
+    /// Longest synthesis timeout in milliseconds, whatever the input length
+    #[arg(long, default_value = "600000")]
+    synthesis_timeout_max_ms: u64,
+    /// Time in seconds allowed to handle a request, synthesis included, before it is cancelled and answered with 504. Unlimited if not set
+    #[arg(long)]
+    request_timeout_secs: Option<u64>,
+    /// Maximum number of requests waiting for the synthesizer. Requests over it get 429. Unlimited if not set
+    #[arg(long, visible_alias = "max-queue")]
+    max_queue_size: Option<usize>,
+    /// Maximum number of speech requests per minute of a client, told apart by API key, or by address when keys are not checked. Requests over it get 429. Unlimited if not set
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit_rpm: Option<u32>,
+    /// Maximum number of input characters synthesized per day for a client. Requests over it get 429. Unlimited if not set
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    rate_limit_chars_per_day: Option<u64>,
+    /// Number of synthesized audios kept in memory for repeated requests. 0 disables the cache
+    #[arg(long, default_value = "0")]
+    cache_size: usize,
+    /// Directory the synthesized audios are also cached in, kept across restarts. Not cached on disk if not set
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+    /// Maximum number of bytes of audio in the memory cache, and of files in `--cache-dir`. Unlimited if not set
+    #[arg(long)]
+    cache_max_bytes: Option<u64>,
+    /// Directory the uploaded files are stored in, created if missing
+    #[arg(long, default_value = backend::files::DEFAULT_OUTPUT_DIR)]
+    output_dir: PathBuf,
+    /// Time in seconds the uploaded files are kept before they are removed. Kept forever if not set
+    #[arg(long)]
+    file_ttl: Option<u64>,
+    /// Maximum number of bytes of the uploaded files, the oldest ones being removed above it. Unlimited if not set
+    #[arg(long)]
+    files_max_bytes: Option<u64>,
+    /// URL of the S3-compatible service the uploaded files are stored in, along with `--s3-bucket`, instead of `--output-dir`. Requires the `s3` feature
+    #[arg(long, requires = "s3_bucket")]
+    s3_endpoint: Option<String>,
+    /// Bucket of `--s3-endpoint` the uploaded files are stored in
+    #[arg(long, requires = "s3_endpoint")]
+    s3_bucket: Option<String>,
+    /// Region of `--s3-endpoint` the requests are signed for
+    #[arg(long, default_value = "us-east-1")]
+    s3_region: String,
+    /// Prefix of the keys of the objects in `--s3-bucket`, e.g. `tts/`
+    #[arg(long, default_value = "")]
+    s3_prefix: String,
+    /// Time in seconds the presigned URLs of the files stored in S3 are valid
+    #[arg(long, default_value = "3600")]
+    s3_url_expires: u64,
+    /// Log the API keys of the requests in full. They are masked by default
+    #[arg(long)]
+    log_api_key: bool,
+    /// Only accept API keys from the `Authorization` header, not from the `api_key` query parameter
+    #[arg(long)]
+    disable_query_key: bool,
+    /// Accept every request without checking its API key, even with keys configured. For local development only
+    #[arg(long)]
+    no_auth: bool,
+    /// Path to a file of accepted API keys, one per line. Reloaded on SIGHUP
+    #[arg(long)]
+    api_key_file: Option<PathBuf>,
+    /// Key authorizing the `/v1/admin` endpoints. They are disabled if it is not set
+    #[arg(long)]
+    admin_key: Option<String>,
+    /// Path to a file the requests are appended to, in the NCSA Combined Log Format. Disabled if not set
+    #[arg(long)]
+    access_log: Option<PathBuf>,
+    /// Synthesize a test phrase, print whether it worked and exit instead of starting the server
+    #[arg(long)]
+    self_test: bool,
+    /// Number of the latest log records kept in memory for `/v1/admin/logs`. 0 disables it
+    #[arg(long, default_value = "200")]
+    log_buffer_size: usize,
+    /// Format of the log output, `json` writes one object per line to stdout
+    #[arg(long, value_enum, default_value = "text")]
+    log_format: logs::LogFormat,
+}
+
+/// Run the server configured by the command line, the config file of `--config-file` and the
+/// environment, as the `tts-api-server` binary does.
+pub async fn run() -> Result<(), ServerError> {
+    let log_level = log_level();
+
+    // set global logger
+    logs::install().expect("failed to install the logger");
+    log::set_max_level(log_level.into());
+
+    info!(target: "stdout", "log_level: {}", log_level);
+
+    // read the config file, which provides the defaults of the command line arguments
+    let config_file = match config_file::path_from_args() {
+        Some(path) => match config_file::load(&path) {
+            Ok(config_file) => {
+                info!(target: "stdout", "config file: {}", path.to_string_lossy());
+                Some(config_file)
+            }
+            Err(e) => {
+                error!(target: "stdout", "{}", e);
+
+                return Err(ServerError::Operation(e));
+            }
+        },
+        None => None,
+    };
+    let command = match config_file::apply(Cli::command(), config_file.as_ref()) {
+        Ok(command) => command,
+        Err(e) => {
+            error!(target: "stdout", "{}", e);
+
+            return Err(ServerError::Operation(e));
+        }
+    };
+
+    // parse the command line arguments
+    let cli = match Cli::from_arg_matches(&command.get_matches()) {
+        Ok(cli) => cli,
+        Err(e) => e.exit(),
+    };
+
+    let api_key = env_var("TTS_API_KEY", "API_KEY")
+        .or_else(|| config_file.and_then(|config_file| config_file.api_key));
+
+    start(cli, log_level, api_key).await
+}
+
+/// Stop accepting connections and shut the server down once the pending requests are finished,
+/// as on SIGTERM.
+pub fn shutdown() {
+    SHUTDOWN.notify_one();
+}
+
+/// The value of the environment variable `name`, or of its `legacy` name if it is not set.
+fn env_var(name: &str, legacy: &str) -> Option<String> {
+    std::env::var(name).or_else(|_| std::env::var(legacy)).ok()
+}
+
+/// The log level of the `TTS_LOG` environment variable, or of the legacy `LLAMA_LOG`, `info` if
+/// neither is set.
+fn log_level() -> LogLevel {
+    let rust_log = env_var("TTS_LOG", "LLAMA_LOG")
+        .unwrap_or_default()
+        .to_lowercase();
+    let (_, log_level) = match rust_log.is_empty() {
+        true => ("stdout", LogLevel::Info),
+        false => match rust_log.split_once("=") {
+            Some((target, level)) => (target, level.parse().unwrap_or(LogLevel::Info)),
+            None => ("stdout", rust_log.parse().unwrap_or(LogLevel::Info)),
+        },
+    };
+
+    log_level
+}
+
+/// Load the model, set up the server from the parsed options and serve until shutdown.
+#[allow(clippy::needless_return)]
+async fn start(cli: Cli, log_level: LogLevel, api_key: Option<String>) -> Result<(), ServerError> {
+    if let Some(api_key) = api_key {
+        // define a const variable for the API key
+        if let Err(e) = API_KEY.set(api_key) {
+            let err_msg = format!("Failed to set API key. {}", e);
+
+            error!(target: "stdout", "{}", err_msg);
+
+            return Err(ServerError::Operation(err_msg));
+        }
+    }
+
+    // load the named API keys of the environment
+    if let Some(api_keys) = env_var("TTS_API_KEYS", "API_KEYS") {
+        match auth::init_env(&api_keys) {
+            Ok(count) => info!(target: "stdout", "Loaded {} API keys from `TTS_API_KEYS`", count),
+            Err(e) => {
+                // log
+                error!(target: "stdout", "{}", &e);
+
+                return Err(ServerError::Operation(e));
+            }
+        }
+    }
+
+    // switch the log output to the requested format as early as possible
+    logs::set_format(cli.log_format).map_err(ServerError::Operation)?;
+    info!(target: "stdout", "log format: {}", cli.log_format);
+
+    // select the synthesis backend
+    match cli.backend.or_else(backend::Backend::default_compiled) {
+        Some(backend) if !backend.is_compiled() => {
+            let err_msg = format!(
+                "The `{}` backend is not compiled in, build the server with `--features {}`.",
+                backend, backend
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::Operation(err_msg));
+        }
+        Some(backend) => {
+            info!(target: "stdout", "backend: {}", backend);
+            BACKEND
+                .set(backend)
+                .map_err(|_| ServerError::Operation("Failed to set `BACKEND`.".to_string()))?;
+            if let Some(engine) = backend.engine() {
+                backend::registry::register(engine).map_err(ServerError::Operation)?;
+            }
+        }
+        None => {
+            warn!(target: "stdout", "No synthesis backend is compiled in, speech requests will be answered with 501");
+        }
+    }
+
+    CONFIG
+        .set(config_snapshot(&cli, log_level))
+        .map_err(|_| ServerError::Operation("Failed to set `CONFIG`.".to_string()))?;
+
+    // log the version of the server
+    info!(target: "stdout", "TTS API Server v{}", env!("CARGO_PKG_VERSION"));
+
+    let start_time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    START_TIME
+        .set(start_time)
+        .map_err(|_| ServerError::Operation("Failed to set `START_TIME`.".to_string()))?;
+
+    // log model name
+    info!(target: "stdout", "model name: {}", &cli.model_name);
+    MODEL_NAME
+        .set(cli.model_name.clone())
+        .map_err(|_| ServerError::Operation("Failed to set `MODEL_NAME`.".to_string()))?;
+
+    #[cfg(feature = "piper")]
+    if BACKEND.get() == Some(&backend::Backend::Piper) {
+        // log model path
+        info!(target: "stdout", "model path: {}", cli.model.display());
+
+        // log voice config path
+        info!(target: "stdout", "voice config path: {}", cli.config.display());
+
+        let voice_config = std::fs::read_to_string(&cli.config)
+            .ok()
+            .and_then(|config| serde_json::from_str::<serde_json::Value>(&config).ok())
+            .unwrap_or_default();
+
+        // voices with `"phoneme_type": "text"` skip espeak-ng and read their input as phonemes
+        let phoneme_type = voice_config["phoneme_type"]
+            .as_str()
+            .unwrap_or("espeak")
+            .to_string();
+        info!(target: "stdout", "phoneme type: {}", &phoneme_type);
+        PHONEME_INPUT
+            .set(phoneme_type == "text")
+            .map_err(|_| ServerError::Operation("Failed to set `PHONEME_INPUT`.".to_string()))?;
+        VOICE_CONFIG
+            .set(voice_config)
+            .map_err(|_| ServerError::Operation("Failed to set `VOICE_CONFIG`.".to_string()))?;
+
+        // log espeak-ng data directory
+        info!(target: "stdout", "espeak-ng data directory: {}", cli.espeak_ng_dir.display());
+
+        // name the missing file instead of failing inside the piper context
+        check_path("--model", &cli.model, false)?;
+        check_path("--config", &cli.config, false)?;
+        check_path("--espeak-ng-dir", &cli.espeak_ng_dir, true)?;
+
+        // create a default metadata
+        let metadata = PiperMetadata::default();
+
+        // init the piper context
+        llama_core::init_piper_context(&metadata, cli.model, cli.config, cli.espeak_ng_dir)
+            .map_err(|e| ServerError::Operation(e.to_string()))?;
+    }
+
+    // log whether the echo endpoint is enabled
+    info!(target: "stdout", "enable echo: {}", cli.enable_echo);
+    ECHO_ENABLED
+        .set(cli.enable_echo)
+        .map_err(|_| ServerError::Operation("Failed to set `ECHO_ENABLED`.".to_string()))?;
+
+    // load the default parameters of the voices
+    if let Some(voices_config) = &cli.voices_config {
+        info!(target: "stdout", "voices config: {}", voices_config.display());
+        match backend::voices::init(voices_config) {
+            Ok(count) => info!(target: "stdout", "Loaded the defaults of {} voices", count),
+            Err(e) => {
+                // log
+                error!(target: "stdout", "{}", &e);
+
+                return Err(ServerError::Operation(e));
+            }
+        }
+    }
+
+    // load the voice names translated to local voices
+    if let Some(voice_map) = &cli.voice_map {
+        info!(target: "stdout", "voice map: {}", voice_map.display());
+        match backend::voices::init_map(voice_map) {
+            Ok(count) => info!(target: "stdout", "Mapped {} voice names", count),
+            Err(e) => {
+                // log
+                error!(target: "stdout", "{}", &e);
+
+                return Err(ServerError::Operation(e));
+            }
+        }
+    }
+
+    // log the input length limit
+    if let Some(max_input_chars) = cli.max_input_chars {
+        info!(target: "stdout", "max input chars: {}", max_input_chars);
+        MAX_INPUT_CHARS
+            .set(max_input_chars)
+            .map_err(|_| ServerError::Operation("Failed to set `MAX_INPUT_CHARS`.".to_string()))?;
+    }
+
+    // log the request body size limit
+    if let Some(max_request_bytes) = cli.max_request_bytes {
+        info!(target: "stdout", "max request bytes: {}", max_request_bytes);
+        MAX_REQUEST_BYTES.set(max_request_bytes).map_err(|_| {
+            ServerError::Operation("Failed to set `MAX_REQUEST_BYTES`.".to_string())
+        })?;
+    }
+
+    // log the chunk length limit
+    if let Some(max_chunk_chars) = cli.max_chunk_chars {
+        info!(target: "stdout", "max chunk chars: {}", max_chunk_chars);
+        MAX_CHUNK_CHARS
+            .set(max_chunk_chars)
+            .map_err(|_| ServerError::Operation("Failed to set `MAX_CHUNK_CHARS`.".to_string()))?;
+    }
+
+    // log the input filter
+    if let Some(input_filter) = cli.input_filter {
+        let classes: Vec<String> = cli
+            .input_filter_classes
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        info!(target: "stdout", "input filter: {} {}", input_filter, classes.join(","));
+        INPUT_FILTER
+            .set(input_filter)
+            .map_err(|_| ServerError::Operation("Failed to set `INPUT_FILTER`.".to_string()))?;
+        INPUT_FILTER_CLASSES
+            .set(cli.input_filter_classes.clone())
+            .map_err(|_| {
+                ServerError::Operation("Failed to set `INPUT_FILTER_CLASSES`.".to_string())
+            })?;
+    }
+
+    // log the default response format
+    if let Some(format) = cli.default_response_format {
+        info!(target: "stdout", "default response format: {}", format);
+
+        // fail fast on a format that is not compiled in
+        let options = audio::SpeechOptions {
+            response_format: Some(format),
+            ..Default::default()
+        };
+        if let Err(e) = options.validate() {
+            let err_msg = format!("Invalid `--default-response-format`: {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::Operation(err_msg));
+        }
+
+        DEFAULT_RESPONSE_FORMAT.set(format).map_err(|_| {
+            ServerError::Operation("Failed to set `DEFAULT_RESPONSE_FORMAT`.".to_string())
+        })?;
+    }
+
+    // log the default voice
+    if let Some(voice) = &cli.default_voice {
+        info!(target: "stdout", "default voice: {}", voice);
+
+        if voice.trim().is_empty() {
+            let err_msg = "Invalid `--default-voice`: the voice name is empty";
+
+            // log
+            error!(target: "stdout", "{}", err_msg);
+
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
+
+        DEFAULT_VOICE
+            .set(voice.clone())
+            .map_err(|_| ServerError::Operation("Failed to set `DEFAULT_VOICE`.".to_string()))?;
+    }
+
+    // log the default speed
+    if let Some(speed) = cli.default_speed {
+        info!(target: "stdout", "default speed: {}", speed);
+
+        let options = audio::SpeechOptions {
+            speed: Some(speed),
+            ..Default::default()
+        };
+        if let Err(e) = options.validate() {
+            let err_msg = format!("Invalid `--default-speed`: {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::Operation(err_msg));
+        }
+
+        DEFAULT_SPEED
+            .set(speed)
+            .map_err(|_| ServerError::Operation("Failed to set `DEFAULT_SPEED`.".to_string()))?;
+    }
+
+    // log the default loudness target
+    if let Some(target_lufs) = cli.default_target_lufs {
+        info!(target: "stdout", "default target lufs: {}", target_lufs);
+
+        let options = audio::SpeechOptions {
+            target_lufs: Some(target_lufs),
+            ..Default::default()
+        };
+        if let Err(e) = options.validate() {
+            let err_msg = format!("Invalid `--default-target-lufs`: {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::Operation(err_msg));
+        }
+
+        DEFAULT_TARGET_LUFS.set(target_lufs).map_err(|_| {
+            ServerError::Operation("Failed to set `DEFAULT_TARGET_LUFS`.".to_string())
+        })?;
+    }
+
+    // log the fallback language of the text normalization
+    info!(target: "stdout", "default language: {}", cli.default_language);
+    DEFAULT_LANGUAGE
+        .set(cli.default_language)
+        .map_err(|_| ServerError::Operation("Failed to set `DEFAULT_LANGUAGE`.".to_string()))?;
+
+    // log the settings of the input logging
+    info!(target: "stdout", "log input max chars: {}", cli.log_input_max_chars);
+    LOG_INPUT_MAX_CHARS
+        .set(cli.log_input_max_chars)
+        .map_err(|_| ServerError::Operation("Failed to set `LOG_INPUT_MAX_CHARS`.".to_string()))?;
+    info!(target: "stdout", "log redact input: {}", cli.log_redact_input);
+    LOG_REDACT_INPUT
+        .set(cli.log_redact_input)
+        .map_err(|_| ServerError::Operation("Failed to set `LOG_REDACT_INPUT`.".to_string()))?;
+
+    // log the retry policy when the synthesizer is busy
+    info!(target: "stdout", "busy retries: {}, busy backoff: {} ms", cli.busy_retries, cli.busy_backoff_ms);
+    BUSY_RETRIES
+        .set(cli.busy_retries)
+        .map_err(|_| ServerError::Operation("Failed to set `BUSY_RETRIES`.".to_string()))?;
+    BUSY_BACKOFF_MS
+        .set(cli.busy_backoff_ms)
+        .map_err(|_| ServerError::Operation("Failed to set `BUSY_BACKOFF_MS`.".to_string()))?;
+
+    // log the synthesis timeout
+    info!(target: "stdout", "synthesis timeout: {} ms + {} ms per character, at most {} ms", cli.synthesis_timeout_base_ms, cli.synthesis_timeout_per_char_ms, cli.synthesis_timeout_max_ms);
+    SYNTHESIS_TIMEOUT_BASE_MS
+        .set(cli.synthesis_timeout_base_ms)
+        .map_err(|_| {
+            ServerError::Operation("Failed to set `SYNTHESIS_TIMEOUT_BASE_MS`.".to_string())
+        })?;
+    SYNTHESIS_TIMEOUT_PER_CHAR_MS
+        .set(cli.synthesis_timeout_per_char_ms)
+        .map_err(|_| {
+            ServerError::Operation("Failed to set `SYNTHESIS_TIMEOUT_PER_CHAR_MS`.".to_string())
+        })?;
+    SYNTHESIS_TIMEOUT_MAX_MS
+        .set(cli.synthesis_timeout_max_ms)
+        .map_err(|_| {
+            ServerError::Operation("Failed to set `SYNTHESIS_TIMEOUT_MAX_MS`.".to_string())
+        })?;
+
+    // log the request timeout
+    if let Some(request_timeout_secs) = cli.request_timeout_secs {
+        info!(target: "stdout", "request timeout: {} s", request_timeout_secs);
+        REQUEST_TIMEOUT
+            .set(Duration::from_secs(request_timeout_secs))
+            .map_err(|_| ServerError::Operation("Failed to set `REQUEST_TIMEOUT`.".to_string()))?;
+    }
+
+    // log the queue size limit
+    if let Some(max_queue_size) = cli.max_queue_size {
+        info!(target: "stdout", "max queue size: {}", max_queue_size);
+        MAX_QUEUE_SIZE
+            .set(max_queue_size)
+            .map_err(|_| ServerError::Operation("Failed to set `MAX_QUEUE_SIZE`.".to_string()))?;
+    }
+
+    // log the rate limits of the clients
+    if let Some(rate_limit_rpm) = cli.rate_limit_rpm {
+        info!(target: "stdout", "rate limit rpm: {}", rate_limit_rpm);
+    }
+    if let Some(rate_limit_chars_per_day) = cli.rate_limit_chars_per_day {
+        info!(target: "stdout", "rate limit chars per day: {}", rate_limit_chars_per_day);
+    }
+    rate_limit::init(cli.rate_limit_rpm, cli.rate_limit_chars_per_day)
+        .map_err(ServerError::Operation)?;
+
+    // log the size of the audio cache
+    info!(target: "stdout", "cache size: {}", cli.cache_size);
+    if let Some(cache_max_bytes) = cli.cache_max_bytes {
+        info!(target: "stdout", "cache max bytes: {}", cache_max_bytes);
+    }
+    backend::cache::init(cli.cache_size, cli.cache_max_bytes).map_err(ServerError::Operation)?;
+
+    // open the disk cache
+    if let Some(cache_dir) = &cli.cache_dir {
+        info!(target: "stdout", "cache dir: {}", cache_dir.to_string_lossy());
+        match backend::cache::init_disk(cache_dir.clone(), cli.cache_max_bytes) {
+            Ok(count) => info!(target: "stdout", "Found {} cached audios on disk", count),
+            Err(e) => {
+                // log
+                error!(target: "stdout", "{}", &e);
+
+                return Err(ServerError::Operation(e));
+            }
+        }
+    }
+
+    // log whether API keys are logged in full
+    info!(target: "stdout", "log api key: {}", cli.log_api_key);
+    LOG_API_KEY
+        .set(cli.log_api_key)
+        .map_err(|_| ServerError::Operation("Failed to set `LOG_API_KEY`.".to_string()))?;
+
+    // log whether API keys are accepted in the query string
+    info!(target: "stdout", "disable query key: {}", cli.disable_query_key);
+    QUERY_KEY_DISABLED
+        .set(cli.disable_query_key)
+        .map_err(|_| ServerError::Operation("Failed to set `QUERY_KEY_DISABLED`.".to_string()))?;
+
+    // log whether the API keys are checked
+    if cli.no_auth {
+        warn!(target: "stdout", "no auth: the API keys of the requests are not checked, do not expose the server");
+    }
+    AUTH_DISABLED
+        .set(cli.no_auth)
+        .map_err(|_| ServerError::Operation("Failed to set `AUTH_DISABLED`.".to_string()))?;
+
+    // load the API key file
+    if let Some(api_key_file) = cli.api_key_file {
+        info!(target: "stdout", "api key file: {}", api_key_file.to_string_lossy());
+        match auth::init(api_key_file) {
+            Ok(count) => info!(target: "stdout", "Loaded {} API keys", count),
+            Err(e) => {
+                // log
+                error!(target: "stdout", "{}", &e);
+
+                return Err(ServerError::Operation(e));
+            }
+        }
+    }
+
+    // log whether the admin endpoints are enabled
+    info!(target: "stdout", "enable admin endpoints: {}", cli.admin_key.is_some());
+    if let Some(admin_key) = cli.admin_key {
+        if admin_key.trim().is_empty() {
+            let err_msg = "Invalid `--admin-key`: the key is empty";
+
+            // log
+            error!(target: "stdout", "{}", err_msg);
+
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
+
+        ADMIN_KEY
+            .set(admin_key)
+            .map_err(|_| ServerError::Operation("Failed to set `ADMIN_KEY`.".to_string()))?;
+    }
+
+    // log the size of the in-memory log buffer
+    info!(target: "stdout", "log buffer size: {}", cli.log_buffer_size);
+    logs::set_capacity(cli.log_buffer_size).map_err(ServerError::Operation)?;
+
+    // open the access log
+    if let Some(access_log) = &cli.access_log {
+        info!(target: "stdout", "access log: {}", access_log.display());
+        if let Err(e) = access_log::init(access_log) {
+            // log
+            error!(target: "stdout", "{}", &e);
+
+            return Err(ServerError::Operation(e));
+        }
+    }
+
+    // log the directory of the uploaded files
+    info!(target: "stdout", "output dir: {}", cli.output_dir.to_string_lossy());
+    if let Err(e) = backend::files::init_output_dir(cli.output_dir.clone()) {
+        // log
+        error!(target: "stdout", "{}", &e);
+
+        return Err(ServerError::Operation(e));
+    }
+
+    // store the uploaded files in S3
+    if let (Some(endpoint), Some(bucket)) = (&cli.s3_endpoint, &cli.s3_bucket) {
+        info!(target: "stdout", "s3: storing the files in the bucket {} of {}", bucket, endpoint);
+
+        #[cfg(feature = "s3")]
+        if let Err(e) = backend::s3::init(
+            endpoint,
+            bucket.clone(),
+            cli.s3_region.clone(),
+            cli.s3_prefix.clone(),
+            std::time::Duration::from_secs(cli.s3_url_expires),
+        ) {
+            // log
+            error!(target: "stdout", "{}", &e);
+
+            return Err(ServerError::Operation(e));
+        }
+
+        #[cfg(not(feature = "s3"))]
+        {
+            let err_msg = "S3 storage is not supported by this build, rebuild with `--features s3` or leave out `--s3-endpoint` and `--s3-bucket`";
+
+            // log
+            error!(target: "stdout", "{}", err_msg);
+
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
+    }
+
+    // log the retention policy of the uploaded files
+    if let Some(file_ttl) = cli.file_ttl {
+        info!(target: "stdout", "file ttl: {} s", file_ttl);
+    }
+    if let Some(files_max_bytes) = cli.files_max_bytes {
+        info!(target: "stdout", "files max bytes: {}", files_max_bytes);
+    }
+    let file_janitor = backend::files::init_retention(
+        cli.file_ttl.map(std::time::Duration::from_secs),
+        cli.files_max_bytes,
+    )
+    .map_err(ServerError::Operation)?;
+
+    // check the synthesis end to end instead of serving
+    if cli.self_test {
+        let passed = self_test(&cli.model_name).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    // socket address
+    let addr = match cli.socket_addr {
+        Some(addr) => addr,
+        None => SocketAddr::from(([0, 0, 0, 0], cli.port)),
+    };
+
+    // log the allowed origins
+    info!(target: "stdout", "cors allow origin: {}", cli.cors_allow_origin.join(","));
+    CORS_ALLOW_ORIGINS
+        .set(cli.cors_allow_origin.clone())
+        .map_err(|_| ServerError::Operation("Failed to set `CORS_ALLOW_ORIGINS`.".to_string()))?;
+
+    // log the connection limit
+    if let Some(max_connections) = cli.max_connections {
+        info!(target: "stdout", "max connections: {}", max_connections);
+    }
+    let connections = cli
+        .max_connections
+        .map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+    .map_err(|e| ServerError::Operation(format!("Failed to create the socket. {}", e)))?;
+    socket
+        .bind(addr)
+        .map_err(|e| ServerError::Operation(format!("Failed to bind {}. {}", addr, e)))?;
+    let tcp_listener = socket
+        .listen(cli.tcp_backlog)
+        .map_err(|e| ServerError::Operation(format!("Failed to listen on {}. {}", addr, e)))?;
+    info!(target: "stdout", "Listening on {}", addr);
+
+    // log the tcp settings
+    info!(target: "stdout", "tcp backlog: {}, tcp keepalive: {} s", cli.tcp_backlog, cli.tcp_keepalive);
+    let tcp_keepalive = match cli.tcp_keepalive {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let mut incoming = AddrIncoming::from_listener(tcp_listener)
+        .map_err(|e| ServerError::Operation(format!("Failed to listen on {}. {}", addr, e)))?;
+    incoming.set_keepalive(tcp_keepalive);
+
+    // notified once the server stops accepting connections, to start the shutdown timeout
+    let draining = Arc::new(Notify::new());
+    let server: Pin<Box<dyn Future<Output = Result<(), hyper::Error>>>> = match (
+        &cli.tls_cert,
+        &cli.tls_key,
+    ) {
+        #[cfg(feature = "tls")]
+        (Some(cert), Some(key)) => {
+            let acceptor = tls::acceptor(cert, key).map_err(|e| {
+                error!(target: "stdout", "{}", &e);
+                ServerError::Operation(e)
+            })?;
+            info!(target: "stdout", "tls: serving HTTPS with {}", cert.to_string_lossy());
+            Box::pin(serve(
+                tls::TlsIncoming::new(incoming, acceptor),
+                connections,
+                draining.clone(),
+            ))
+        }
+        #[cfg(not(feature = "tls"))]
+        (Some(_), _) | (_, Some(_)) => {
+            let err_msg = "HTTPS is not supported by this build, rebuild with `--features tls` or leave out `--tls-cert` and `--tls-key`";
+
+            // log
+            error!(target: "stdout", "{}", err_msg);
+
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
+        _ => Box::pin(serve(incoming, connections, draining.clone())),
+    };
+
+    if file_janitor {
+        tokio::spawn(backend::files::janitor());
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let (mut sigterm, mut sigint) = match (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+        ) {
+            (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+            (Err(e), _) | (_, Err(e)) => {
+                error!(target: "stdout", "Failed to listen for SIGTERM and SIGINT. {}", e);
+                return;
+            }
+        };
+        tokio::select! {
+            _ = sigterm.recv() => info!(target: "stdout", "Received SIGTERM"),
+            _ = sigint.recv() => info!(target: "stdout", "Received SIGINT"),
+        }
+        SHUTDOWN.notify_one();
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(sighup) => sighup,
+            Err(e) => {
+                error!(target: "stdout", "Failed to listen for SIGHUP. {}", e);
+                return;
+            }
+        };
+        while sighup.recv().await.is_some() {
+            info!(target: "stdout", "Received SIGHUP, reload the API key file");
+            match auth::reload() {
+                Ok(count) => info!(target: "stdout", "Reloaded {} API keys", count),
+                Err(e) => error!(target: "stdout", "Failed to reload the API keys. {}", e),
+            }
+        }
+    });
+
+    // log the shutdown timeout
+    info!(target: "stdout", "shutdown timeout: {} s", cli.shutdown_timeout);
+    let shutdown_timeout = cli.shutdown_timeout;
+    let drain_timeout = async move {
+        draining.notified().await;
+        match shutdown_timeout {
+            0 => std::future::pending::<()>().await,
+            secs => tokio::time::sleep(Duration::from_secs(secs)).await,
+        }
+    };
+
+    let result = tokio::select! {
+        result = server => result.map_err(|e| ServerError::Operation(e.to_string())),
+        _ = drain_timeout => {
+            warn!(target: "stdout", "The pending requests did not finish within {} s, exit anyway", shutdown_timeout);
+            Ok(())
+        }
+    };
+
+    // make sure the logged requests reach the disk before exiting
+    access_log::flush();
+    info!(target: "stdout", "Server stopped");
+
+    result
+}
+
+/// The effective configuration of the server, with secrets reduced to whether they are set.
+fn config_snapshot(cli: &Cli, log_level: LogLevel) -> serde_json::Value {
+    let addr = match cli.socket_addr {
+        Some(addr) => addr,
+        None => SocketAddr::from(([0, 0, 0, 0], cli.port)),
+    };
+    let backend = BACKEND.get().map(ToString::to_string);
+
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "backend": backend,
+        "features": {
+            "piper": cfg!(feature = "piper"),
+            "gpt_sovits": cfg!(feature = "gpt_sovits"),
+            "opus": cfg!(feature = "opus"),
+            "aac": cfg!(feature = "aac"),
+            "mp3": cfg!(feature = "mp3"),
+            "tls": cfg!(feature = "tls"),
+            "s3": cfg!(feature = "s3"),
+            "webhooks": cfg!(feature = "webhooks"),
+        },
+        "config_file": &cli.config_file,
+        "model": {
+            "name": &cli.model_name,
+            "path": &cli.model,
+            "config": &cli.config,
+            "espeak_ng_dir": &cli.espeak_ng_dir,
+            "voices_config": &cli.voices_config,
+            "voice_map": &cli.voice_map,
+        },
+        "server": {
+            "socket_addr": addr.to_string(),
+            "tls_cert": &cli.tls_cert,
+            "tcp_backlog": cli.tcp_backlog,
+            "tcp_keepalive_secs": cli.tcp_keepalive,
+            "max_connections": cli.max_connections,
+            "cors_allow_origin": &cli.cors_allow_origin,
+            "shutdown_timeout_secs": cli.shutdown_timeout,
+            "enable_echo": cli.enable_echo,
+        },
+        "limits": {
+            "max_input_chars": cli.max_input_chars,
+            "max_request_bytes": cli.max_request_bytes,
+            "max_chunk_chars": cli.max_chunk_chars,
+            "input_filter": cli.input_filter.map(|policy| policy.to_string()),
+            "input_filter_classes": cli
+                .input_filter_classes
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            "max_queue_size": cli.max_queue_size,
+            "rate_limit_rpm": cli.rate_limit_rpm,
+            "rate_limit_chars_per_day": cli.rate_limit_chars_per_day,
+            "busy_retries": cli.busy_retries,
+            "busy_backoff_ms": cli.busy_backoff_ms,
+            "synthesis_timeout_base_ms": cli.synthesis_timeout_base_ms,
+            "synthesis_timeout_per_char_ms": cli.synthesis_timeout_per_char_ms,
+            "synthesis_timeout_max_ms": cli.synthesis_timeout_max_ms,
+            "request_timeout_secs": cli.request_timeout_secs,
+            "cache_size": cli.cache_size,
+            "cache_dir": &cli.cache_dir,
+            "cache_max_bytes": cli.cache_max_bytes,
+            "output_dir": &cli.output_dir,
+            "file_ttl_secs": cli.file_ttl,
+            "files_max_bytes": cli.files_max_bytes,
+            "s3_endpoint": &cli.s3_endpoint,
+            "s3_bucket": &cli.s3_bucket,
+            "s3_region": &cli.s3_region,
+            "s3_prefix": &cli.s3_prefix,
+        },
+        "defaults": {
+            "response_format": cli.default_response_format.map(|format| format.to_string()),
+            "voice": &cli.default_voice,
+            "speed": cli.default_speed,
+            "language": cli.default_language.to_string(),
+            "target_lufs": cli.default_target_lufs,
+        },
+        "logging": {
+            "level": log_level.to_string(),
+            "input_max_chars": cli.log_input_max_chars,
+            "redact_input": cli.log_redact_input,
+            "log_api_key": cli.log_api_key,
+            "buffer_size": cli.log_buffer_size,
+            "format": cli.log_format.to_string(),
+            "access_log": &cli.access_log,
+        },
+        "auth": {
+            "api_key_set": API_KEY.get().is_some(),
+            "api_key_file": &cli.api_key_file,
+            "listed_api_keys": auth::listed_keys(),
+            "query_key_enabled": !cli.disable_query_key,
+            "no_auth": cli.no_auth,
+            "admin_key_set": cli.admin_key.is_some(),
+        },
+    })
+}
+
+/// Check that the path given to `flag` exists, is a directory or a file as expected, and can be
+/// read.
+#[cfg(feature = "piper")]
+fn check_path(flag: &str, path: &std::path::Path, is_dir: bool) -> Result<(), ServerError> {
+    let result = match std::fs::metadata(path) {
+        Err(e) => Err(e.to_string()),
+        Ok(metadata) if is_dir && !metadata.is_dir() => Err("not a directory".to_string()),
+        Ok(metadata) if !is_dir && !metadata.is_file() => Err("not a file".to_string()),
+        Ok(_) if is_dir => std::fs::read_dir(path)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Ok(_) => std::fs::File::open(path)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    };
+
+    result.map_err(|reason| {
+        let err_msg = format!("Invalid `{}` {}: {}", flag, path.display(), reason);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        ServerError::Operation(err_msg)
+    })
+}
+
+/// Synthesize [`SELF_TEST_INPUT`] through the same path as the speech endpoint and print a
+/// pass/fail summary, returning whether the test passed.
+async fn self_test(model_name: &str) -> bool {
+    println!("Self-test: synthesize \"{}\"", SELF_TEST_INPUT);
+
+    let started = std::time::Instant::now();
+    let outcome = match backend::registry::registered() {
+        // e.g. gpt_sovits has no speaker known to be available for a test synthesis
+        Some(backend) if !backend.capabilities().test_synthesis => Err(format!(
+            "the self-test is not supported by the {} backend",
+            backend.name()
+        )),
+        _ => {
+            let body = serde_json::json!({
+                "model": model_name,
+                "input": SELF_TEST_INPUT,
+                "response_format": "wav",
+            });
+            match backend::synthesize(body).await {
+                Ok(audio) => match audio::wav::Pcm::from_wav(&audio.data) {
+                    Ok(pcm) if pcm.frames() > 0 && audio::dsp::rms(&pcm.samples) > 0.0 => {
+                        Ok(format!(
+                            "{:.2} s of audio at {} Hz",
+                            pcm.frames() as f64 / pcm.sample_rate as f64,
+                            pcm.sample_rate
+                        ))
+                    }
+                    Ok(_) => Err("the synthesized audio is empty or silent".to_string()),
+                    Err(e) => Err(format!("the synthesized audio is not valid wav. {}", e)),
+                },
+                Err(e) => Err(e.to_string()),
+            }
+        }
+    };
+
+    match outcome {
+        Ok(summary) => {
+            println!(
+                "Self-test passed: {} in {} ms",
+                summary,
+                started.elapsed().as_millis()
+            );
+            true
+        }
+        Err(e) => {
+            println!("Self-test failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Addresses of an accepted connection, plain or TLS.
+pub(crate) trait Connection {
+    fn remote_addr(&self) -> SocketAddr;
+    fn local_addr(&self) -> SocketAddr;
+}
+
+impl Connection for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        AddrStream::remote_addr(self)
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        AddrStream::local_addr(self)
+    }
+}
+
+/// Serve the connections of `incoming` until [`SHUTDOWN`] is notified, then notify `draining`
+/// and wait for the pending requests.
+fn serve<I>(
+    incoming: I,
+    connections: Option<Arc<Semaphore>>,
+    draining: Arc<Notify>,
+) -> impl Future<Output = Result<(), hyper::Error>>
+where
+    I: Accept,
+    I::Error: Into<Error>,
+    I::Conn: Connection + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let new_service = make_service_fn(move |conn: &I::Conn| {
+        // log socket address
+        info!(target: "stdout",
+            "remote_addr: {}, local_addr: {}",
+            conn.remote_addr().to_string(),
+            conn.local_addr().to_string()
+        );
+
+        let remote_addr = conn.remote_addr();
+
+        // the permit is held by the service, so until the connection is closed
+        let permit = match &connections {
+            Some(connections) => connections.clone().try_acquire_owned().map(Some),
+            None => Ok(None),
+        };
+        if permit.is_err() {
+            warn!(target: "stdout", "Reached the maximum number of connections, refuse {}", conn.remote_addr());
+        }
+
+        async move {
+            Ok::<_, Error>(service_fn(move |mut req: Request<Body>| {
+                let refused = permit.is_err();
+                async move {
+                    req.extensions_mut().insert(remote_addr);
+                    let access = access_log::Entry::new(&req, remote_addr);
+                    let origin = req.headers().get(hyper::header::ORIGIN).cloned();
+                    let endpoint = metrics::endpoint(req.uri().path());
+                    let request_id = logs::request_id_or_new(req.headers().get("x-request-id"));
+                    let response = logs::with_request_id(request_id.clone(), async move {
+                        match refused {
+                            true => Ok(refuse_connection()),
+                            false => handle_request(req).await,
+                        }
+                    });
+                    let mut response = response.await?;
+                    if let Ok(value) = hyper::header::HeaderValue::from_str(&request_id) {
+                        response.headers_mut().insert("x-request-id", value);
+                    }
+                    cors::apply(origin.as_ref(), &mut response);
+                    if let Some(access) = access {
+                        access.write(&response);
+                    }
+                    metrics::record_request(endpoint, response.status());
+                    Ok::<_, hyper::Error>(response)
+                }
+            }))
+        }
+    });
+
+    Server::builder(incoming)
+        .serve(new_service)
+        .with_graceful_shutdown(async move {
+            SHUTDOWN.notified().await;
+            info!(target: "stdout", "Shutting down, wait for the pending requests to finish");
+            backend::health::set_draining();
+            draining.notify_one();
+        })
+}
+
+/// The request, or `413 Payload Too Large` if its body is longer than `max_bytes`.
+///
+/// The length of the body is known from its `Content-Length`, so only chunked bodies are read
+/// here, up to the limit.
+async fn limit_body(req: Request<Body>, max_bytes: u64) -> Result<Request<Body>, Response<Body>> {
+    let too_large = || {
+        error::payload_too_large(format!(
+            "The request body is larger than {} bytes.",
+            max_bytes
+        ))
+    };
+
+    let size_hint = req.body().size_hint();
+    if size_hint.lower() > max_bytes {
+        return Err(too_large());
+    }
+    if matches!(size_hint.upper(), Some(upper) if upper <= max_bytes) {
+        return Ok(req);
+    }
+
+    let (parts, mut body) = req.into_parts();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            error::bad_request(format!("Fail to read buffer from request body. {}", e))
+        })?;
+        if (buf.len() + chunk.len()) as u64 > max_bytes {
+            return Err(too_large());
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(Request::from_parts(parts, Body::from(buf)))
+}
+
+/// Answer a request on a connection over `--max-connections`, asking the client to close it.
+fn refuse_connection() -> Response<Body> {
+    let mut response =
+        error::service_unavailable("The server has reached its maximum number of connections.");
+    response.headers_mut().insert(
+        hyper::header::CONNECTION,
+        hyper::header::HeaderValue::from_static("close"),
+    );
+    response
+}
+
+async fn handle_request(mut req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let path_str = req.uri().path();
+    let path_buf = PathBuf::from(path_str);
+    let mut path_iter = path_buf.iter();
+    path_iter.next(); // Must be Some(OsStr::new(&path::MAIN_SEPARATOR.to_string()))
+    let root_path = path_iter.next().unwrap_or_default();
+    let root_path = "/".to_owned() + root_path.to_str().unwrap_or_default();
+
+    // browsers send preflight requests without credentials
+    if let Some(response) = cors::preflight(&req) {
+        return Ok(response);
+    }
+
+    // probes cannot carry an API key
+    match req.uri().path() {
+        "/health" => return Ok(backend::health::liveness_handler()),
+        "/ready" => return Ok(backend::health::readiness_handler(req).await),
+        _ => {}
+    }
+
+    // the admin endpoints are authorized with the admin key instead of the API key
+    if root_path == "/v1" && req.uri().path().starts_with("/v1/admin/") {
+        return Ok(handle_admin_request(req));
+    }
+
+    // once keys are configured, every request must carry one of them
+    if auth::enabled() {
+        let api_key = match request_api_key(&req) {
+            Ok(Some(api_key)) => api_key,
+            Ok(None) => {
+                let err_msg = "Missing API key, pass it as `Authorization: Bearer <API key>`.";
+                return Ok(error::unauthorized(err_msg));
+            }
+            Err(err_msg) => return Ok(error::unauthorized(err_msg)),
+        };
+
+        match LOG_API_KEY.get().copied().unwrap_or_default() {
+            true => info!(target: "stdout", "API Key: {}", api_key),
+            false => info!(target: "stdout", "API Key: {}", mask_api_key(&api_key)),
+        }
+
+        match auth::key_name(&api_key) {
+            Some(name) => info!(target: "stdout", "API key name: {}", name),
+            None => {
+                let err_msg = "Invalid API key.";
+                return Ok(error::unauthorized(err_msg));
+            }
+        }
+    }
+
+    // refuse the bodies over the size limit before they are read
+    if let Some(&max_request_bytes) = MAX_REQUEST_BYTES.get() {
+        req = match limit_body(req, max_request_bytes).await {
+            Ok(req) => req,
+            Err(response) => return Ok(response),
+        };
+    }
+
+    // count the request, and the characters of its input, against the limits of the client
+    let mut rate_limit_headers = Vec::new();
+    if rate_limit::enabled() && rate_limit::limited(req.uri().path()) {
+        // clients are told apart by API key, or by address when keys are not checked
+        let (client, name) = match request_api_key(&req) {
+            Ok(Some(api_key)) if auth::enabled() => (
+                format!("key:{}", api_key),
+                auth::key_name(&api_key).unwrap_or_default(),
+            ),
+            _ => {
+                let ip = req
+                    .extensions()
+                    .get::<SocketAddr>()
+                    .map(|remote_addr| remote_addr.ip().to_string())
+                    .unwrap_or_default();
+                (format!("addr:{}", ip), ip)
+            }
+        };
+
+        let (parts, body) = req.into_parts();
+        let (body, chars) = match rate_limit::counts_chars(&parts.method, parts.uri.path()) {
+            true => {
+                let body_bytes = match hyper::body::to_bytes(body).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => {
+                        return Ok(error::bad_request(format!(
+                            "Fail to read buffer from request body. {}",
+                            e
+                        )))
+                    }
+                };
+                let chars = rate_limit::input_chars(&body_bytes);
+                (Body::from(body_bytes), chars)
+            }
+            false => (body, 0),
+        };
+        req = Request::from_parts(parts, body);
+
+        match rate_limit::check(&client, chars) {
+            Ok(headers) => rate_limit_headers = headers,
+            Err(response) => {
+                warn!(target: "stdout", "Rate limit exceeded by {}", name);
+                return Ok(response);
+            }
+        }
+    }
+
+    // log request
+    {
+        let method = hyper::http::Method::as_str(req.method()).to_string();
+        let path = req.uri().path().to_string();
+        let version = format!("{:?}", req.version());
+        if req.method() == hyper::http::Method::POST {
+            let size: u64 = match req.headers().get("content-length") {
+                Some(content_length) => content_length.to_str().unwrap().parse().unwrap(),
+                None => 0,
+            };
+
+            info!(target: "stdout", "method: {}, http_version: {}, content-length: {}", method, version, size);
+            info!(target: "stdout", "endpoint: {}", path);
+        } else {
+            info!(target: "stdout", "method: {}, http_version: {}", method, version);
+            info!(target: "stdout", "endpoint: {}", path);
+        }
+    }
+
+    let mut response = match root_path.as_str() {
+        "/echo" if ECHO_ENABLED.get().copied().unwrap_or_default() => {
+            Response::new(Body::from("echo test"))
+        }
+        "/metrics" => metrics::metrics_handler(req).await,
+        "/v1" => handle_with_timeout(req).await,
+        _ => error::invalid_endpoint("The requested service endpoint is not found."),
+    };
+    response.headers_mut().extend(rate_limit_headers);
+
+    // log response
+    {
+        let status_code = response.status();
+        if status_code.as_u16() < 400 {
+            // log response
+            let response_version = format!("{:?}", response.version());
+            info!(target: "stdout", "response_version: {}", response_version);
+            let response_body_size: u64 = response.body().size_hint().lower();
+            info!(target: "stdout", "response_body_size: {}", response_body_size);
+            let response_status = status_code.as_u16();
+            info!(target: "stdout", "response_status: {}", response_status);
+            let response_is_success = status_code.is_success();
+            info!(target: "stdout", "response_is_success: {}", response_is_success);
+        } else {
+            let response_version = format!("{:?}", response.version());
+            error!(target: "stdout", "response_version: {}", response_version);
+            let response_body_size: u64 = response.body().size_hint().lower();
+            error!(target: "stdout", "response_body_size: {}", response_body_size);
+            let response_status = status_code.as_u16();
+            error!(target: "stdout", "response_status: {}", response_status);
+            let response_is_success = status_code.is_success();
+            error!(target: "stdout", "response_is_success: {}", response_is_success);
+            let response_is_client_error = status_code.is_client_error();
+            error!(target: "stdout", "response_is_client_error: {}", response_is_client_error);
+            let response_is_server_error = status_code.is_server_error();
+            error!(target: "stdout", "response_is_server_error: {}", response_is_server_error);
+        }
+    }
+
+    Ok(response)
+}
+
+/// Handle a `/v1` request, answering `504 Gateway Timeout` if it takes longer than
+/// `--request-timeout-secs`.
+///
+/// The handler is dropped on expiry, which cancels its synthesis. The synthesizer can only be
+/// interrupted between the pieces of the input, so a single piece stuck in the plugin still
+/// blocks the server. Streamed responses are only bounded until their headers are sent.
+async fn handle_with_timeout(req: Request<Body>) -> Response<Body> {
+    let Some(timeout) = REQUEST_TIMEOUT.get().copied() else {
+        return backend::handle_llama_request(req).await;
+    };
+
+    match tokio::time::timeout(timeout, backend::handle_llama_request(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            // log
+            error!(target: "stdout", "Cancel the request {} after {} s", logs::request_id().unwrap_or_default(), timeout.as_secs());
+
+            error::gateway_timeout(format!(
+                "The request did not finish within {} s.",
+                timeout.as_secs()
+            ))
+        }
+    }
+}
+
+/// The API key of `req`: the bearer token of the `Authorization` header, the `xi-api-key` header
+/// of the ElevenLabs clients, or the `api_key` query parameter without the headers, unless
+/// `--disable-query-key` is set.
+fn request_api_key(req: &Request<Body>) -> Result<Option<Cow<'_, str>>, String> {
+    if let (None, Some(api_key)) = (
+        req.headers().get("authorization"),
+        req.headers().get("xi-api-key"),
+    ) {
+        let api_key = api_key
+            .to_str()
+            .map_err(|e| format!("Failed to get the `xi-api-key` header: {}", e))?;
+        return Ok(Some(api_key.trim())
+            .filter(|api_key| !api_key.is_empty())
+            .map(Cow::Borrowed));
+    }
+
+    let auth_header = match req.headers().get("authorization") {
+        Some(auth_header) => auth_header,
+        None => {
+            return Ok(
+                match QUERY_KEY_DISABLED.get().copied().unwrap_or_default() {
+                    true => None,
+                    // the key is percent-encoded like any query value
+                    false => {
+                        form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                            .find_map(|(name, value)| (name == "api_key").then_some(value))
+                            .filter(|api_key| !api_key.is_empty())
+                    }
+                },
+            );
+        }
+    };
+
+    let auth_header = auth_header
+        .to_str()
+        .map_err(|e| format!("Failed to get authorization header: {}", e))?;
+    match bearer_token(auth_header) {
+        Some(api_key) => Ok(Some(Cow::Borrowed(api_key))),
+        None => Err("Malformed `Authorization` header, expected `Bearer <API key>`.".to_string()),
+    }
+}
+
+/// The token of a `Bearer <token>` authorization header, `None` for other schemes or an empty
+/// token.
+fn bearer_token(auth_header: &str) -> Option<&str> {
+    match auth_header.trim().split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Some(token.trim()).filter(|token| !token.is_empty())
+        }
+        _ => None,
+    }
+}
+
+/// Keep the first 3 and the last 4 characters of an API key, e.g. `sk-...abcd`.
+fn mask_api_key(api_key: &str) -> String {
+    let chars: Vec<char> = api_key.chars().collect();
+    match chars.len() {
+        0 => String::new(),
+        len if len <= 8 => "***".to_string(),
+        len => format!(
+            "{}...{}",
+            chars[..3].iter().collect::<String>(),
+            chars[len - 4..].iter().collect::<String>()
+        ),
+    }
+}
+
+fn handle_admin_request(req: Request<Body>) -> Response<Body> {
+    let admin_key = match ADMIN_KEY.get() {
+        Some(admin_key) => admin_key,
+        None => return error::invalid_endpoint(req.uri().path()),
+    };
+
+    let admin_key_given = req
+        .headers()
+        .get("authorization")
+        .and_then(|auth_header| auth_header.to_str().ok())
+        .and_then(bearer_token);
+    if !admin_key_given.is_some_and(|given| auth::keys_match(admin_key, given)) {
+        return error::unauthorized("Invalid admin key.");
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&hyper::http::Method::POST, "/v1/admin/shutdown") => {
+            info!(target: "stdout", "Shutdown requested through the admin endpoint");
+            SHUTDOWN.notify_one();
+
+            Response::builder()
+                .status(hyper::StatusCode::ACCEPTED)
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"status":"shutting down"}"#))
+                .unwrap()
+        }
+        (&hyper::http::Method::GET, "/v1/admin/config") => {
+            let body = CONFIG.get().cloned().unwrap_or_default();
+
+            Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        }
+        (&hyper::http::Method::GET, "/v1/admin/logs") => {
+            // `?limit=N` returns the latest N records only
+            let limit = req
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("limit="))
+                .and_then(|limit| limit.parse().ok());
+            let body = serde_json::json!({
+                "object": "list",
+                "data": logs::recent(limit),
+            });
+
+            Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        }
+        (_, path) => error::invalid_endpoint(path),
+    }
+}
+
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogLevel {
+    /// Describes messages about the values of variables and the flow of
+    /// control within a program.
+    Trace,
+
+    /// Describes messages likely to be of interest to someone debugging a
+    /// program.
+    Debug,
+
+    /// Describes messages likely to be of interest to someone monitoring a
+    /// program.
+    Info,
+
+    /// Describes messages indicating hazardous situations.
+    Warn,
+
+    /// Describes messages indicating serious errors.
+    Error,
+
+    /// Describes messages indicating fatal errors.
+    Critical,
+}
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Trace => log::LevelFilter::Trace,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Critical => log::LevelFilter::Error,
+        }
+    }
+}
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LogLevel::Trace => write!(f, "trace"),
+            LogLevel::Debug => write!(f, "debug"),
+            LogLevel::Info => write!(f, "info"),
+            LogLevel::Warn => write!(f, "warn"),
+            LogLevel::Error => write!(f, "error"),
+            LogLevel::Critical => write!(f, "critical"),
+        }
+    }
+}
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "trace" => Ok(LogLevel::Trace),
+            "debug" => Ok(LogLevel::Debug),
+            "info" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            "critical" => Ok(LogLevel::Critical),
+            _ => Err(format!("Invalid log level: {}", s)),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! The logger of the server: plain text through `wasi_logger`, or JSON lines on stdout with
+//! `--log-format json`, tagged with the id of the request being handled. The latest records are
+//! also kept in memory and served by `GET /v1/admin/logs`.
+
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Mutex, time::SystemTime};
+
+/// Number of records kept before `--log-buffer-size` is read.
+const DEFAULT_CAPACITY: usize = 200;
+
+// maximum number of records kept
+static CAPACITY: OnceCell<usize> = OnceCell::new();
+
+// the latest records, oldest first
+static BUFFER: Lazy<Mutex<VecDeque<LogLine>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+// format of the log output, text until `--log-format` is read
+static FORMAT: OnceCell<LogFormat> = OnceCell::new();
+
+tokio::task_local! {
+    // id of the request handled by the current task
+    static REQUEST_ID: String;
+}
+
+/// Longest `X-Request-Id` accepted from a client, in bytes.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Output format of the logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Human-readable lines, written by the WasmEdge host.
+    #[default]
+    Text,
+    /// One JSON object per line on stdout.
+    Json,
+}
+
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// A log record as returned by the admin endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LogLine {
+    /// Unix time of the record, in milliseconds.
+    pub timestamp: u64,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    /// Id of the request being handled when the record was logged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Logger writing through `wasi_logger` and keeping the latest records in memory.
+struct BufferedLogger {
+    inner: wasi_logger::Logger,
+}
+
+impl log::Log for BufferedLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = LogLine {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or_default(),
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            request_id: request_id(),
+        };
+
+        match FORMAT.get().copied().unwrap_or_default() {
+            LogFormat::Json => match serde_json::to_string(&line) {
+                Ok(json) => println!("{}", json),
+                Err(_) => self.inner.log(record),
+            },
+            LogFormat::Text => match &line.request_id {
+                Some(request_id) => self.inner.log(
+                    &record
+                        .to_builder()
+                        .args(format_args!("[{}] {}", request_id, record.args()))
+                        .build(),
+                ),
+                None => self.inner.log(record),
+            },
+        }
+
+        push(line);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install the logger of the server.
+pub(crate) fn install() -> Result<(), log::SetLoggerError> {
+    static LOGGER: BufferedLogger = BufferedLogger {
+        inner: wasi_logger::Logger,
+    };
+    log::set_logger(&LOGGER)
+}
+
+/// Set the output format of the logs.
+pub(crate) fn set_format(format: LogFormat) -> Result<(), String> {
+    FORMAT
+        .set(format)
+        .map_err(|_| "Failed to set `FORMAT`.".to_string())
+}
+
+/// The `X-Request-Id` a client sent, if it is short printable ASCII, or a new random id.
+pub(crate) fn request_id_or_new(header: Option<&hyper::header::HeaderValue>) -> String {
+    header
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
+/// Run `future` with the records it logs tagged with `request_id`.
+pub(crate) async fn with_request_id<F: std::future::Future>(
+    request_id: String,
+    future: F,
+) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+/// Id of the request handled by the current task, if any.
+pub(crate) fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|request_id| request_id.clone()).ok()
+}
+
+/// Set the number of records kept, dropping the oldest ones over it. 0 disables the buffer.
+pub(crate) fn set_capacity(capacity: usize) -> Result<(), String> {
+    CAPACITY
+        .set(capacity)
+        .map_err(|_| "Failed to set `CAPACITY`.".to_string())?;
+
+    let mut buffer = BUFFER.lock().unwrap();
+    while buffer.len() > capacity {
+        buffer.pop_front();
+    }
+
+    Ok(())
+}
+
+/// The latest `limit` records, or all of them, oldest first.
+pub(crate) fn recent(limit: Option<usize>) -> Vec<LogLine> {
+    let buffer = BUFFER.lock().unwrap();
+    let skip = buffer.len() - limit.unwrap_or(buffer.len()).min(buffer.len());
+    buffer.iter().skip(skip).cloned().collect()
+}
+
+fn push(line: LogLine) {
+    let capacity = CAPACITY.get().copied().unwrap_or(DEFAULT_CAPACITY);
+    if capacity == 0 {
+        return;
+    }
+
+    let mut buffer = BUFFER.lock().unwrap();
+    while buffer.len() >= capacity {
+        buffer.pop_front();
+    }
+    buffer.push_back(line);
+}
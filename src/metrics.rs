@@ -0,0 +1,185 @@
+//! Prometheus metrics of the server, served by `GET /metrics` in the text exposition format.
+
+use crate::error;
+use hyper::{Body, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+static METRICS: Lazy<Mutex<Metrics>> = Lazy::new(|| Mutex::new(Metrics::default()));
+
+/// Upper bounds of the synthesis latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 60.0, 120.0, 300.0,
+];
+
+/// Endpoints counted under their own label, the others are counted as `other`.
+const ENDPOINTS: [&str; 20] = [
+    "/echo",
+    "/health",
+    "/metrics",
+    "/ready",
+    "/v1/audio/speech",
+    "/v1/audio/speech/batch",
+    "/v1/audio/speech/jobs",
+    "/v1/audio/speech/jobs/{id}",
+    "/v1/audio/speech/stream",
+    "/v1/audio/speech/validate",
+    "/v1/audio/speech/ws",
+    "/v1/audio/voices",
+    "/v1/files",
+    "/v1/files/{id}",
+    "/v1/health",
+    "/v1/models",
+    "/v1/models/{model}",
+    "/v1/admin/config",
+    "/v1/admin/logs",
+    "/v1/admin/shutdown",
+];
+
+#[derive(Default)]
+struct Metrics {
+    /// Requests answered, by endpoint label and status code.
+    requests: BTreeMap<(&'static str, u16), u64>,
+    /// Counts of the syntheses in every latency bucket, not cumulated.
+    latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_sum: f64,
+    syntheses: u64,
+    characters: u64,
+    audio_seconds: f64,
+}
+
+/// The label of the endpoint of `path`, with the ids in paths replaced so that every file or
+/// model does not get its own series.
+pub(crate) fn endpoint(path: &str) -> &'static str {
+    let path = if path.starts_with("/v1/files/") {
+        "/v1/files/{id}"
+    } else if path.starts_with("/v1/models/") {
+        "/v1/models/{model}"
+    } else if path.starts_with("/v1/audio/speech/jobs/") {
+        "/v1/audio/speech/jobs/{id}"
+    } else {
+        path
+    };
+    ENDPOINTS
+        .iter()
+        .find(|endpoint| **endpoint == path)
+        .copied()
+        .unwrap_or("other")
+}
+
+/// Count a request answered with `status`.
+pub(crate) fn record_request(endpoint: &'static str, status: StatusCode) {
+    let mut metrics = METRICS.lock().unwrap();
+    *metrics
+        .requests
+        .entry((endpoint, status.as_u16()))
+        .or_default() += 1;
+}
+
+/// Record a synthesis of `characters` input characters into `audio_seconds` of audio.
+pub(crate) fn record_synthesis(characters: usize, latency: Duration, audio_seconds: f64) {
+    let latency = latency.as_secs_f64();
+    let mut metrics = METRICS.lock().unwrap();
+    if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&le| latency <= le) {
+        metrics.latency_buckets[bucket] += 1;
+    }
+    metrics.latency_sum += latency;
+    metrics.syntheses += 1;
+    metrics.characters += characters as u64;
+    metrics.audio_seconds += audio_seconds;
+}
+
+pub(crate) async fn metrics_handler(_req: Request<Body>) -> Response<Body> {
+    let result = Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(render()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// The metrics in the Prometheus text exposition format.
+fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+
+    // writing to a string cannot fail
+    let _ = writeln!(
+        out,
+        "# HELP tts_http_requests_total HTTP requests answered, by endpoint and status code."
+    );
+    let _ = writeln!(out, "# TYPE tts_http_requests_total counter");
+    for ((endpoint, status), count) in &metrics.requests {
+        let _ = writeln!(
+            out,
+            "tts_http_requests_total{{endpoint=\"{}\",status=\"{}\"}} {}",
+            endpoint, status, count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP tts_synthesis_duration_seconds Time spent synthesizing and encoding the audio of a request."
+    );
+    let _ = writeln!(out, "# TYPE tts_synthesis_duration_seconds histogram");
+    let mut cumulated = 0;
+    for (le, count) in LATENCY_BUCKETS.iter().zip(metrics.latency_buckets) {
+        cumulated += count;
+        let _ = writeln!(
+            out,
+            "tts_synthesis_duration_seconds_bucket{{le=\"{}\"}} {}",
+            le, cumulated
+        );
+    }
+    let _ = writeln!(
+        out,
+        "tts_synthesis_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+        metrics.syntheses
+    );
+    let _ = writeln!(
+        out,
+        "tts_synthesis_duration_seconds_sum {}",
+        metrics.latency_sum
+    );
+    let _ = writeln!(
+        out,
+        "tts_synthesis_duration_seconds_count {}",
+        metrics.syntheses
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP tts_synthesized_characters_total Input characters synthesized, cache hits excluded."
+    );
+    let _ = writeln!(out, "# TYPE tts_synthesized_characters_total counter");
+    let _ = writeln!(
+        out,
+        "tts_synthesized_characters_total {}",
+        metrics.characters
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP tts_audio_seconds_total Seconds of audio synthesized, cache hits excluded."
+    );
+    let _ = writeln!(out, "# TYPE tts_audio_seconds_total counter");
+    let _ = writeln!(out, "tts_audio_seconds_total {}", metrics.audio_seconds);
+
+    let _ = writeln!(
+        out,
+        "# HELP tts_queue_depth Requests waiting for the synthesizer."
+    );
+    let _ = writeln!(out, "# TYPE tts_queue_depth gauge");
+    let _ = writeln!(out, "tts_queue_depth {}", crate::backend::queue_len());
+
+    out
+}
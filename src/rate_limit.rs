@@ -0,0 +1,362 @@
+//! Per-client rate limits of the speech endpoints: token buckets of requests per minute and of
+//! input characters per day, keyed by API key, or by address for clients without one.
+//!
+//! Both buckets start full and refill continuously, so a client may burst up to its limit and
+//! then gets one more request, or character, every `1 / limit` of the period.
+
+use crate::error;
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    http::Method,
+    Body, Response,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+/// Number of clients tracked before the ones with full buckets are forgotten.
+const MAX_CLIENTS: usize = 10_000;
+
+const MINUTE_SECS: f64 = 60.0;
+const DAY_SECS: f64 = 86_400.0;
+
+// the limits given on the command line
+static LIMITS: OnceCell<Limits> = OnceCell::new();
+
+// the buckets of every client seen
+static CLIENTS: Lazy<Mutex<HashMap<String, Buckets>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    requests_per_minute: Option<u32>,
+    chars_per_day: Option<u64>,
+}
+
+/// The buckets of a client, `None` for the unlimited ones.
+struct Buckets {
+    requests: Option<Bucket>,
+    chars: Option<Bucket>,
+}
+
+struct Bucket {
+    /// Tokens when the bucket is full.
+    capacity: f64,
+    /// Tokens added per second.
+    refill: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: f64, period_secs: f64) -> Self {
+        Self {
+            capacity,
+            refill: capacity / period_secs,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Seconds until `tokens` tokens are available.
+    fn wait_secs(&self, tokens: f64) -> u64 {
+        ((tokens - self.tokens).max(0.0) / self.refill).ceil() as u64
+    }
+
+    /// The `X-RateLimit-*` headers of the bucket, the names ending with `suffix`.
+    fn headers(&self, suffix: &str) -> Vec<(HeaderName, HeaderValue)> {
+        [
+            ("limit", self.capacity as u64),
+            ("remaining", self.tokens.max(0.0).floor() as u64),
+            ("reset", self.wait_secs(self.capacity)),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let name = HeaderName::try_from(format!("x-ratelimit-{}{}", name, suffix)).ok()?;
+            Some((name, HeaderValue::from(value)))
+        })
+        .collect()
+    }
+}
+
+/// Set the limits applied to every client, unlimited when `None`.
+pub(crate) fn init(
+    requests_per_minute: Option<u32>,
+    chars_per_day: Option<u64>,
+) -> Result<(), String> {
+    LIMITS
+        .set(Limits {
+            requests_per_minute,
+            chars_per_day,
+        })
+        .map_err(|_| "Failed to set `LIMITS`.".to_string())
+}
+
+/// Whether any limit is set.
+pub(crate) fn enabled() -> bool {
+    match LIMITS.get() {
+        Some(limits) => limits.requests_per_minute.is_some() || limits.chars_per_day.is_some(),
+        None => false,
+    }
+}
+
+/// Whether the requests to `path` are counted: the speech endpoints, not the ones that only
+/// read or validate.
+pub(crate) fn limited(path: &str) -> bool {
+    matches!(
+        path,
+        "/v1/audio/speech"
+            | "/v1/audio/speech/batch"
+            | "/v1/audio/speech/jobs"
+            | "/v1/audio/speech/stream"
+            | "/v1/audio/speech/ws"
+    ) || path.starts_with("/v1/text-to-speech/")
+}
+
+/// Whether the input characters of the `method` requests to `path` are counted, those of the
+/// streaming endpoints arriving after the request.
+pub(crate) fn counts_chars(method: &Method, path: &str) -> bool {
+    method == Method::POST
+        && (matches!(
+            path,
+            "/v1/audio/speech" | "/v1/audio/speech/batch" | "/v1/audio/speech/jobs"
+        ) || path.starts_with("/v1/text-to-speech/"))
+}
+
+/// Number of input characters of a speech or batch request body, 0 if it is invalid.
+pub(crate) fn input_chars(body: &[u8]) -> u64 {
+    let body: serde_json::Value = match serde_json::from_slice(body) {
+        Ok(body) => body,
+        Err(_) => return 0,
+    };
+
+    let chars = match (body["items"].as_array(), body["text"].as_str()) {
+        (Some(items), _) => items.iter().map(crate::backend::input_chars).sum(),
+        // the ElevenLabs endpoints take the input as `text`
+        (None, Some(text)) => text.chars().count(),
+        (None, None) => crate::backend::input_chars(&body),
+    };
+    chars as u64
+}
+
+/// Take a request of `chars` input characters from the buckets of `client`.
+///
+/// Returns the `X-RateLimit-*` headers of the response, or the `429 Too Many Requests` answer,
+/// carrying them too, when a bucket is short. A rejected request takes nothing.
+pub(crate) fn check(
+    client: &str,
+    chars: u64,
+) -> Result<Vec<(HeaderName, HeaderValue)>, Response<Body>> {
+    let limits = match LIMITS.get() {
+        Some(limits) => *limits,
+        None => return Ok(vec![]),
+    };
+
+    let now = Instant::now();
+    let mut clients = CLIENTS.lock().unwrap();
+    if clients.len() >= MAX_CLIENTS {
+        clients.retain(|_, buckets| {
+            [&mut buckets.requests, &mut buckets.chars]
+                .into_iter()
+                .flatten()
+                .any(|bucket| {
+                    bucket.refill(now);
+                    bucket.tokens < bucket.capacity
+                })
+        });
+    }
+    let buckets = clients
+        .entry(client.to_string())
+        .or_insert_with(|| Buckets {
+            requests: limits
+                .requests_per_minute
+                .map(|limit| Bucket::new(limit as f64, MINUTE_SECS)),
+            chars: limits
+                .chars_per_day
+                .map(|limit| Bucket::new(limit as f64, DAY_SECS)),
+        });
+
+    let mut refusal = None;
+    if let Some(bucket) = &mut buckets.requests {
+        bucket.refill(now);
+        if bucket.tokens < 1.0 {
+            refusal = Some((
+                format!(
+                    "Rate limit of {} requests per minute exceeded.",
+                    bucket.capacity
+                ),
+                bucket.wait_secs(1.0),
+            ));
+        }
+    }
+    if let Some(bucket) = &mut buckets.chars {
+        bucket.refill(now);
+        if refusal.is_none() && bucket.tokens < chars as f64 {
+            let err_msg = match chars as f64 > bucket.capacity {
+                true => format!(
+                    "The input of {} characters exceeds the quota of {} characters per day.",
+                    chars, bucket.capacity
+                ),
+                false => format!(
+                    "Quota of {} characters per day exceeded, {} left.",
+                    bucket.capacity,
+                    bucket.tokens.max(0.0).floor()
+                ),
+            };
+            refusal = Some((err_msg, bucket.wait_secs(chars as f64)));
+        }
+    }
+
+    if refusal.is_none() {
+        if let Some(bucket) = &mut buckets.requests {
+            bucket.tokens -= 1.0;
+        }
+        if let Some(bucket) = &mut buckets.chars {
+            bucket.tokens -= chars as f64;
+        }
+    }
+
+    let mut headers = Vec::new();
+    if let Some(bucket) = &buckets.requests {
+        headers.extend(bucket.headers(""));
+    }
+    if let Some(bucket) = &buckets.chars {
+        headers.extend(bucket.headers("-chars"));
+    }
+
+    match refusal {
+        None => Ok(headers),
+        Some((err_msg, retry_after)) => {
+            let mut response = error::too_many_requests(err_msg);
+            let response_headers = response.headers_mut();
+            response_headers.insert(
+                hyper::header::RETRY_AFTER,
+                HeaderValue::from(retry_after.max(1)),
+            );
+            for (name, value) in headers {
+                response_headers.insert(name, value);
+            }
+            Err(response)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+    use std::time::Duration;
+
+    /// The limits of every test: 2 requests per minute and 10 characters per day.
+    fn with_limits() {
+        // the first test to run sets them, the others find them set
+        let _ = init(Some(2), Some(10));
+    }
+
+    fn header<'a>(headers: &'a [(HeaderName, HeaderValue)], name: &str) -> &'a str {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.to_str().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn bucket_refills_continuously_up_to_its_capacity() {
+        let mut bucket = Bucket::new(60.0, MINUTE_SECS);
+        let start = bucket.updated;
+        bucket.tokens = 0.0;
+
+        bucket.refill(start + Duration::from_secs(30));
+        assert_eq!(bucket.tokens, 30.0);
+        bucket.refill(start + Duration::from_secs(120));
+        assert_eq!(bucket.tokens, 60.0);
+    }
+
+    #[test]
+    fn bucket_waits_for_the_missing_tokens() {
+        let mut bucket = Bucket::new(60.0, MINUTE_SECS);
+        bucket.tokens = 0.5;
+        assert_eq!(bucket.wait_secs(1.0), 1);
+        assert_eq!(bucket.wait_secs(3.0), 3);
+        bucket.tokens = 5.0;
+        assert_eq!(bucket.wait_secs(3.0), 0);
+
+        let headers = bucket.headers("-chars");
+        assert_eq!(header(&headers, "x-ratelimit-limit-chars"), "60");
+        assert_eq!(header(&headers, "x-ratelimit-remaining-chars"), "5");
+        assert_eq!(header(&headers, "x-ratelimit-reset-chars"), "55");
+    }
+
+    #[test]
+    fn check_refuses_the_requests_over_the_limit() {
+        with_limits();
+        let client = "test:requests";
+
+        let headers = check(client, 0).unwrap();
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "2");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        check(client, 0).unwrap();
+
+        let response = check(client, 0).unwrap_err();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[hyper::header::RETRY_AFTER], "30");
+        assert_eq!(response.headers()["x-ratelimit-remaining"], "0");
+    }
+
+    #[test]
+    fn check_refuses_the_characters_over_the_quota() {
+        with_limits();
+        let client = "test:chars";
+
+        let headers = check(client, 6).unwrap();
+        assert_eq!(header(&headers, "x-ratelimit-remaining-chars"), "4");
+
+        let response = check(client, 6).unwrap_err();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["x-ratelimit-remaining-chars"], "4");
+
+        // the refused request took nothing
+        let headers = check(client, 4).unwrap();
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+        assert_eq!(header(&headers, "x-ratelimit-remaining-chars"), "0");
+    }
+
+    #[test]
+    fn check_refuses_an_input_longer_than_the_quota() {
+        with_limits();
+        let response = check("test:long", 11).unwrap_err();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["x-ratelimit-remaining-chars"], "10");
+    }
+
+    #[test]
+    fn input_chars_counts_the_characters_of_every_input() {
+        assert_eq!(input_chars(r#"{"input": "Héllo"}"#.as_bytes()), 5);
+        assert_eq!(input_chars(br#"{"input": ["Hello", "world"]}"#), 10);
+        assert_eq!(
+            input_chars(br#"{"items": [{"input": "Hello"}, {"input": "you"}]}"#),
+            8
+        );
+        assert_eq!(input_chars(br#"{"text": "Hello"}"#), 5);
+        assert_eq!(input_chars(br#"{"input": 42}"#), 0);
+        assert_eq!(input_chars(b"Hello"), 0);
+    }
+
+    #[test]
+    fn only_the_speech_requests_are_counted() {
+        assert!(limited("/v1/audio/speech"));
+        assert!(limited("/v1/text-to-speech/amy"));
+        assert!(!limited("/v1/audio/speech/validate"));
+        assert!(!limited("/v1/models"));
+
+        assert!(counts_chars(&Method::POST, "/v1/audio/speech/batch"));
+        assert!(!counts_chars(&Method::GET, "/v1/audio/speech"));
+        // the input of the streaming endpoints arrives after the request
+        assert!(!counts_chars(&Method::POST, "/v1/audio/speech/stream"));
+    }
+}
@@ -0,0 +1,97 @@
+//! Filtering of the input characters that the synthesizers read as garbage, set up with
+//! `--input-filter` and `--input-filter-classes`.
+
+/// What happens to the filtered characters of an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum InputFilter {
+    /// Remove them and synthesize the rest.
+    Strip,
+    /// Reject the request with 400.
+    Reject,
+}
+
+impl std::fmt::Display for InputFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputFilter::Strip => write!(f, "strip"),
+            InputFilter::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// Groups of characters the input filter applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum CharClass {
+    /// Control characters other than whitespace.
+    Control,
+    /// Emoji and pictographs, with their variation selectors and modifiers.
+    Emoji,
+    /// Private use characters, which have no meaning outside the application using them.
+    PrivateUse,
+}
+
+impl CharClass {
+    /// Whether `c` belongs to the class.
+    pub(crate) fn contains(self, c: char) -> bool {
+        match self {
+            CharClass::Control => c.is_control() && !c.is_whitespace(),
+            CharClass::Emoji => matches!(
+                c,
+                '\u{231A}'..='\u{231B}'
+                    | '\u{23E9}'..='\u{23FA}'
+                    | '\u{2600}'..='\u{27BF}'
+                    | '\u{2B1B}'..='\u{2B1C}'
+                    | '\u{2B50}'
+                    | '\u{2B55}'
+                    | '\u{20E3}'
+                    | '\u{FE0E}'..='\u{FE0F}'
+                    | '\u{1F000}'..='\u{1FAFF}'
+                    | '\u{E0020}'..='\u{E007F}'
+            ),
+            CharClass::PrivateUse => matches!(
+                c,
+                '\u{E000}'..='\u{F8FF}' | '\u{F0000}'..='\u{FFFFD}' | '\u{100000}'..='\u{10FFFD}'
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for CharClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CharClass::Control => write!(f, "control"),
+            CharClass::Emoji => write!(f, "emoji"),
+            CharClass::PrivateUse => write!(f, "private-use"),
+        }
+    }
+}
+
+/// The first character of `text` in one of `classes`, with its position in characters.
+pub(crate) fn find(text: &str, classes: &[CharClass]) -> Option<(usize, char, CharClass)> {
+    text.chars().enumerate().find_map(|(pos, c)| {
+        classes
+            .iter()
+            .find(|class| class.contains(c))
+            .map(|class| (pos, c, *class))
+    })
+}
+
+/// `text` without its characters in one of `classes`.
+///
+/// Zero width joiners following a stripped character go with it, as they only glue emoji
+/// sequences together.
+pub(crate) fn strip(text: &str, classes: &[CharClass]) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut previous_stripped = false;
+    for c in text.chars() {
+        previous_stripped = match c {
+            '\u{200D}' if previous_stripped => true,
+            c if classes.iter().any(|class| class.contains(c)) => true,
+            c => {
+                stripped.push(c);
+                false
+            }
+        };
+    }
+    stripped
+}
@@ -0,0 +1,74 @@
+//! Mapping of the style guidance in the OpenAI `instructions` field to prosody adjustments.
+
+/// Speed and pitch adjustments derived from style keywords.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Prosody {
+    /// Speaking rate, 1.0 being the natural rate of the voice.
+    pub speed: f32,
+    /// Pitch shift in semitones.
+    pub pitch_semitones: f32,
+}
+
+/// Recognized keywords and the speed factor and pitch shift they stand for.
+const STYLES: [(&[&str], f32, f32); 7] = [
+    (
+        &[
+            "cheerful",
+            "cheerfully",
+            "happy",
+            "happily",
+            "excited",
+            "excitedly",
+            "energetic",
+            "upbeat",
+            "enthusiastic",
+        ],
+        1.1,
+        1.5,
+    ),
+    (
+        &[
+            "calm", "calmly", "soothing", "relaxed", "gentle", "gently", "soft", "softly",
+        ],
+        0.9,
+        -1.0,
+    ),
+    (
+        &["sad", "sadly", "somber", "sombre", "melancholic", "gloomy"],
+        0.85,
+        -2.0,
+    ),
+    (&["slow", "slowly", "slower"], 0.8, 0.0),
+    (&["fast", "quickly", "quick", "faster", "hurried"], 1.2, 0.0),
+    (&["deep", "low", "lower"], 1.0, -2.0),
+    (&["high", "higher", "bright"], 1.0, 2.0),
+];
+/// Speed factors the combined keywords are clamped to.
+const SPEED_RANGE: std::ops::RangeInclusive<f32> = 0.5..=2.0;
+/// Largest pitch shift the combined keywords can add up to, in semitones.
+const MAX_PITCH_SEMITONES: f32 = 6.0;
+
+/// The prosody of the style keywords found in `instructions`, or `None` if there are none.
+///
+/// Every other part of the instructions is ignored.
+pub(crate) fn prosody(instructions: &str) -> Option<Prosody> {
+    let mut found = false;
+    let mut speed = 1.0;
+    let mut pitch_semitones = 0.0;
+
+    for word in instructions.split(|c: char| !c.is_alphabetic()) {
+        let word = word.to_lowercase();
+        for (keywords, speed_factor, pitch_shift) in STYLES {
+            if keywords.contains(&word.as_str()) {
+                found = true;
+                speed *= speed_factor;
+                pitch_semitones += pitch_shift;
+            }
+        }
+    }
+
+    found.then(|| Prosody {
+        speed: speed.clamp(*SPEED_RANGE.start(), *SPEED_RANGE.end()),
+        pitch_semitones: pitch_semitones.clamp(-MAX_PITCH_SEMITONES, MAX_PITCH_SEMITONES),
+    })
+}
@@ -0,0 +1,291 @@
+//! Text handling shared by the speech endpoints.
+
+pub(crate) mod filter;
+pub(crate) mod instructions;
+pub(crate) mod normalize;
+pub(crate) mod ssml;
+
+use normalize::Language;
+use serde::Deserialize;
+
+/// How the `input` of a speech request is to be read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum InputFormat {
+    /// Text, converted to phonemes by the backend.
+    #[default]
+    Text,
+    /// Phonemes, synthesized as they are.
+    Phonemes,
+    /// An SSML document, expanded into text segments before synthesis.
+    Ssml,
+}
+
+/// Request options controlling how the input text is rewritten before synthesis.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct TextOptions {
+    /// Whether the input is text or phonemes.
+    #[serde(default)]
+    pub input_format: InputFormat,
+    /// Spell out numbers, abbreviations and symbols.
+    #[serde(default)]
+    pub normalize: bool,
+    /// Language of the normalization. Detected per segment if not set.
+    #[serde(default)]
+    pub language: Option<Language>,
+}
+
+/// The `input` of a speech request: a single text, or segments separated by silence.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Input {
+    Text(String),
+    Segments(Vec<String>),
+}
+
+impl Input {
+    /// The texts of the input, one per segment.
+    pub(crate) fn segments(&self) -> &[String] {
+        match self {
+            Input::Text(text) => std::slice::from_ref(text),
+            Input::Segments(segments) => segments,
+        }
+    }
+
+    fn segments_mut(&mut self) -> &mut [String] {
+        match self {
+            Input::Text(text) => std::slice::from_mut(text),
+            Input::Segments(segments) => segments.as_mut_slice(),
+        }
+    }
+
+    /// Apply `--input-filter` to every segment: strip the characters of the filtered classes, or
+    /// fail on the first one with a message suitable for a 400 response.
+    pub(crate) fn filter(&mut self) -> Result<(), String> {
+        let policy = match crate::INPUT_FILTER.get() {
+            Some(policy) => *policy,
+            None => return Ok(()),
+        };
+        let classes = crate::INPUT_FILTER_CLASSES
+            .get()
+            .map(Vec::as_slice)
+            .unwrap_or(&[filter::CharClass::Control]);
+
+        let multiple = self.segments().len() > 1;
+        for (index, segment) in self.segments_mut().iter_mut().enumerate() {
+            match policy {
+                filter::InputFilter::Strip => *segment = filter::strip(segment, classes),
+                filter::InputFilter::Reject => {
+                    if let Some((pos, c, class)) = filter::find(segment, classes) {
+                        let segment = match multiple {
+                            true => format!(" of segment {}", index),
+                            false => String::new(),
+                        };
+                        return Err(format!(
+                            "unsupported {} character U+{:04X} at position {}{}",
+                            class, c as u32, pos, segment
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Normalize every segment as requested by `options`. Phonemes are left as they are.
+    ///
+    /// Segments whose language cannot be detected fall back to `--default-language`.
+    pub(crate) fn normalize(&mut self, options: &TextOptions) {
+        if !options.normalize || options.input_format == InputFormat::Phonemes {
+            return;
+        }
+
+        let default_language = crate::DEFAULT_LANGUAGE
+            .get()
+            .copied()
+            .unwrap_or(Language::En);
+        for segment in self.segments_mut() {
+            let language = options
+                .language
+                .or_else(|| normalize::detect_language(segment))
+                .unwrap_or(default_language);
+            *segment = normalize::normalize(segment, language);
+        }
+    }
+
+    /// Check that there is some text to synthesize, and at most `max_chars` characters of it.
+    ///
+    /// Characters are Unicode scalar values, so every script counts the same.
+    pub(crate) fn validate(&self, max_chars: Option<usize>) -> Result<(), String> {
+        if self.segments().iter().all(|s| s.trim().is_empty()) {
+            return Err("`input` is empty or only contains whitespace".to_string());
+        }
+
+        if let Some(max_chars) = max_chars {
+            let chars: usize = self.segments().iter().map(|s| s.chars().count()).sum();
+            if chars > max_chars {
+                return Err(format!(
+                    "the input is limited to {} characters, got {}",
+                    max_chars, chars
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The pieces synthesized on their own for every non-blank segment: the whole segment, or
+    /// its sentences when `by_sentence` is set.
+    ///
+    /// With `--max-chunk-chars`, a segment is instead split into chunks of whole sentences of at
+    /// most that many characters, and the sentences longer than that are split further.
+    pub(crate) fn pieces(&self, by_sentence: bool) -> Vec<Vec<String>> {
+        let max_chars = crate::MAX_CHUNK_CHARS.get().copied();
+        self.segments()
+            .iter()
+            .filter(|segment| !segment.trim().is_empty())
+            .map(|segment| {
+                let mut pieces = match (by_sentence, max_chars) {
+                    (true, _) => stream_sentences(segment),
+                    (false, Some(max_chars)) => split_chunks(segment, max_chars),
+                    (false, None) => vec![],
+                };
+                if pieces.is_empty() {
+                    pieces.push(segment.clone());
+                }
+                pieces
+            })
+            .collect()
+    }
+}
+
+/// Split `text` into sentences, keeping the terminating punctuation.
+///
+/// A sentence ends at `.`, `!`, `?` or `;` followed by whitespace, at their full-width
+/// counterparts, or at a line break. Empty sentences are dropped.
+pub(crate) fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let end = match c {
+            '\n' => true,
+            '。' | '！' | '？' | '；' => true,
+            '.' | '!' | '?' | ';' => chars.peek().filter(|next| !next.is_whitespace()).is_none(),
+            _ => false,
+        };
+        current.push(c);
+
+        if end {
+            let sentence = current.trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence.to_string());
+            }
+            current.clear();
+        }
+    }
+
+    let sentence = current.trim();
+    if !sentence.is_empty() {
+        sentences.push(sentence.to_string());
+    }
+
+    sentences
+}
+
+/// Split `text` into sentences, like [`split_sentences`], with the sentences longer than
+/// `--max-chunk-chars` split further by [`split_long`].
+pub(crate) fn stream_sentences(text: &str) -> Vec<String> {
+    let sentences = split_sentences(text);
+    match crate::MAX_CHUNK_CHARS.get() {
+        Some(&max_chars) => sentences
+            .iter()
+            .flat_map(|sentence| split_long(sentence, max_chars))
+            .collect(),
+        None => sentences,
+    }
+}
+
+/// Split `text` into chunks of at most `max_chars` characters, made of as many whole sentences
+/// as fit, the sentences longer than that being split by [`split_long`].
+pub(crate) fn split_chunks(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_chars = 0;
+
+    for sentence in split_sentences(text) {
+        for piece in split_long(&sentence, max_chars) {
+            let chars = piece.chars().count();
+            if current_chars > 0 && current_chars + 1 + chars > max_chars {
+                chunks.push(std::mem::take(&mut current));
+                current_chars = 0;
+            }
+            if current_chars > 0 {
+                current.push(' ');
+                current_chars += 1;
+            }
+            current.push_str(&piece);
+            current_chars += chars;
+        }
+    }
+
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+/// Split `sentence` into pieces of at most `max_chars` characters.
+///
+/// A piece ends at the last clause punctuation (`,`, `:`, `，`, `、`, `：`) of the second half of
+/// the characters that fit, else at the last whitespace, else in the middle of a word when there
+/// is neither.
+pub(crate) fn split_long(sentence: &str, max_chars: usize) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+    let mut pieces = Vec::new();
+    let mut rest = sentence.trim();
+
+    while rest.chars().count() > max_chars {
+        // the byte offset just after the `max_chars` first characters
+        let limit = rest
+            .char_indices()
+            .nth(max_chars)
+            .map_or(rest.len(), |(i, _)| i);
+        let head = &rest[..limit];
+        let clause = head
+            .char_indices()
+            .rev()
+            .find(|(_, c)| is_clause_end(*c))
+            .map(|(i, c)| i + c.len_utf8())
+            .filter(|&i| i > limit / 2);
+        let space = head
+            .char_indices()
+            .rev()
+            .find(|(i, c)| *i > 0 && c.is_whitespace())
+            .map(|(i, _)| i);
+        // a space right after the limit ends the piece at the limit
+        let end = match rest[limit..].starts_with(char::is_whitespace) {
+            true => Some(limit),
+            false => None,
+        };
+        let end = clause.or(end).or(space).unwrap_or(limit);
+
+        pieces.push(rest[..end].trim_end().to_string());
+        // a pause is made between the pieces anyway
+        rest = rest[end..].trim_start_matches(|c: char| c.is_whitespace() || is_clause_end(c));
+    }
+
+    if !rest.is_empty() {
+        pieces.push(rest.to_string());
+    }
+
+    pieces
+}
+
+/// Whether `c` ends a clause within a sentence.
+fn is_clause_end(c: char) -> bool {
+    matches!(c, ',' | ':' | '，' | '、' | '：')
+}
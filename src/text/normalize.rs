@@ -0,0 +1,333 @@
+//! Expansion of numbers, abbreviations and symbols into words, in English or Spanish.
+
+use serde::Deserialize;
+
+/// Languages the text normalization knows about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Language {
+    En,
+    Es,
+}
+impl std::fmt::Display for Language {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Language::En => write!(f, "en"),
+            Language::Es => write!(f, "es"),
+        }
+    }
+}
+
+const EN_COMMON_WORDS: [&str; 16] = [
+    "the", "and", "is", "are", "was", "of", "to", "in", "that", "it", "with", "for", "you", "this",
+    "on", "have",
+];
+const ES_COMMON_WORDS: [&str; 18] = [
+    "el", "la", "los", "las", "de", "del", "que", "y", "en", "un", "una", "es", "por", "con",
+    "para", "se", "no", "muy",
+];
+
+/// Guess the language of `text` from its letters and most common words.
+///
+/// Returns `None` when there is no clear winner.
+pub(crate) fn detect_language(text: &str) -> Option<Language> {
+    let mut en = 0;
+    let mut es = text
+        .chars()
+        .filter(|c| "ñÑ¿¡áéíóúÁÉÍÓÚü".contains(*c))
+        .count()
+        * 2;
+
+    for word in text.split(|c: char| !c.is_alphabetic()) {
+        let word = word.to_lowercase();
+        if EN_COMMON_WORDS.contains(&word.as_str()) {
+            en += 1;
+        }
+        if ES_COMMON_WORDS.contains(&word.as_str()) {
+            es += 1;
+        }
+    }
+
+    match en.cmp(&es) {
+        std::cmp::Ordering::Greater => Some(Language::En),
+        std::cmp::Ordering::Less => Some(Language::Es),
+        std::cmp::Ordering::Equal => None,
+    }
+}
+
+/// Spell out the numbers, abbreviations and symbols of `text` in `language`.
+pub(crate) fn normalize(text: &str, language: Language) -> String {
+    let mut out = String::with_capacity(text.len());
+    for piece in text.split_inclusive(char::is_whitespace) {
+        let token = piece.trim_end_matches(char::is_whitespace);
+        let space = &piece[token.len()..];
+
+        match expand_abbreviation(token, language) {
+            Some(expansion) => out.push_str(expansion),
+            None => out.push_str(&expand_numbers(token, language)),
+        }
+        out.push_str(space);
+    }
+    out
+}
+
+fn expand_abbreviation(token: &str, language: Language) -> Option<&'static str> {
+    let expansion = match (language, token) {
+        (_, "&") => match language {
+            Language::En => "and",
+            Language::Es => "y",
+        },
+        (Language::En, "Mr.") => "Mister",
+        (Language::En, "Mrs.") => "Missus",
+        (Language::En, "Dr.") => "Doctor",
+        (Language::En, "St.") => "Saint",
+        (Language::En, "vs.") => "versus",
+        (Language::En, "etc.") => "et cetera",
+        (Language::Es, "Sr.") => "Señor",
+        (Language::Es, "Sra.") => "Señora",
+        (Language::Es, "Dr.") => "Doctor",
+        (Language::Es, "Dra.") => "Doctora",
+        (Language::Es, "Ud.") => "usted",
+        (Language::Es, "etc.") => "etcétera",
+        _ => return None,
+    };
+    Some(expansion)
+}
+
+/// Replace the numbers inside `token` with words, keeping the other characters.
+fn expand_numbers(token: &str, language: Language) -> String {
+    let (group_separator, decimal_separator, decimal_word, percent) = match language {
+        Language::En => (',', '.', "point", "percent"),
+        Language::Es => ('.', ',', "coma", "por ciento"),
+    };
+
+    let chars: Vec<char> = token.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if !chars[i].is_ascii_digit() {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+
+        let mut integer = String::new();
+        loop {
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                integer.push(chars[i]);
+                i += 1;
+            }
+            // a separator followed by exactly three digits groups thousands
+            let is_group = chars.get(i) == Some(&group_separator)
+                && chars[i + 1..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_digit())
+                    .count()
+                    == 3;
+            if !is_group {
+                break;
+            }
+            i += 1;
+        }
+
+        let mut words = integer_words(&integer, language);
+        if chars.get(i) == Some(&decimal_separator)
+            && chars.get(i + 1).filter(|c| c.is_ascii_digit()).is_some()
+        {
+            i += 1;
+            words.push(' ');
+            words.push_str(decimal_word);
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                words.push(' ');
+                words.push_str(&integer_words(&chars[i].to_string(), language));
+                i += 1;
+            }
+        }
+        if chars.get(i) == Some(&'%') {
+            i += 1;
+            words.push(' ');
+            words.push_str(percent);
+        }
+
+        if out.chars().last().filter(|c| c.is_alphabetic()).is_some() {
+            out.push(' ');
+        }
+        out.push_str(&words);
+        if chars.get(i).filter(|c| c.is_alphabetic()).is_some() {
+            out.push(' ');
+        }
+    }
+    out
+}
+
+/// Words for a string of digits. Numbers too long to be read as a whole are read digit by digit.
+fn integer_words(digits: &str, language: Language) -> String {
+    match digits.parse::<u64>() {
+        Ok(n) if n < 1_000_000_000_000 && !(digits.len() > 1 && digits.starts_with('0')) => {
+            match language {
+                Language::En => english(n),
+                Language::Es => spanish(n, false),
+            }
+        }
+        _ => digits
+            .chars()
+            .filter_map(|c| c.to_digit(10))
+            .map(|d| match language {
+                Language::En => english(d as u64),
+                Language::Es => spanish(d as u64, false),
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+fn english(n: u64) -> String {
+    const ONES: [&str; 20] = [
+        "zero",
+        "one",
+        "two",
+        "three",
+        "four",
+        "five",
+        "six",
+        "seven",
+        "eight",
+        "nine",
+        "ten",
+        "eleven",
+        "twelve",
+        "thirteen",
+        "fourteen",
+        "fifteen",
+        "sixteen",
+        "seventeen",
+        "eighteen",
+        "nineteen",
+    ];
+    const TENS: [&str; 10] = [
+        "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+    ];
+
+    match n {
+        0..=19 => ONES[n as usize].to_string(),
+        20..=99 => match n % 10 {
+            0 => TENS[(n / 10) as usize].to_string(),
+            ones => format!("{}-{}", TENS[(n / 10) as usize], ONES[ones as usize]),
+        },
+        100..=999 => scale(english(n / 100), "hundred", n % 100, english),
+        1_000..=999_999 => scale(english(n / 1_000), "thousand", n % 1_000, english),
+        1_000_000..=999_999_999 => scale(english(n / 1_000_000), "million", n % 1_000_000, english),
+        _ => scale(
+            english(n / 1_000_000_000),
+            "billion",
+            n % 1_000_000_000,
+            english,
+        ),
+    }
+}
+
+fn scale(count: String, unit: &str, rest: u64, words: fn(u64) -> String) -> String {
+    match rest {
+        0 => format!("{} {}", count, unit),
+        rest => format!("{} {} {}", count, unit, words(rest)),
+    }
+}
+
+/// Spanish words for `n`. With `apocope`, a final "uno" becomes "un", as before a noun.
+fn spanish(n: u64, apocope: bool) -> String {
+    const ONES: [&str; 30] = [
+        "cero",
+        "uno",
+        "dos",
+        "tres",
+        "cuatro",
+        "cinco",
+        "seis",
+        "siete",
+        "ocho",
+        "nueve",
+        "diez",
+        "once",
+        "doce",
+        "trece",
+        "catorce",
+        "quince",
+        "dieciséis",
+        "diecisiete",
+        "dieciocho",
+        "diecinueve",
+        "veinte",
+        "veintiuno",
+        "veintidós",
+        "veintitrés",
+        "veinticuatro",
+        "veinticinco",
+        "veintiséis",
+        "veintisiete",
+        "veintiocho",
+        "veintinueve",
+    ];
+    const TENS: [&str; 10] = [
+        "",
+        "",
+        "",
+        "treinta",
+        "cuarenta",
+        "cincuenta",
+        "sesenta",
+        "setenta",
+        "ochenta",
+        "noventa",
+    ];
+    const HUNDREDS: [&str; 10] = [
+        "",
+        "ciento",
+        "doscientos",
+        "trescientos",
+        "cuatrocientos",
+        "quinientos",
+        "seiscientos",
+        "setecientos",
+        "ochocientos",
+        "novecientos",
+    ];
+
+    match n {
+        1 if apocope => "un".to_string(),
+        21 if apocope => "veintiún".to_string(),
+        0..=29 => ONES[n as usize].to_string(),
+        30..=99 => match n % 10 {
+            0 => TENS[(n / 10) as usize].to_string(),
+            ones => format!("{} y {}", TENS[(n / 10) as usize], spanish(ones, apocope)),
+        },
+        100 => "cien".to_string(),
+        101..=999 => match n % 100 {
+            0 => HUNDREDS[(n / 100) as usize].to_string(),
+            rest => format!(
+                "{} {}",
+                HUNDREDS[(n / 100) as usize],
+                spanish(rest, apocope)
+            ),
+        },
+        1_000..=999_999 => {
+            let thousands = match n / 1_000 {
+                1 => "mil".to_string(),
+                count => format!("{} mil", spanish(count, true)),
+            };
+            match n % 1_000 {
+                0 => thousands,
+                rest => format!("{} {}", thousands, spanish(rest, apocope)),
+            }
+        }
+        _ => {
+            let millions = match n / 1_000_000 {
+                1 => "un millón".to_string(),
+                count => format!("{} millones", spanish(count, true)),
+            };
+            match n % 1_000_000 {
+                0 => millions,
+                rest => format!("{} {}", millions, spanish(rest, apocope)),
+            }
+        }
+    }
+}
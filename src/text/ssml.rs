@@ -0,0 +1,664 @@
+//! SSML input, expanded into the plain text segments and request options the backends read.
+//!
+//! The parser is tolerant: unknown elements keep their text, malformed markup is read as text,
+//! and unclosed elements end with the document. The elements the backends have controls for are
+//! mapped to them:
+//!
+//! - `<break>` splits the input into segments, separated by its silence;
+//! - `<p>` and `<s>` end a sentence;
+//! - `<say-as interpret-as="characters">` (or `spell-out`, `digits`, `telephone`) spells its
+//!   text out;
+//! - `<sub alias="...">` is read as its alias;
+//! - `<prosody>` sets the `speed` and `pitch_semitones` of the whole request, as a request is
+//!   synthesized at a single rate and pitch;
+//! - the `xml:lang` of `<speak>` sets the `language` of the normalization.
+//!
+//! The backends cannot mix phonemes with text, so `<phoneme>` is read as its text. Every other
+//! element, like `<emphasis>` or `<voice>`, is read as its text too.
+
+use super::normalize::Language;
+
+/// Longest silence of a single `<break>`, in milliseconds.
+const MAX_BREAK_MS: u32 = 5000;
+/// Silences of the `strength` values of `<break>`, in milliseconds.
+const BREAK_STRENGTHS: [(&str, u32); 6] = [
+    ("none", 0),
+    ("x-weak", 100),
+    ("weak", 200),
+    ("medium", 400),
+    ("strong", 700),
+    ("x-strong", 1000),
+];
+/// Speeds of the named `rate` values of `<prosody>`.
+const RATES: [(&str, f32); 5] = [
+    ("x-slow", 0.6),
+    ("slow", 0.8),
+    ("medium", 1.0),
+    ("fast", 1.2),
+    ("x-fast", 1.4),
+];
+/// Pitch shifts in semitones of the named `pitch` values of `<prosody>`.
+const PITCHES: [(&str, f32); 5] = [
+    ("x-low", -4.0),
+    ("low", -2.0),
+    ("medium", 0.0),
+    ("high", 2.0),
+    ("x-high", 4.0),
+];
+/// Speeds the `rate` of `<prosody>` is clamped to.
+const SPEED_RANGE: std::ops::RangeInclusive<f32> = 0.5..=2.0;
+/// Largest pitch shift of `<prosody>` in either direction, in semitones.
+const MAX_PITCH_SEMITONES: f32 = 12.0;
+
+/// The content of an SSML document.
+#[derive(Debug, Default)]
+pub(crate) struct Document {
+    /// Text of the segments between the breaks, none of them blank.
+    pub segments: Vec<String>,
+    /// Silence before every segment but the first, in milliseconds.
+    pub silences_ms: Vec<u32>,
+    /// Silence of the breaks before the first segment, in milliseconds.
+    pub lead_silence_ms: u32,
+    pub language: Option<Language>,
+    pub speed: Option<f32>,
+    pub pitch_semitones: Option<f32>,
+}
+
+/// Whether a speech request body carries SSML: `"input_format": "ssml"`, or a text `input`
+/// starting with `<speak`.
+pub(crate) fn requested(body: &serde_json::Value) -> bool {
+    body["input_format"].as_str() == Some("ssml")
+        || body["input"]
+            .as_str()
+            .is_some_and(|input| input.trim_start().starts_with("<speak"))
+}
+
+/// Replace the SSML `input` of a speech request body with its text segments, and fill in the
+/// options it sets that the request leaves out. Bodies without SSML are left as they are.
+///
+/// Fails with a message suitable for a 400 response when the SSML `input` is not a string.
+pub(crate) fn expand(body: &mut serde_json::Value) -> Result<(), String> {
+    if !requested(body) {
+        return Ok(());
+    }
+    let document = match body["input"].as_str() {
+        Some(input) => parse(input),
+        None => return Err("SSML `input` must be a single document string".to_string()),
+    };
+
+    // log
+    debug!(target: "stdout", "SSML input: {} segments", document.segments.len());
+
+    let body = match body.as_object_mut() {
+        Some(body) => body,
+        None => return Ok(()),
+    };
+    let mut set_default = |name: &str, value: serde_json::Value| {
+        if body.get(name).filter(|value| !value.is_null()).is_none() {
+            body.insert(name.to_string(), value);
+        }
+    };
+    if let Some(language) = document.language {
+        set_default("language", language.to_string().into());
+    }
+    if let Some(speed) = document.speed {
+        set_default("speed", speed.into());
+    }
+    if let Some(pitch_semitones) = document.pitch_semitones {
+        set_default("pitch_semitones", pitch_semitones.into());
+    }
+    if document.lead_silence_ms > 0 {
+        set_default("lead_silence_ms", document.lead_silence_ms.into());
+    }
+    if !document.silences_ms.is_empty() {
+        body.insert(
+            "segment_silences_ms".to_string(),
+            document.silences_ms.into(),
+        );
+    }
+    body.insert("input".to_string(), document.segments.into());
+    body.insert("input_format".to_string(), "text".into());
+
+    Ok(())
+}
+
+/// An element being read, with what to do with its text once it ends.
+struct Open {
+    name: String,
+    /// Position in the current segment where the text of the element starts.
+    start: usize,
+    action: Action,
+}
+
+enum Action {
+    Keep,
+    Spell,
+    Alias(String),
+    Skip,
+}
+
+/// Read an SSML document.
+pub(crate) fn parse(ssml: &str) -> Document {
+    let mut parser = Parser {
+        document: Document::default(),
+        current: String::new(),
+        pending_silence_ms: 0,
+        open: Vec::new(),
+    };
+
+    let mut rest = ssml;
+    while let Some(lt) = rest.find('<') {
+        parser.text(&rest[..lt]);
+        rest = &rest[lt..];
+
+        // comments, processing instructions and declarations are skipped
+        let skipped = [("<!--", "-->"), ("<?", "?>"), ("<!", ">")]
+            .iter()
+            .find(|(open, _)| rest.starts_with(open))
+            .map(|(open, close)| {
+                rest[open.len()..]
+                    .find(close)
+                    .map_or(rest.len(), |end| open.len() + end + close.len())
+            });
+        if let Some(len) = skipped {
+            rest = &rest[len..];
+            continue;
+        }
+
+        match rest.find('>') {
+            Some(gt) if is_tag(&rest[1..gt]) => {
+                parser.tag(&rest[1..gt]);
+                rest = &rest[gt + 1..];
+            }
+            // a `<` that does not start a tag is text
+            _ => {
+                parser.text("<");
+                rest = &rest[1..];
+            }
+        }
+    }
+    parser.text(rest);
+
+    while let Some(open) = parser.open.pop() {
+        parser.close(open);
+    }
+    parser.end_segment();
+
+    parser.document
+}
+
+struct Parser {
+    document: Document,
+    current: String,
+    /// Silence of the breaks since the last segment, in milliseconds.
+    pending_silence_ms: u32,
+    open: Vec<Open>,
+}
+
+impl Parser {
+    fn text(&mut self, text: &str) {
+        if self
+            .open
+            .iter()
+            .any(|open| matches!(open.action, Action::Skip | Action::Alias(_)))
+        {
+            return;
+        }
+
+        for c in decode_entities(text).chars() {
+            match c.is_whitespace() {
+                true if self.current.is_empty() || self.current.ends_with(char::is_whitespace) => {}
+                true => self.current.push(' '),
+                false => self.current.push(c),
+            }
+        }
+    }
+
+    fn tag(&mut self, tag: &str) {
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim();
+            // tolerate missing end tags by closing the elements opened inside this one
+            if let Some(index) = self.open.iter().rposition(|open| open.name == name) {
+                while self.open.len() > index {
+                    let open = self.open.pop().unwrap();
+                    self.close(open);
+                }
+            }
+            return;
+        }
+
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let (name, attributes) = match tag.find(char::is_whitespace) {
+            Some(end) => (&tag[..end], attributes(&tag[end..])),
+            None => (tag, Vec::new()),
+        };
+        let attribute = |key: &str| {
+            attributes
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str())
+        };
+
+        let action = match name {
+            "speak" => {
+                if let Some(lang) = attribute("xml:lang") {
+                    self.document.language = language(lang);
+                }
+                Action::Keep
+            }
+            "break" => {
+                let silence_ms = match (attribute("time"), attribute("strength")) {
+                    (Some(time), _) => duration_ms(time),
+                    (None, Some(strength)) => BREAK_STRENGTHS
+                        .iter()
+                        .find(|(name, _)| *name == strength)
+                        .map(|(_, ms)| *ms),
+                    (None, None) => Some(400),
+                };
+                self.add_break(silence_ms.unwrap_or_default().min(MAX_BREAK_MS));
+                Action::Keep
+            }
+            "p" | "s" => {
+                self.end_sentence();
+                Action::Keep
+            }
+            "prosody" => {
+                if self.document.speed.is_none() {
+                    self.document.speed = attribute("rate").and_then(rate);
+                }
+                if self.document.pitch_semitones.is_none() {
+                    self.document.pitch_semitones = attribute("pitch").and_then(pitch);
+                }
+                Action::Keep
+            }
+            "say-as" => match attribute("interpret-as") {
+                Some("characters" | "spell-out" | "digits" | "telephone" | "verbatim") => {
+                    Action::Spell
+                }
+                _ => Action::Keep,
+            },
+            "sub" => match attribute("alias") {
+                Some(alias) => Action::Alias(decode_entities(alias)),
+                None => Action::Keep,
+            },
+            "desc" => Action::Skip,
+            _ => Action::Keep,
+        };
+
+        let open = Open {
+            name: name.to_string(),
+            start: self.current.len(),
+            action,
+        };
+        match self_closing {
+            true => self.close(open),
+            false => self.open.push(open),
+        }
+    }
+
+    fn close(&mut self, open: Open) {
+        match open.action {
+            Action::Keep | Action::Skip => {}
+            Action::Spell => {
+                // the text may have been split by a break, then only the last part is spelled
+                let start = open.start.min(self.current.len());
+                let spelled = spell(&self.current[start..]);
+                self.current.truncate(start);
+                self.current.push_str(&spelled);
+            }
+            // read as text, unless an enclosing element skips it
+            Action::Alias(alias) => self.text(&alias),
+        }
+        if open.name == "p" || open.name == "s" {
+            self.end_sentence();
+        }
+    }
+
+    fn end_sentence(&mut self) {
+        let trimmed = self.current.trim_end().len();
+        self.current.truncate(trimmed);
+        if !self.current.is_empty() {
+            self.current.push('\n');
+        }
+    }
+
+    fn add_break(&mut self, silence_ms: u32) {
+        if silence_ms == 0 {
+            return;
+        }
+        self.end_segment();
+        self.pending_silence_ms = (self.pending_silence_ms + silence_ms).min(MAX_BREAK_MS);
+    }
+
+    /// Close the current segment if it has some text.
+    fn end_segment(&mut self) {
+        let segment = self.current.trim().to_string();
+        self.current.clear();
+        for open in &mut self.open {
+            open.start = 0;
+        }
+        if segment.is_empty() {
+            return;
+        }
+
+        match self.document.segments.is_empty() {
+            true => self.document.lead_silence_ms = self.pending_silence_ms,
+            false => self.document.silences_ms.push(self.pending_silence_ms),
+        }
+        self.pending_silence_ms = 0;
+        self.document.segments.push(segment);
+    }
+}
+
+/// Whether the content between `<` and `>` is a start or end tag.
+fn is_tag(content: &str) -> bool {
+    let name = content.strip_prefix('/').unwrap_or(content);
+    name.starts_with(|c: char| c.is_ascii_alphabetic()) && !content.contains('<')
+}
+
+/// The `name="value"` attributes of a start tag, with their values decoded.
+fn attributes(mut rest: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => return attributes,
+        };
+        let name = rest[..eq].trim().to_string();
+        rest = rest[eq + 1..].trim_start();
+
+        let (value, len) = match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => match rest[1..].find(quote) {
+                Some(end) => (&rest[1..end + 1], end + 2),
+                None => (&rest[1..], rest.len()),
+            },
+            _ => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+        attributes.push((name, decode_entities(value)));
+        rest = &rest[len..];
+    }
+}
+
+/// `text` with its XML character and entity references replaced.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let reference = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let c = reference.and_then(|reference| match reference {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match reference
+                .strip_prefix("#x")
+                .or_else(|| reference.strip_prefix("#X"))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => reference
+                    .strip_prefix('#')
+                    .and_then(|dec| dec.parse().ok())
+                    .and_then(char::from_u32),
+            },
+        });
+        match (c, reference) {
+            (Some(c), Some(reference)) => {
+                decoded.push(c);
+                rest = &rest[reference.len() + 2..];
+            }
+            // a lone `&` is text
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Milliseconds of a time like `500ms` or `1.5s`.
+fn duration_ms(time: &str) -> Option<u32> {
+    let time = time.trim();
+    let (value, scale) = match time.strip_suffix("ms") {
+        Some(value) => (value, 1.0),
+        None => (time.strip_suffix('s')?, 1000.0),
+    };
+    let ms = value.trim().parse::<f32>().ok()? * scale;
+    (ms.is_finite() && ms >= 0.0).then(|| ms.round().min(u32::MAX as f32) as u32)
+}
+
+/// Speed of a `rate` like `slow`, `80%`, `+20%` or `1.2`.
+fn rate(rate: &str) -> Option<f32> {
+    let rate = rate.trim();
+    let speed = match RATES.iter().find(|(name, _)| *name == rate) {
+        Some((_, speed)) => *speed,
+        None => match rate.strip_suffix('%') {
+            Some(percent) if percent.starts_with(['+', '-']) => {
+                1.0 + percent.parse::<f32>().ok()? / 100.0
+            }
+            Some(percent) => percent.parse::<f32>().ok()? / 100.0,
+            None => rate.parse().ok()?,
+        },
+    };
+    speed
+        .is_finite()
+        .then(|| speed.clamp(*SPEED_RANGE.start(), *SPEED_RANGE.end()))
+}
+
+/// Pitch shift in semitones of a `pitch` like `high`, `+2st` or `-10%`. Shifts in Hz depend on
+/// the voice, so they are not supported.
+fn pitch(pitch: &str) -> Option<f32> {
+    let pitch = pitch.trim();
+    let semitones = match PITCHES.iter().find(|(name, _)| *name == pitch) {
+        Some((_, semitones)) => *semitones,
+        None => match (pitch.strip_suffix("st"), pitch.strip_suffix('%')) {
+            (Some(semitones), _) => semitones.parse().ok()?,
+            (None, Some(percent)) => 12.0 * (1.0 + percent.parse::<f32>().ok()? / 100.0).log2(),
+            (None, None) => return None,
+        },
+    };
+    semitones
+        .is_finite()
+        .then(|| semitones.clamp(-MAX_PITCH_SEMITONES, MAX_PITCH_SEMITONES))
+}
+
+/// The normalization language of an `xml:lang` tag like `en-US`.
+fn language(tag: &str) -> Option<Language> {
+    match tag.split(['-', '_']).next()?.to_lowercase().as_str() {
+        "en" => Some(Language::En),
+        "es" => Some(Language::Es),
+        _ => None,
+    }
+}
+
+/// `text` read character by character, e.g. `ABC` as `A B C`.
+fn spell(text: &str) -> String {
+    let mut spelled = String::with_capacity(text.len() * 2);
+    for c in text.chars().filter(|c| !c.is_whitespace()) {
+        if !spelled.is_empty() {
+            spelled.push(' ');
+        }
+        spelled.push(c);
+    }
+    match text.starts_with(char::is_whitespace) && !spelled.is_empty() {
+        true => format!(" {}", spelled),
+        false => spelled,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Option<f32>, expected: f32) {
+        let actual = actual.expect("a value");
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn parse_splits_segments_at_breaks() {
+        let document = parse(r#"<speak>Hello there.<break time="500ms"/>How are you?</speak>"#);
+        assert_eq!(document.segments, ["Hello there.", "How are you?"]);
+        assert_eq!(document.silences_ms, [500]);
+        assert_eq!(document.lead_silence_ms, 0);
+    }
+
+    #[test]
+    fn parse_adds_up_consecutive_breaks() {
+        let document =
+            parse(r#"<speak><break strength="strong"/>Hi<break/><break time="1s"/>there</speak>"#);
+        assert_eq!(document.segments, ["Hi", "there"]);
+        assert_eq!(document.lead_silence_ms, 700);
+        assert_eq!(document.silences_ms, [1400]);
+    }
+
+    #[test]
+    fn parse_caps_breaks() {
+        let document = parse(r#"<speak>Hi<break time="20s"/>there</speak>"#);
+        assert_eq!(document.silences_ms, [MAX_BREAK_MS]);
+    }
+
+    #[test]
+    fn parse_tolerates_nested_and_unclosed_tags() {
+        let document = parse("<speak><p>First <emphasis>sentence</p><s>Second");
+        assert_eq!(document.segments, ["First sentence\nSecond"]);
+
+        let document = parse("<speak>Stray</voice> end tag</speak>");
+        assert_eq!(document.segments, ["Stray end tag"]);
+    }
+
+    #[test]
+    fn parse_reads_malformed_markup_as_text() {
+        let document = parse("<speak>1 < 2 and 3 <> 4</speak>");
+        assert_eq!(document.segments, ["1 < 2 and 3 <> 4"]);
+    }
+
+    #[test]
+    fn parse_skips_comments_and_descriptions() {
+        let document = parse(
+            "<?xml version=\"1.0\"?><speak>Hi <!-- a note --> there<desc>ignored</desc></speak>",
+        );
+        assert_eq!(document.segments, ["Hi there"]);
+    }
+
+    #[test]
+    fn parse_reads_sub_as_its_alias() {
+        let document = parse(r#"<speak>The <sub alias="World Wide Web">WWW</sub> is big</speak>"#);
+        assert_eq!(document.segments, ["The World Wide Web is big"]);
+    }
+
+    #[test]
+    fn parse_spells_say_as_characters() {
+        let document =
+            parse(r#"<speak>Spell <say-as interpret-as="characters">abc</say-as> now</speak>"#);
+        assert_eq!(document.segments, ["Spell a b c now"]);
+
+        let document = parse(r#"<speak><say-as interpret-as="date">2024</say-as></speak>"#);
+        assert_eq!(document.segments, ["2024"]);
+    }
+
+    #[test]
+    fn parse_reads_prosody_and_language() {
+        let document = parse(
+            r#"<speak xml:lang="es-ES"><prosody rate="slow" pitch="+2st">Hola</prosody> <prosody rate="fast">adiós</prosody></speak>"#,
+        );
+        assert_eq!(document.segments, ["Hola adiós"]);
+        assert_eq!(document.language, Some(Language::Es));
+        // the first prosody wins, as the request has a single rate and pitch
+        assert_close(document.speed, 0.8);
+        assert_close(document.pitch_semitones, 2.0);
+    }
+
+    #[test]
+    fn parse_decodes_entities() {
+        let document = parse("<speak>Tom &amp; Jerry &lt;3 &#65;&#x42; &unknown; & co</speak>");
+        assert_eq!(document.segments, ["Tom & Jerry <3 AB &unknown; & co"]);
+    }
+
+    #[test]
+    fn duration_ms_reads_milliseconds_and_seconds() {
+        assert_eq!(duration_ms("500ms"), Some(500));
+        assert_eq!(duration_ms("1.5s"), Some(1500));
+        assert_eq!(duration_ms(" 2 s "), Some(2000));
+        assert_eq!(duration_ms("-1s"), None);
+        assert_eq!(duration_ms("10"), None);
+        assert_eq!(duration_ms("fast"), None);
+    }
+
+    #[test]
+    fn rate_reads_names_percentages_and_numbers() {
+        assert_close(rate("slow"), 0.8);
+        assert_close(rate("80%"), 0.8);
+        assert_close(rate("+20%"), 1.2);
+        assert_close(rate("-50%"), 0.5);
+        assert_close(rate("1.2"), 1.2);
+        assert_close(rate("10"), *SPEED_RANGE.end());
+        assert_close(rate("10%"), *SPEED_RANGE.start());
+        assert_eq!(rate("faster"), None);
+    }
+
+    #[test]
+    fn pitch_reads_names_semitones_and_percentages() {
+        assert_close(pitch("high"), 2.0);
+        assert_close(pitch("+2st"), 2.0);
+        assert_close(pitch("-3st"), -3.0);
+        assert_close(pitch("+100%"), 12.0);
+        assert_close(pitch("+30st"), MAX_PITCH_SEMITONES);
+        assert_eq!(pitch("+20Hz"), None);
+        assert_eq!(pitch("loud"), None);
+    }
+
+    #[test]
+    fn expand_leaves_bodies_without_ssml_untouched() {
+        for body in [
+            serde_json::json!({ "input": "Hello <b>there</b>", "voice": "amy" }),
+            serde_json::json!({ "input": ["Hello", "<speak>there</speak>"] }),
+            serde_json::json!({ "input": "Hello", "input_format": "text", "speed": 1.2 }),
+        ] {
+            let mut expanded = body.clone();
+            assert_eq!(expand(&mut expanded), Ok(()));
+            assert_eq!(expanded, body);
+        }
+    }
+
+    #[test]
+    fn expand_replaces_the_input_and_fills_in_the_options() {
+        let mut body = serde_json::json!({
+            "input": r#"<speak xml:lang="es"><prosody rate="fast">Hola</prosody><break time="300ms"/>adiós</speak>"#,
+            "speed": 1.0,
+        });
+        assert_eq!(expand(&mut body), Ok(()));
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "input": ["Hola", "adiós"],
+                "input_format": "text",
+                "segment_silences_ms": [300],
+                "language": "es",
+                "speed": 1.0,
+            })
+        );
+    }
+
+    #[test]
+    fn expand_rejects_a_non_string_ssml_input() {
+        let mut body =
+            serde_json::json!({ "input": ["<speak>Hi</speak>"], "input_format": "ssml" });
+        assert!(expand(&mut body).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! HTTPS termination: the connections accepted by the server are wrapped in TLS with the
+//! `--tls-cert` certificate chain and `--tls-key` private key.
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use hyper::server::{
+    accept::Accept,
+    conn::{AddrIncoming, AddrStream},
+};
+use std::{
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::Timeout;
+use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+
+/// Time a client is given to complete the TLS handshake, before its connection is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build the TLS acceptor of the PEM certificate chain at `cert` and PEM private key at `key`.
+pub(crate) fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, String> {
+    let certs: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut open(cert)?)
+        .map_err(|e| {
+            format!(
+                "Failed to parse the TLS certificate {}. {}",
+                cert.to_string_lossy(),
+                e
+            )
+        })?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!(
+            "No certificate found in the TLS certificate {}",
+            cert.to_string_lossy()
+        ));
+    }
+
+    let key = rustls_pemfile::read_all(&mut open(key)?)
+        .map_err(|e| {
+            format!(
+                "Failed to parse the TLS key {}. {}",
+                key.to_string_lossy(),
+                e
+            )
+        })?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            format!(
+                "No private key found in the TLS key {}",
+                key.to_string_lossy()
+            )
+        })?;
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid TLS certificate or key. {}", e))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Failed to open {}. {}", path.to_string_lossy(), e))
+}
+
+/// The TCP connections of `incoming`, yielded once their TLS handshake succeeded.
+///
+/// Handshakes run concurrently, so a slow client does not hold up the others, and failed ones
+/// are only logged.
+pub(crate) struct TlsIncoming {
+    incoming: AddrIncoming,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<Timeout<tokio_rustls::Accept<AddrStream>>>,
+}
+
+impl TlsIncoming {
+    pub(crate) fn new(incoming: AddrIncoming, acceptor: TlsAcceptor) -> Self {
+        Self {
+            incoming,
+            acceptor,
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream<AddrStream>;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        loop {
+            match Pin::new(&mut this.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => {
+                    let handshake = this.acceptor.accept(stream);
+                    this.handshakes
+                        .push(tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake));
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match this.handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(Ok(stream)))) => return Poll::Ready(Some(Ok(stream))),
+                Poll::Ready(Some(Ok(Err(e)))) => {
+                    warn!(target: "stdout", "TLS handshake failed. {}", e)
+                }
+                Poll::Ready(Some(Err(_))) => {
+                    warn!(target: "stdout", "TLS handshake timed out")
+                }
+                // polled again when a new connection is accepted
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl crate::Connection for TlsStream<AddrStream> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.get_ref().0.remote_addr()
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.get_ref().0.local_addr()
+    }
+}